@@ -11,78 +11,120 @@ fn main() {
     let two_st = load_csv("TwoSingleThread");
     let many_st = load_csv("ManyClientsST");
     let many_mt = load_csv("ManyClientsMT");
-
-    let text = |f: f64| {
-        if f > 1. {
-            "is faster than"
-        } else if f < 1. {
-            "is slower than"
-        } else {
-            "is equal to"
-        }
-    };
+    let many_mt_lock_free = load_csv("ManyClientsMTLockFree");
+    let backend_chain = load_csv("BackendChain");
+    let backend_swiss = load_csv("BackendSwiss");
+    let unbatched = load_csv("Unbatched");
+    let batched = load_csv("Batched");
+    let many_sharded = load_csv("ManyClientsSharded");
 
     println!("--- Summary ---");
     println!();
 
-    let alone_mt_st = 1.0 / (alone_mt.mean / alone_st.mean);
-    println!(
-        "alone at 16 threads {} alone at 1 thread: {:.02}x ",
-        text(alone_mt_st),
-        alone_mt_st,
+    compare(
+        "alone at 16 threads",
+        &alone_mt,
+        "alone at 1 thread",
+        &alone_st,
     );
-
-    let alone_mat_st = 1.0 / (alone_mat.mean / alone_st.mean);
-    println!(
-        "alone at 32 threads {} alone at 1 thread: {:.02}x ",
-        text(alone_mat_st),
-        alone_mat_st,
+    compare(
+        "alone at 32 threads",
+        &alone_mat,
+        "alone at 1 thread",
+        &alone_st,
     );
 
     println!();
 
-    let alone_two_st = 1.0 / (alone_st.mean / two_st.mean);
-    println!(
-        "alone at 1 thread {} one visitor at 1 thread: {:.02}x",
-        text(alone_two_st),
-        alone_two_st,
+    compare(
+        "alone at 1 thread",
+        &alone_st,
+        "one visitor at 1 thread",
+        &two_st,
     );
-
-    let alone_many_st = 1.0 / (alone_st.mean / many_st.mean);
-    println!(
-        "alone at 1 thread {} 16 visitors at 1 thread: {:.02}x",
-        text(alone_many_st),
-        alone_many_st,
+    compare(
+        "alone at 1 thread",
+        &alone_st,
+        "16 visitors at 1 thread",
+        &many_st,
     );
 
     println!();
 
-    let alone_two_mt = 1.0 / (alone_mt.mean / two_mt.mean);
-    println!(
-        "alone at 16 threads {} one visitor at 16 threads: {:.02}x",
-        text(alone_two_mt),
-        alone_two_mt,
+    compare(
+        "alone at 16 threads",
+        &alone_mt,
+        "one visitor at 16 threads",
+        &two_mt,
     );
+    compare(
+        "alone at 16 threads",
+        &alone_mt,
+        "16 visitors at 16 threads",
+        &many_mt,
+    );
+
+    println!();
 
-    let alone_many_mt = 1.0 / (alone_mt.mean / many_mt.mean);
-    println!(
-        "alone at 16 threads {} 16 visitors at 16 threads: {:.02}x",
-        text(alone_many_mt),
-        alone_many_mt,
+    compare(
+        "16 visitors at 16 threads",
+        &many_mt,
+        "16 visitors at 1 thread",
+        &many_st,
+    );
+    compare(
+        "16 visitors with the lock-free queue",
+        &many_mt_lock_free,
+        "with the mutex queue (16 threads)",
+        &many_mt,
+    );
+    compare(
+        "16 visitors at 16 shards",
+        &many_sharded,
+        "16 visitors at 1 shard (16 threads)",
+        &many_mt,
     );
 
     println!();
 
-    let vis_mt_st = 1.0 / (many_mt.mean / many_st.mean);
-    println!(
-        "16 visitors at 16 threads {} 16 visitors at 1 thread: {:.02}x",
-        text(vis_mt_st),
-        vis_mt_st,
+    compare(
+        "swiss backend",
+        &backend_swiss,
+        "chain backend (4 visitors at 16 threads)",
+        &backend_chain,
+    );
+    compare(
+        "batches of 8",
+        &batched,
+        "single requests (4 visitors at 16 threads)",
+        &unbatched,
     );
 }
 
-fn load_csv(name: &str) -> Record {
-    let mut reader = csv::Reader::from_path(format!("analysis/benchmarks/{name}.csv")).unwrap();
+/// Print how much faster the first scenario is than the second one,
+/// scenarios that were not benchmarked are skipped
+fn compare(name: &str, record: &Option<Record>, other_name: &str, other: &Option<Record>) {
+    let (Some(record), Some(other)) = (record, other) else {
+        return;
+    };
+    let factor = 1.0 / (record.mean / other.mean);
+    let text = if factor > 1. {
+        "is faster than"
+    } else if factor < 1. {
+        "is slower than"
+    } else {
+        "is equal to"
+    };
+    println!("{name} {text} {other_name}: {factor:.02}x");
+}
+
+/// Result of the scenario `name`, `None` if it was not benchmarked
+fn load_csv(name: &str) -> Option<Record> {
+    let path = format!("analysis/benchmarks/{name}.csv");
+    let Ok(mut reader) = csv::Reader::from_path(&path) else {
+        eprintln!("Skipping {name}, {path} does not exist");
+        return None;
+    };
     let list: Result<Vec<Record>, _> = reader.deserialize().collect();
-    list.unwrap().remove(0)
+    Some(list.unwrap().remove(0))
 }
@@ -27,10 +27,13 @@ do_bm() {
   INNER_LOOP=$5
   OUTER_LOOP=$6
   WARMUP_RUNS=${7:-100}
+  BACKEND=${8:-chain}
+  CLIENT_ARGS=${9:-}
+  SERVER_ARGS=${10:-}
 
-  echo "-- Benchmark $NAME (server -s $HM_SIZE -n $NUM_THREADS) ($NUM_CLIENTS bg clients, iLoop iterations $INNER_LOOP) --"
+  echo "-- Benchmark $NAME (server -s $HM_SIZE -n $NUM_THREADS -b $BACKEND $SERVER_ARGS) ($NUM_CLIENTS bg clients, iLoop iterations $INNER_LOOP, client args: $CLIENT_ARGS) --"
 
-  target/benchmark/server -s $HM_SIZE -n $NUM_THREADS &> /dev/null &
+  target/benchmark/server -s $HM_SIZE -n $NUM_THREADS -b $BACKEND $SERVER_ARGS &> /dev/null &
   SERVER_PID=$!
 
   sleep 1
@@ -40,14 +43,14 @@ do_bm() {
   if (( $NUM_CLIENTS > 0 )); then
     for i in $(seq 1 $NUM_CLIENTS)
     do
-      target/benchmark/client 0 $INNER_LOOP &> /dev/null &
+      target/benchmark/client 0 $INNER_LOOP $CLIENT_ARGS &> /dev/null &
       CLIENT_PIDS+=("$!")
     done
   fi
 
   sleep 1
 
-  hyperfine -N --warmup $WARMUP_RUNS "target/benchmark/client $OUTER_LOOP $INNER_LOOP" --export-csv "analysis/benchmarks/$NAME.csv"
+  hyperfine -N --warmup $WARMUP_RUNS "target/benchmark/client $OUTER_LOOP $INNER_LOOP $CLIENT_ARGS" --export-csv "analysis/benchmarks/$NAME.csv"
   if (( $NUM_CLIENTS > 0 )); then
     for pid in "${CLIENT_PIDS[@]}"
     do
@@ -68,7 +71,19 @@ do_bm "TwoMultiThread" 10000 16 1 10 100
 
 do_bm "ManyClientsST" 10000 1 16 10 100 2
 do_bm "ManyClientsMT" 10000 16 16 10 100 2
+do_bm "ManyClientsMTLockFree" 10000 16 16 10 100 2 chain "" "--queue lock-free"
 
 do_bm "SCManyThreads" 10000 32 0 10 100
 
+# Large tables starting from a single bucket, to compare the storage backends
+do_bm "BackendChain" 1 16 4 1000 10 5 chain
+do_bm "BackendSwiss" 1 16 4 1000 10 5 swiss
+
+# Inserts and deletes packed into batches of 8 requests
+do_bm "Unbatched" 10000 16 4 1000 10 5 chain
+do_bm "Batched" 10000 16 4 1000 10 5 chain "--batch-size 8"
+
+# Many clients spread over one request queue per worker
+do_bm "ManyClientsSharded" 10000 16 16 10 100 2 chain "" "--shards 16"
+
 target/benchmark/evaluator
@@ -0,0 +1,131 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use anyhow::bail;
+use shared::{BatchOp, BatchResult, KeyType, RequestPayload, ResponsePayload, BATCH_SIZE};
+
+use crate::client::HashtableClient;
+
+/// Packs operations into batch requests, which are sent once they are full,
+/// or once their oldest operation waited for `max_delay`
+///
+/// Batch requests use their own request ids, so the client must not
+/// be used for other requests while operations are in flight
+#[derive(Debug)]
+pub struct Batcher {
+    max_size: usize,
+    max_delay: Duration,
+    /// Operations of the next batch, with their ids
+    pending: Vec<(u32, BatchOp)>,
+    oldest: Option<Instant>,
+    /// Ids of the operations of sent batches, by request id of the batch
+    in_flight: HashMap<u32, Vec<u32>>,
+    results: VecDeque<(u32, BatchResult)>,
+    next_id: u32,
+}
+
+impl Batcher {
+    pub fn new(max_size: usize, max_delay: Duration) -> Self {
+        Self {
+            max_size: max_size.clamp(1, BATCH_SIZE),
+            max_delay,
+            pending: Vec::new(),
+            oldest: None,
+            in_flight: HashMap::new(),
+            results: VecDeque::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Queue an operation, its result is returned by [`Self::try_recv`] with the same `id`
+    pub fn push(
+        &mut self,
+        client: &mut HashtableClient,
+        op: BatchOp,
+        id: u32,
+    ) -> anyhow::Result<()> {
+        self.oldest.get_or_insert_with(Instant::now);
+        self.pending.push((id, op));
+        self.poll(client)
+    }
+
+    /// Send the pending operations if the size or latency bound is reached
+    pub fn poll(&mut self, client: &mut HashtableClient) -> anyhow::Result<()> {
+        let expired = self.oldest.is_some_and(|t| t.elapsed() >= self.max_delay);
+        if self.pending.len() >= self.max_size || expired {
+            self.flush(client)?;
+        }
+        Ok(())
+    }
+
+    /// Send the pending operations right away
+    pub fn flush(&mut self, client: &mut HashtableClient) -> anyhow::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let mut ops = [BatchOp::Get(KeyType::new()); BATCH_SIZE];
+        let mut ids = Vec::with_capacity(self.pending.len());
+        for ((id, op), slot) in self.pending.drain(..).zip(&mut ops) {
+            ids.push(id);
+            *slot = op;
+        }
+        let len = ids.len();
+
+        let batch_id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.in_flight.insert(batch_id, ids);
+        self.oldest = None;
+        client.send(RequestPayload::Batch { len, ops }, batch_id)
+    }
+
+    /// Result of the next answered operation, with its id
+    ///
+    /// Pending operations are sent once they reach the latency bound
+    pub fn try_recv(
+        &mut self,
+        client: &mut HashtableClient,
+    ) -> anyhow::Result<Option<(u32, BatchResult)>> {
+        self.recv_timeout(client, Duration::ZERO)
+    }
+
+    /// Same as [`Self::try_recv`], but wait for at most `timeout`
+    ///
+    /// Waiting is cut short when the pending operations reach the latency bound
+    pub fn recv_timeout(
+        &mut self,
+        client: &mut HashtableClient,
+        timeout: Duration,
+    ) -> anyhow::Result<Option<(u32, BatchResult)>> {
+        if let Some(result) = self.results.pop_front() {
+            return Ok(Some(result));
+        }
+        self.poll(client)?;
+
+        let timeout = match self.oldest {
+            Some(oldest) => timeout.min(self.max_delay.saturating_sub(oldest.elapsed())),
+            None => timeout,
+        };
+        let response = if timeout.is_zero() {
+            client.try_recv()?
+        } else {
+            client.recv_timeout(timeout)?
+        };
+        let Some(response) = response else {
+            return Ok(None);
+        };
+        let batch_id = response.request_id;
+        let Some(ids) = self.in_flight.remove(&batch_id) else {
+            bail!("unexpected response for batch {batch_id}");
+        };
+        let ResponsePayload::Batch { len, results } = response.payload else {
+            bail!("invalid response for batch {batch_id}");
+        };
+        if len != ids.len() {
+            bail!("invalid number of results for batch {batch_id}");
+        }
+        self.results.extend(ids.into_iter().zip(results));
+        Ok(self.results.pop_front())
+    }
+}
@@ -1,5 +1,7 @@
 use clap::Parser;
 
+use crate::client::{Reconnect, WaitPolicy};
+
 /// HashTable Client
 #[derive(Debug, Parser)]
 pub struct Args {
@@ -17,9 +19,39 @@ pub struct Args {
     #[arg(long)]
     pub seed: Option<u32>,
 
+    /// Pack up to this many inserts and deletes into a single request (at most 8)
+    #[arg(long, default_value_t = 1)]
+    pub batch_size: usize,
+
+    /// Send incomplete batches after this many microseconds
+    #[arg(long, default_value_t = 100)]
+    pub batch_delay: u64,
+
+    /// Insert values of this many bytes instead of numbers, and verify them with `Get`
+    #[arg(long)]
+    pub value_size: Option<usize>,
+
+    /// Use binary keys, which are not valid UTF-8, instead of strings
+    #[arg(long)]
+    pub binary_keys: bool,
+
+    /// How to wait for responses: `park`, `spin`, or spin for <N> microseconds before parking
+    #[arg(long, default_value = "50")]
+    pub wait: WaitPolicy,
+
+    /// What to do once the server is gone
+    #[arg(long, value_enum, default_value_t = Reconnect::Never)]
+    pub reconnect: Reconnect,
+
     /// Print HashTable on the server side
     ///
     /// When this flag is set, all other arguments are ignored
     #[arg(long)]
     pub debug_print: bool,
+
+    /// Let the server write a snapshot of the HashTable
+    ///
+    /// When this flag is set, all other arguments are ignored
+    #[arg(long)]
+    pub snapshot: bool,
 }
@@ -1,23 +1,179 @@
 use std::{
+    collections::VecDeque,
+    error::Error,
+    fmt, hint, iter,
+    str::FromStr,
     sync::{
         atomic::{AtomicBool, Ordering},
-        mpsc::{self, Receiver, TryRecvError},
+        mpsc::{self, Receiver, RecvTimeoutError, TryRecvError},
         Arc,
     },
     thread::{self, JoinHandle},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use anyhow::bail;
-use rand::Rng;
+use anyhow::{anyhow, bail};
+use clap::ValueEnum;
 
 use shared::{
-    shm::SharedMemory, HashtableMemory, RequestData, RequestPayload, ResponseData, ResponseFrame,
-    DESCRIPTOR, REQ_BUFFER_SIZE, RES_BUFFER_SIZE,
+    shm::SharedMemory,
+    slab::{Blob, SlabError},
+    sync::{OwnerDied, SyncError},
+    unix_millis, AbortReason, ErrorCode, Features, HashtableMemory, KeyType, QueueKind,
+    RequestData, RequestPayload, ResponseData, ResponsePayload, TransactionOp, ValueType,
+    DESCRIPTOR, HEARTBEAT_INTERVAL, MAX_CLIENTS, REQ_BUFFER_SIZE, SERVER_LEASE, TRANSACTION_SIZE,
 };
 
+/// Results of all operations, or the index of the operation that aborted the transaction and why
+pub type TransactionResult = Result<Vec<Option<ValueType>>, (usize, AbortReason)>;
+
+/// How the response thread of a client waits while its mailbox is empty
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitPolicy {
+    /// Block until a worker writes a response
+    Park,
+    /// Poll the mailbox for the given time, then block
+    SpinThenPark(Duration),
+    /// Poll the mailbox continuously, for the lowest latency at the cost of a busy core
+    Spin,
+}
+
+impl WaitPolicy {
+    fn should_park(self, idle: Duration) -> bool {
+        match self {
+            Self::Park => true,
+            Self::SpinThenPark(spin) => idle >= spin,
+            Self::Spin => false,
+        }
+    }
+}
+
+impl Default for WaitPolicy {
+    fn default() -> Self {
+        Self::SpinThenPark(Duration::from_micros(50))
+    }
+}
+
+impl FromStr for WaitPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "park" => Ok(Self::Park),
+            "spin" => Ok(Self::Spin),
+            us => us
+                .parse()
+                .map(|us| Self::SpinThenPark(Duration::from_micros(us)))
+                .map_err(|_| format!("expected `park`, `spin` or microseconds, got `{us}`")),
+        }
+    }
+}
+
+/// What a client does once the server is gone
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Reconnect {
+    /// Fail all further calls with [`ServerGone`]
+    #[default]
+    Never,
+    /// Join the region of a restarted server, the call that noticed the
+    /// restart fails with [`ServerGone`], listing the unanswered requests
+    Fail,
+    /// Join the region of a restarted server, and send the unanswered requests again
+    ///
+    /// Only safe if all requests are idempotent, e.g. no `FetchAdd`.
+    /// Requests with `InsertBytes` operations are not sent again, their values were written
+    /// to the region of the old server. The call that noticed the restart fails with
+    /// [`ServerGone`] listing them, if there are any
+    Replay,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Options {
+    pub wait: WaitPolicy,
+    pub reconnect: Reconnect,
+}
+
+/// The server did not signal for [`SERVER_LEASE`], it crashed, hangs or shut down
+#[derive(Debug, Default)]
+pub struct ServerGone {
+    /// Requests that were sent but not answered (and not replayed), only tracked when reconnecting
+    pub lost: Vec<u32>,
+}
+
+impl fmt::Display for ServerGone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the server is gone")?;
+        if !self.lost.is_empty() {
+            write!(f, ", {} requests were lost", self.lost.len())?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for ServerGone {}
+
+/// The server answered a request with [`ResponsePayload::Error`]
+#[derive(Debug)]
+pub struct RequestFailed {
+    pub code: ErrorCode,
+    pub detail: String,
+}
+
+impl RequestFailed {
+    /// Error for an unexpected `payload`, which is either an error response,
+    /// or a response to another kind of request
+    pub fn check(payload: ResponsePayload, request: &str, id: u32) -> anyhow::Error {
+        match payload {
+            ResponsePayload::Error { code, detail } => Self {
+                code,
+                detail: detail.to_string(),
+            }
+            .into(),
+            _ => anyhow!("invalid response for {request} {id}"),
+        }
+    }
+}
+
+impl fmt::Display for RequestFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code, self.detail)
+    }
+}
+
+impl Error for RequestFailed {}
+
+impl From<SlabError> for RequestFailed {
+    fn from(e: SlabError) -> Self {
+        Self {
+            code: e.code(),
+            detail: e.to_string(),
+        }
+    }
+}
+
+/// Number of requests after which a client checks whether it should move to another shard
+const REBALANCE_INTERVAL: usize = 1024;
+
+/// How long a client waits for a restarted server
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
 pub struct HashtableClient {
     client_id: u32,
+    options: Options,
+    conn: Connection,
+    /// Requests sent but not answered yet, in order, only tracked if the client reconnects
+    in_flight: VecDeque<(u32, RequestPayload)>,
+}
+
+/// Registration with a server, replaced when reconnecting
+struct Connection {
+    mailbox: u32,
+    /// Request queue this client sends to, see [`shared::ShardTable`]
+    shard: usize,
+    /// Requests sent since the last rebalancing
+    sent: usize,
+    /// Features negotiated with the server
+    features: Features,
     mem: Arc<SharedMemory<HashtableMemory>>,
     shutdown: Arc<AtomicBool>,
     responses: Receiver<ResponseData>,
@@ -25,126 +181,531 @@ pub struct HashtableClient {
 }
 
 impl HashtableClient {
+    /// # Safety
+    /// The server owning the shared memory region has to be running
+    /// with the same memory layout as this client
     pub unsafe fn init() -> anyhow::Result<Self> {
-        let mem = Arc::new(SharedMemory::join(DESCRIPTOR)?);
+        Self::init_with(Options::default())
+    }
+
+    /// Same as [`Self::init`], with custom options
+    ///
+    /// # Safety
+    /// See [`Self::init`], this also applies to servers the client reconnects to
+    pub unsafe fn init_with(options: Options) -> anyhow::Result<Self> {
+        let mem = SharedMemory::<HashtableMemory>::join(DESCRIPTOR)?;
+
+        let client_id = mem.get().allocate_client_id();
+
+        Ok(Self {
+            client_id,
+            options,
+            conn: Connection::open(mem, client_id, options.wait)?,
+            in_flight: VecDeque::new(),
+        })
+    }
+
+    /// Fails with [`ServerGone`] if the server does not make room in the request queue
+    ///
+    /// Requests the server would reject are not sent, the call fails with [`RequestFailed`]
+    pub fn send(&mut self, request: RequestPayload, id: u32) -> anyhow::Result<()> {
+        let missing = request.features().difference(self.conn.features);
+        let rejected = if missing != Features::NONE {
+            Some(RequestFailed {
+                code: ErrorCode::Unsupported,
+                detail: format!("the server does not support {missing}"),
+            })
+        } else {
+            // Batches and transactions are never truncated, they fail as a whole
+            request
+                .ops_len()
+                .filter(|(len, max)| len > max)
+                .map(|(len, max)| RequestFailed {
+                    code: ErrorCode::InvalidArgument,
+                    detail: format!("{len} operations exceed the maximum of {max}"),
+                })
+        };
+        if let Some(rejected) = rejected {
+            for blob in request.blobs() {
+                let _ = self.conn.mem.get().slab.free(blob, self.client_id);
+            }
+            return Err(rejected.into());
+        }
+        if self.options.reconnect != Reconnect::Never {
+            self.in_flight.push_back((id, request));
+        }
+        self.conn
+            .send(self.client_id, request, id)
+            .or_else(|e| self.recover(e))
+    }
+
+    /// Features both the server and this client support, requests needing others fail right away
+    pub fn features(&self) -> Features {
+        self.conn.features
+    }
+
+    pub fn recv(&mut self) -> anyhow::Result<ResponseData> {
+        loop {
+            match self.conn.responses.recv() {
+                Ok(t) => return Ok(self.answered(t)),
+                Err(_) => self.disconnected()?,
+            }
+        }
+    }
+
+    /// Wait for at most `timeout` for the next response
+    pub fn recv_timeout(&mut self, timeout: Duration) -> anyhow::Result<Option<ResponseData>> {
+        match self.conn.responses.recv_timeout(timeout) {
+            Ok(t) => Ok(Some(self.answered(t))),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(_) => self.disconnected().map(|()| None),
+        }
+    }
+
+    pub fn try_recv(&mut self) -> anyhow::Result<Option<ResponseData>> {
+        let val = self.conn.responses.try_recv();
+        match val {
+            Ok(t) => Ok(Some(self.answered(t))),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(_) => self.disconnected().map(|()| None),
+        }
+    }
+
+    /// Stop tracking a request once its (last) response arrived
+    fn answered(&mut self, response: ResponseData) -> ResponseData {
+        let more = matches!(
+            response.payload,
+            ResponsePayload::BucketContent { more: true, .. }
+        );
+        if !more {
+            if let Some(index) = self
+                .in_flight
+                .iter()
+                .position(|(id, _)| *id == response.request_id)
+            {
+                self.in_flight.remove(index);
+            }
+        }
+        response
+    }
+
+    /// The response thread stopped, returns `Ok` if the client reconnected
+    fn disconnected(&mut self) -> anyhow::Result<()> {
+        let error = match self.conn.response_thread.take().map(JoinHandle::join) {
+            Some(Ok(Err(e))) => e,
+            _ => anyhow!("recv error"),
+        };
+        self.recover(error)
+    }
+
+    /// Reconnect to a restarted server if the server is gone and reconnecting is enabled,
+    /// otherwise return the `error`
+    fn recover(&mut self, error: anyhow::Error) -> anyhow::Result<()> {
+        if !error.is::<ServerGone>() || self.options.reconnect == Reconnect::Never {
+            return Err(error);
+        }
+        // The old server is gone, the response thread fails soon if it did not already
+        let _ = self.conn.shutdown();
+
+        let generation = self.conn.mem.generation();
+        let deadline = Instant::now() + RECONNECT_TIMEOUT;
+        let mem = loop {
+            // Safety: Guaranteed by the caller of `init_with`
+            match unsafe { SharedMemory::<HashtableMemory>::join(DESCRIPTOR) } {
+                Ok(mem) if mem.generation() > generation && mem.is_alive(SERVER_LEASE) => {
+                    break mem
+                }
+                _ if Instant::now() >= deadline => return Err(error),
+                _ => thread::sleep(Duration::from_millis(100)),
+            }
+        };
+        // Ids are only unique within a region, the values of the old id died with the server
+        self.client_id = mem.get().allocate_client_id();
+        self.conn = Connection::open(mem, self.client_id, self.options.wait)?;
+        eprintln!("Reconnected to server {}", self.conn.mem.generation());
+
+        match self.options.reconnect {
+            Reconnect::Replay => {
+                let (stale, replayed): (VecDeque<_>, _) = self
+                    .in_flight
+                    .drain(..)
+                    .partition(|(_, request)| request.blobs().next().is_some());
+                self.in_flight = replayed;
+                for (id, request) in self.in_flight.clone() {
+                    self.conn.send(self.client_id, request, id)?;
+                }
+                if stale.is_empty() {
+                    return Ok(());
+                }
+                let lost = stale.into_iter().map(|(id, _)| id).collect();
+                Err(ServerGone { lost }.into())
+            }
+            _ => {
+                let lost = self.in_flight.drain(..).map(|(id, _)| id).collect();
+                Err(ServerGone { lost }.into())
+            }
+        }
+    }
+
+    /// Write a value to the slab, to send it with `InsertBytes`
+    ///
+    /// The server takes the value with the request, until then it counts against the slab
+    pub fn write_bytes(&self, value: &[u8]) -> anyhow::Result<Blob> {
+        let slab = &self.conn.mem.get().slab;
+        Ok(slab
+            .alloc(value, self.client_id)
+            .map_err(RequestFailed::from)?)
+    }
+
+    /// Copy a value of a `Bytes` response out of the slab, and free it
+    pub fn read_bytes(&self, blob: Blob) -> anyhow::Result<Vec<u8>> {
+        let slab = &self.conn.mem.get().slab;
+        Ok(slab
+            .take(blob, self.client_id)
+            .map_err(RequestFailed::from)?)
+    }
+
+    /// Look up a single value on the server
+    ///
+    /// This waits for the response, so it must not be mixed with
+    /// requests that are still in flight via [`Self::send`]
+    pub fn get(&mut self, key: KeyType, id: u32) -> anyhow::Result<Option<ValueType>> {
+        self.send(RequestPayload::Get(key), id)?;
+        let response = self.recv()?;
+        if response.request_id != id {
+            bail!("unexpected response for get request {id}");
+        }
+        match response.payload {
+            ResponsePayload::Value(v) => Ok(Some(v)),
+            ResponsePayload::NotFound => Ok(None),
+            ResponsePayload::Bytes(blob) => {
+                self.read_bytes(blob)?;
+                Err(wrong_type("a number", "bytes"))
+            }
+            other => Err(RequestFailed::check(other, "get request", id)),
+        }
+    }
 
-        let mut rng = rand::thread_rng();
-        let client_id: u32 = rng.gen();
+    /// Look up a single value stored as bytes
+    ///
+    /// Same as [`Self::get`], this must not be mixed with requests in flight
+    pub fn get_bytes(&mut self, key: KeyType, id: u32) -> anyhow::Result<Option<Vec<u8>>> {
+        self.send(RequestPayload::Get(key), id)?;
+        let response = self.recv()?;
+        if response.request_id != id {
+            bail!("unexpected response for get request {id}");
+        }
+        match response.payload {
+            ResponsePayload::Bytes(blob) => self.read_bytes(blob).map(Some),
+            ResponsePayload::NotFound => Ok(None),
+            ResponsePayload::Value(_) => Err(wrong_type("bytes", "a number")),
+            other => Err(RequestFailed::check(other, "get request", id)),
+        }
+    }
+
+    /// Run a conditional request (`InsertIfAbsent`, `ReplaceIfPresent` or `CompareAndSwap`),
+    /// returns whether it succeeded and the value after it
+    ///
+    /// Same as [`Self::get`], this must not be mixed with requests in flight
+    pub fn conditional(
+        &mut self,
+        request: RequestPayload,
+        id: u32,
+    ) -> anyhow::Result<(bool, Option<ValueType>)> {
+        self.send(request, id)?;
+        let response = self.recv()?;
+        if response.request_id != id {
+            bail!("unexpected response for conditional request {id}");
+        }
+        match response.payload {
+            ResponsePayload::Conditional { success, current } => Ok((success, current)),
+            other => Err(RequestFailed::check(other, "conditional request", id)),
+        }
+    }
+
+    /// Run a numeric request (`FetchAdd`, `FetchSub`, `FetchMin` or `FetchMax`),
+    /// returns the value before it
+    ///
+    /// Same as [`Self::get`], this must not be mixed with requests in flight
+    pub fn fetch(&mut self, request: RequestPayload, id: u32) -> anyhow::Result<ValueType> {
+        self.send(request, id)?;
+        let response = self.recv()?;
+        if response.request_id != id {
+            bail!("unexpected response for fetch request {id}");
+        }
+        match response.payload {
+            ResponsePayload::Fetched(previous) => Ok(previous),
+            other => Err(RequestFailed::check(other, "fetch request", id)),
+        }
+    }
 
+    /// Execute up to [`TRANSACTION_SIZE`] operations atomically, returns the result of every
+    /// operation, or the index of the operation that aborted the transaction and why
+    ///
+    /// Same as [`Self::get`], this must not be mixed with requests in flight
+    pub fn transaction(
+        &mut self,
+        ops: &[TransactionOp],
+        id: u32,
+    ) -> anyhow::Result<TransactionResult> {
+        if ops.len() > TRANSACTION_SIZE {
+            bail!("transactions are limited to {TRANSACTION_SIZE} operations");
+        }
+        let mut request = [TransactionOp::Get(KeyType::new()); TRANSACTION_SIZE];
+        request[..ops.len()].copy_from_slice(ops);
+        self.send(
+            RequestPayload::Transaction {
+                len: ops.len(),
+                ops: request,
+            },
+            id,
+        )?;
+
+        let response = self.recv()?;
+        if response.request_id != id {
+            bail!("unexpected response for transaction {id}");
+        }
+        match response.payload {
+            ResponsePayload::Committed { len, results } => Ok(Ok(results[..len].to_vec())),
+            ResponsePayload::Aborted { index, reason } => Ok(Err((index, reason))),
+            other => Err(RequestFailed::check(other, "transaction", id)),
+        }
+    }
+
+    /// Read the contents of the bucket containing `key`
+    ///
+    /// Same as [`Self::get`], this must not be mixed with requests in flight
+    pub fn read_bucket(
+        &mut self,
+        key: KeyType,
+        id: u32,
+    ) -> anyhow::Result<Vec<(KeyType, ValueType)>> {
+        self.send(RequestPayload::ReadBucket(key), id)?;
+        let mut bucket = Vec::new();
+        loop {
+            let response = self.recv()?;
+            if response.request_id != id {
+                bail!("unexpected response for read request {id}");
+            }
+            let ResponsePayload::BucketContent { len, more, data } = response.payload else {
+                return Err(RequestFailed::check(response.payload, "read request", id));
+            };
+            bucket.extend_from_slice(&data[..len]);
+            if !more {
+                return Ok(bucket);
+            }
+        }
+    }
+
+    pub fn shutdown(&mut self) -> anyhow::Result<()> {
+        self.conn.shutdown()
+    }
+}
+
+fn wrong_type(expected: &str, found: &str) -> anyhow::Error {
+    RequestFailed {
+        code: ErrorCode::WrongType,
+        detail: format!("expected {expected}, the entry holds {found}"),
+    }
+    .into()
+}
+
+impl Connection {
+    /// Register with the server of `mem`, and start receiving responses
+    fn open(
+        mem: SharedMemory<HashtableMemory>,
+        client_id: u32,
+        wait: WaitPolicy,
+    ) -> anyhow::Result<Self> {
+        let mem = Arc::new(mem);
+        let features = Features::from_bits(mem.features()).intersection(Features::ALL);
         let (snd_responses, responses) = mpsc::channel();
 
         let shutdown = Arc::new(AtomicBool::new(false));
         let s = shutdown.clone();
 
-        let mut read_next;
-        {
-            let mem: &HashtableMemory = mem.get();
-            let mut tail = mem.response_frame.tail.lock();
-            tail.rx_cnt = tail.rx_cnt.checked_add(1).expect("too many clients");
-            read_next = tail.pos;
-        }
+        let (mailbox, shard) = Self::register(mem.get(), client_id)?;
 
         let imem = mem.clone();
         let response_thread = thread::spawn(move || {
-            let is = &imem.get().response_frame;
+            let (shards, slab) = (&imem.get().shards, &imem.get().slab);
+            let mailbox = &imem.get().mailboxes[mailbox as usize];
+            let mut received = Vec::new();
+            let mut idle_since = None;
+            let mut last_heartbeat = Instant::now();
+
+            'outer: loop {
+                // The mailbox is consistent at all times, a worker advances `write`
+                // only after writing the response
+                let mut queue = mailbox.queue.lock().unwrap_or_else(OwnerDied::into_inner);
+                // Checked under the lock, `shutdown` signals while holding it
+                if s.load(Ordering::Relaxed) {
+                    break;
+                }
+                if queue.owner != Some(client_id) {
+                    bail!("the server reaped this client, its lease expired");
+                }
+                if last_heartbeat.elapsed() >= HEARTBEAT_INTERVAL {
+                    if !imem.is_alive(SERVER_LEASE) {
+                        return Err(ServerGone::default().into());
+                    }
+                    queue.heartbeat = unix_millis();
+                    last_heartbeat = Instant::now();
+                }
+                received.extend(iter::from_fn(|| queue.pop()));
 
-            while !s.load(Ordering::Relaxed) {
-                let msg = Self::inner_try_recv(&mut read_next, is);
-                if let Some(msg) = msg {
-                    if msg.client_id != client_id {
-                        continue;
+                if received.is_empty() {
+                    let idle = *idle_since.get_or_insert_with(Instant::now);
+                    if wait.should_park(idle.elapsed()) {
+                        // Woken up by the worker that writes the next response,
+                        // or to renew the registration
+                        drop(mailbox.ready.wait(queue, Some(HEARTBEAT_INTERVAL)));
+                    } else {
+                        drop(queue);
+                        hint::spin_loop();
                     }
+                    continue;
+                }
+                drop(queue);
+                mailbox.space.notify_all();
+                idle_since = None;
+
+                for frame in received.drain(..) {
+                    // A corrupt response fails its request, and not the whole client
+                    let msg =
+                        ResponseData::decode(&frame).unwrap_or_else(|malformed| ResponseData {
+                            client_id: malformed.client_id,
+                            request_id: malformed.request_id,
+                            payload: ResponsePayload::error(
+                                ErrorCode::Malformed,
+                                &format!("malformed response: {}", malformed.error),
+                            ),
+                        });
                     if snd_responses.send(msg).is_err() {
-                        break;
+                        break 'outer;
                     }
                 }
             }
 
-            // Safety: Shuts down the client, leaving the response stream
-            let mut tail = is.tail.lock();
-            tail.rx_cnt -= 1;
-            let until = tail.pos;
-
-            while read_next < until {
-                match Self::inner_try_recv(&mut read_next, is) {
-                    Some(_) => {}
-                    None => panic!("empty channel?"),
-                }
+            // Safety: Shuts down the client, unregistering the mailbox
+            // drops all responses which were not read yet
+            let mut queue = mailbox.queue.lock().unwrap_or_else(OwnerDied::into_inner);
+            if queue.owner == Some(client_id) {
+                queue.unregister(shards, slab);
             }
+            // Workers waiting for space drop their responses now
+            mailbox.space.notify_all();
             eprintln!("Left session");
 
             anyhow::Ok(())
         });
 
         Ok(Self {
-            client_id,
+            mailbox,
+            shard,
+            sent: 0,
+            features,
             mem,
+            shutdown,
             responses,
             response_thread: Some(response_thread),
-            shutdown,
         })
     }
 
-    pub fn send(&mut self, request: RequestPayload, id: u32) {
-        let os = &self.mem.get().request_frame;
-        os.space.wait();
-
-        let mut queue = os.queue.lock();
-
-        let qid = queue.write & (REQ_BUFFER_SIZE - 1);
-        queue.buffer[qid].write(RequestData {
-            client_id: self.client_id,
-            request_id: id,
-            payload: request,
-        });
-
-        queue.write = queue.write.wrapping_add(1);
-        os.count.post();
-    }
-
-    pub fn recv(&mut self) -> anyhow::Result<ResponseData> {
-        let val = self.responses.recv();
-        match val {
-            Ok(t) => Ok(t),
-            Err(_) => bail!("recv error"),
+    /// Register a free mailbox for `client_id`, returns its index and the shard of the client
+    fn register(mem: &HashtableMemory, client_id: u32) -> anyhow::Result<(u32, usize)> {
+        for (index, mailbox) in mem.mailboxes.iter().enumerate() {
+            let mut queue = mailbox.queue.lock().unwrap_or_else(|died| {
+                // The client that owned the mailbox died while reading it
+                let mut queue = died.into_inner();
+                queue.unregister(&mem.shards, &mem.slab);
+                queue
+            });
+            if queue.owner.is_none() {
+                queue.register(client_id, &mem.shards);
+                return Ok((index as u32, queue.shard));
+            }
         }
+        bail!("too many clients, all {MAX_CLIENTS} mailboxes are in use")
     }
 
-    pub fn try_recv(&mut self) -> anyhow::Result<Option<ResponseData>> {
-        let val = self.responses.try_recv();
-        match val {
-            Ok(t) => Ok(Some(t)),
-            Err(TryRecvError::Empty) => Ok(None),
-            Err(_) => bail!("recv error"),
+    fn send(&mut self, client_id: u32, request: RequestPayload, id: u32) -> anyhow::Result<()> {
+        let mem = self.mem.get();
+        self.sent += 1;
+        if self.sent == REBALANCE_INTERVAL {
+            // Responses go to the mailbox, so requests in flight are unaffected
+            let mailbox = &mem.mailboxes[self.mailbox as usize];
+            let mut queue = mailbox.queue.lock().unwrap_or_else(OwnerDied::into_inner);
+            if queue.owner == Some(client_id) {
+                queue.shard = mem.shards.rebalance(queue.shard);
+                self.shard = queue.shard;
+            }
+            self.sent = 0;
         }
-    }
 
-    fn inner_try_recv(read_next: &mut u64, is: &ResponseFrame) -> Option<ResponseData> {
-        let id = (*read_next & (RES_BUFFER_SIZE - 1) as u64) as usize;
-        let lock = &is.buffer[id];
-        let slot = lock.read();
+        let os = &mem.request_frames[self.shard];
+        let request = RequestData {
+            client_id,
+            mailbox: self.mailbox,
+            request_id: id,
+            payload: request,
+        };
+        // Waiting is interrupted regularly, to check whether the server is still alive
+        let server_gone = || -> anyhow::Result<()> {
+            match self.mem.is_alive(SERVER_LEASE) {
+                true => Ok(()),
+                false => Err(ServerGone::default().into()),
+            }
+        };
 
-        if slot.pos != *read_next {
-            drop(slot);
-            // Backoff to avoid thrashing
-            thread::sleep(Duration::from_nanos(30));
-            return None;
+        if mem.queue_kind == QueueKind::LockFree {
+            let mut frame = request.encode();
+            while let Err(f) = os.ring.push_timeout(frame, HEARTBEAT_INTERVAL) {
+                frame = f;
+                server_gone()?;
+            }
+            return Ok(());
         }
 
-        *read_next = read_next.wrapping_add(1);
-        let value = unsafe { slot.val.assume_init_read() };
-        let orig_rem = slot.rem.fetch_sub(1, Ordering::Relaxed);
-        if orig_rem == 1 {
-            // Last receiver, allow overwriting of slot
-            is.space.post();
-        }
+        // Whether there is room is decided by the queue itself, and the permit for the slot is taken
+        // while holding the lock, so a client dying before it published its request leaks no space
+        let mut permit = false;
+        let mut queue = loop {
+            let queue = os.lock_queue();
+            if queue.write.wrapping_sub(queue.read) < REQ_BUFFER_SIZE {
+                if !permit {
+                    // Only missing if a permit leaked, which merely delays waking up clients
+                    let _ = os.space.try_wait();
+                }
+                break queue;
+            }
+            drop(queue);
+            // A permit taken while waiting was used up by the client that took the slot
+            permit = false;
+            match os.space.wait_timeout(HEARTBEAT_INTERVAL) {
+                Ok(()) => permit = true,
+                Err(SyncError::TimedOut) => server_gone()?,
+                Err(e) => return Err(e.into()),
+            }
+        };
 
-        return Some(value);
+        let qid = queue.write & (REQ_BUFFER_SIZE - 1);
+        request.encode_into(&mut queue.buffer[qid]);
+
+        queue.write = queue.write.wrapping_add(1);
+        os.count.post();
+        queue.posted = queue.write;
+        Ok(())
     }
 
-    pub fn shutdown(&mut self) -> anyhow::Result<()> {
-        self.shutdown.store(true, Ordering::Relaxed);
+    fn shutdown(&mut self) -> anyhow::Result<()> {
         if let Some(t) = self.response_thread.take() {
+            let mailbox = &self.mem.get().mailboxes[self.mailbox as usize];
+            let queue = mailbox.queue.lock().unwrap_or_else(OwnerDied::into_inner);
+            self.shutdown.store(true, Ordering::Relaxed);
+            mailbox.ready.notify_all();
+            drop(queue);
+
             t.join().unwrap()?;
         }
         Ok(())
@@ -153,6 +714,8 @@ impl HashtableClient {
 
 impl Drop for HashtableClient {
     fn drop(&mut self) {
-        self.shutdown().unwrap()
+        if let Err(e) = self.shutdown() {
+            eprintln!("Client failed: {e}");
+        }
     }
 }
@@ -4,18 +4,24 @@ use std::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
+    time::Duration,
 };
 
-use anyhow::bail;
+use anyhow::{anyhow, bail};
+use batch::Batcher;
 use clap::Parser;
-use client::HashtableClient;
+use client::{HashtableClient, Options};
 use rand::Rng;
 
+pub mod batch;
 pub mod cli;
 pub mod client;
 
 use cli::Args;
-use shared::{KeyType, RequestPayload, ResponsePayload};
+use shared::{BatchOp, BatchResult, KeyType, RequestPayload, ResponsePayload};
+
+/// How often waiting for responses is interrupted to check for CTRL-C
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(10);
 
 fn main() -> anyhow::Result<()> {
     let args = Args::parse();
@@ -32,11 +38,23 @@ fn main() -> anyhow::Result<()> {
         }
     })?;
 
-    let mut client = unsafe { HashtableClient::init()? };
+    let mut client = unsafe {
+        HashtableClient::init_with(Options {
+            wait: args.wait,
+            reconnect: args.reconnect,
+        })?
+    };
 
     if args.debug_print {
-        client.send(RequestPayload::PrintHashmap, 0);
+        client.send(RequestPayload::PrintHashmap, 0)?;
         let _ = client.recv();
+    } else if args.snapshot {
+        client.send(RequestPayload::Snapshot, 0)?;
+        match client.recv()?.payload {
+            ResponsePayload::Snapshotted { entries } => println!("Snapshot of {entries} entries"),
+            ResponsePayload::Error { code, detail } => bail!("Snapshot failed: {code}: {detail}"),
+            other => bail!("Unexpected response: {other:?}"),
+        }
     } else {
         benchmark(&args, &mut client, exit_signal)?;
     }
@@ -53,7 +71,7 @@ fn benchmark(
 
     let recv = |client: &mut HashtableClient| {
         let response = loop {
-            match client.try_recv()? {
+            match client.recv_timeout(EXIT_POLL_INTERVAL)? {
                 Some(response) => break response,
                 None => {
                     if exit_signal.load(Ordering::Relaxed) {
@@ -68,6 +86,40 @@ fn benchmark(
 
     let send = |client: &mut HashtableClient, request, id| client.send(request, id);
 
+    // Inserts and deletes can be batched, bucket contents are always streamed
+    let mut batcher = (args.batch_size > 1)
+        .then(|| Batcher::new(args.batch_size, Duration::from_micros(args.batch_delay)));
+
+    let send_op = |client: &mut HashtableClient, batcher: &mut Option<Batcher>, op: BatchOp, id| {
+        match batcher {
+            Some(batcher) => batcher.push(client, op, id),
+            None => client.send(op.into(), id),
+        }
+    };
+
+    let recv_op = |client: &mut HashtableClient, batcher: &mut Option<Batcher>| loop {
+        let result = match batcher {
+            Some(batcher) => batcher.recv_timeout(client, EXIT_POLL_INTERVAL)?,
+            None => match client.recv_timeout(EXIT_POLL_INTERVAL)? {
+                Some(response) => Some((
+                    response.request_id,
+                    BatchResult::try_from(response.payload)
+                        .map_err(|p| anyhow!("invalid response {p:?}"))?,
+                )),
+                None => None,
+            },
+        };
+        match result {
+            Some(result) => break anyhow::Ok(result),
+            None => {
+                if exit_signal.load(Ordering::Relaxed) {
+                    client.shutdown()?;
+                    std::process::exit(0);
+                }
+            }
+        }
+    };
+
     // Outer Iterations: Number of runs: Insert Read Delete
     let mut outer_iter = 0;
     // Inner Iterations: Number of values to be inserted
@@ -87,11 +139,20 @@ fn benchmark(
             break;
         }
 
-        for i in 0..inner_iter {
+        for key in buffer.iter_mut() {
             let suffix: u32 = rng.gen();
-            let name = format!("ht{seed}{suffix}");
-            buffer[i] = KeyType::new();
-            buffer[i].push_str(&name);
+            *key = if args.binary_keys {
+                // Never valid UTF-8, due to the leading byte
+                let bytes = [
+                    [0xff].as_slice(),
+                    &seed.to_le_bytes(),
+                    &suffix.to_le_bytes(),
+                ]
+                .concat();
+                KeyType::try_from(&bytes[..])?
+            } else {
+                KeyType::try_from(format!("ht{seed}{suffix}").as_str())?
+            };
         }
 
         let mut copy = buffer.clone();
@@ -101,63 +162,94 @@ fn benchmark(
         let mut duplicates = (buffer.len() - copy.len()) as isize;
 
         // Insert random numbers
-        for i in 0..inner_iter {
-            let val = buffer[i];
-            send(client, RequestPayload::Insert(val, i as u32), i as u32);
+        for (i, &key) in buffer.iter().enumerate() {
+            let op = match args.value_size {
+                Some(size) => BatchOp::InsertBytes(key, client.write_bytes(&value(i, size))?),
+                None => BatchOp::Insert(key, i as u32),
+            };
+            send_op(client, &mut batcher, op, i as u32)?;
+        }
+        if let Some(batcher) = &mut batcher {
+            batcher.flush(client)?;
         }
 
         // Split send and receive to allow for server concurrency
 
-        for i in 0..inner_iter {
-            let response = recv(client)?;
-            let ResponsePayload::Inserted = response.payload else {
-                bail!("Invalid response for insert request {i}");
-            };
+        for _ in 0..inner_iter {
+            let (id, result) = recv_op(client, &mut batcher)?;
+            match result {
+                BatchResult::Inserted => {}
+                BatchResult::Error(code) => bail!("Insert request {id} failed: {code}"),
+                _ => bail!("Invalid response for insert request {id}"),
+            }
         }
 
         // Verify that all values are correct
-        // Send read request to HashMap
-        for i in 0..inner_iter {
-            send(client, RequestPayload::ReadBucket(buffer[i]), i as u32);
-        }
+        if let Some(size) = args.value_size {
+            for (i, &key) in buffer.iter().enumerate() {
+                send(client, RequestPayload::Get(key), i as u32)?;
+            }
+            for _ in 0..inner_iter {
+                let response = recv(client)?;
+                let id = response.request_id;
+                let ResponsePayload::Bytes(blob) = response.payload else {
+                    bail!("Invalid response for get request {id}");
+                };
+                if client.read_bytes(blob)? != value(id as usize, size) {
+                    bail!("wrong value for key {}", buffer[id as usize]);
+                }
+            }
+        } else {
+            // Send read request to HashMap
+            for (i, &key) in buffer.iter().enumerate() {
+                send(client, RequestPayload::ReadBucket(key), i as u32)?;
+            }
 
-        // Get read responses
-        for i in 0..inner_iter {
-            let response = recv(client)?;
-            let ResponsePayload::BucketContent { len, data } = response.payload else {
-                bail!("Invalid response for read request {i}");
-            };
+            // Get read responses, large buckets span multiple responses
+            let mut complete = 0;
+            while complete < inner_iter {
+                let response = recv(client)?;
+                let id = response.request_id;
+                let ResponsePayload::BucketContent { len, more, data } = response.payload else {
+                    bail!("Invalid response for read request {id}");
+                };
 
-            rmap.insert(response.request_id, data[..len].to_vec());
-        }
+                rmap.entry(id)
+                    .or_insert_with(Vec::new)
+                    .extend_from_slice(&data[..len]);
+                if !more {
+                    complete += 1;
+                }
+            }
 
-        // Compare for equality, bucket must contain value
-        for i in 0..inner_iter {
-            let expected = buffer[i];
-            let Some(v) = rmap.get(&(i as u32)) else {
-                panic!("Missing response for read request {i}");
-            };
-            let value = v.iter().find(|(k, v)| *k == expected && *v == i as u32);
-            let Some(_) = value else {
-                bail!("missing value in bucket {expected}");
-            };
+            // Compare for equality, bucket must contain value
+            for (i, &expected) in buffer.iter().enumerate() {
+                let Some(v) = rmap.get(&(i as u32)) else {
+                    panic!("Missing response for read request {i}");
+                };
+                let value = v.iter().find(|(k, v)| *k == expected && *v == i as u32);
+                let Some(_) = value else {
+                    bail!("missing value in bucket {expected}");
+                };
+            }
         }
 
         // Delete values again
-        for i in 0..inner_iter {
-            let val = buffer[i];
-
-            send(client, RequestPayload::Delete(val), i as u32);
+        for (i, &key) in buffer.iter().enumerate() {
+            send_op(client, &mut batcher, BatchOp::Delete(key), i as u32)?;
+        }
+        if let Some(batcher) = &mut batcher {
+            batcher.flush(client)?;
         }
 
-        for i in 0..inner_iter {
-            let response = recv(client)?;
-            match response.payload {
-                ResponsePayload::Deleted => continue,
-                ResponsePayload::NotFound => {
+        for _ in 0..inner_iter {
+            let (id, result) = recv_op(client, &mut batcher)?;
+            match result {
+                BatchResult::Deleted => continue,
+                BatchResult::NotFound => {
                     duplicates -= 1;
                     if duplicates < 0 {
-                        bail!("element was wrongly deleted: {i}");
+                        bail!("element was wrongly deleted: {id}");
                     }
                 }
                 _ => bail!("invalid deletion response"),
@@ -169,3 +261,13 @@ fn benchmark(
     }
     Ok(())
 }
+
+/// Value of the `i`-th insert, when values are stored as bytes
+fn value(i: usize, size: usize) -> Vec<u8> {
+    (i as u32)
+        .to_le_bytes()
+        .into_iter()
+        .cycle()
+        .take(size)
+        .collect()
+}
@@ -1,12 +1,85 @@
-use clap::Parser;
+use std::path::PathBuf;
+
+use clap::{Parser, ValueEnum};
+use shared::{QueueKind, MAX_KEY_SIZE, MAX_VALUE_SIZE};
+
+use crate::{hash_table::eviction::EvictionPolicy, wal::SyncPolicy};
 
 /// HashTable Server
 #[derive(Debug, Clone, Parser)]
 pub struct Args {
-    /// Size of hash table
+    /// Initial (and minimum) size of hash table
+    ///
+    /// The table grows and shrinks automatically based on its load factor
     #[arg(short)]
     pub size: usize,
     /// Number of parallel processing threads
     #[arg(short, default_value_t = 1)]
     pub num_threads: usize,
+    /// Number of request queues, clients are spread across them
+    ///
+    /// Every thread serves one queue and helps with the others when it is idle
+    #[arg(long, default_value_t = 1)]
+    pub shards: usize,
+    /// Implementation of the request queues
+    #[arg(long, value_enum, default_value_t = Queue::Mutex)]
+    pub queue: Queue,
+    /// Storage backend of the hash table buckets
+    #[arg(short, long, value_enum, default_value_t = Backend::Chain)]
+    pub backend: Backend,
+    /// Maximum number of entries, inserts evict other entries once it is reached
+    #[arg(long)]
+    pub max_entries: Option<usize>,
+    /// Maximum (approximate) memory used by entries, including values stored as bytes
+    #[arg(long)]
+    pub max_bytes: Option<usize>,
+    /// Maximum length of values stored as bytes, at most 65536
+    #[arg(long, default_value_t = MAX_VALUE_SIZE)]
+    pub max_value_size: usize,
+    /// Maximum length of keys, at most 128 bytes
+    #[arg(long, default_value_t = MAX_KEY_SIZE)]
+    pub max_key_size: usize,
+    /// Which entries to evict, if the table is bounded
+    #[arg(long, value_enum, default_value_t = EvictionPolicy::Lru)]
+    pub eviction: EvictionPolicy,
+    /// File to write snapshots to, on shutdown, SIGHUP and on request of a client
+    #[arg(long)]
+    pub snapshot: Option<PathBuf>,
+    /// Snapshot to load on startup, the `snapshot` is loaded by default if there is a `wal`
+    #[arg(long)]
+    pub restore: Option<PathBuf>,
+    /// Append all mutations to this write-ahead log, and replay it on startup
+    #[arg(long)]
+    pub wal: Option<PathBuf>,
+    /// When to fsync the write-ahead log: `always`, `never` or every <N> milliseconds
+    #[arg(long, default_value = "always")]
+    pub wal_sync: SyncPolicy,
+    /// Fold the write-ahead log into the snapshot once it grew to this many bytes
+    #[arg(long, default_value_t = 64 << 20)]
+    pub wal_compact_size: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Backend {
+    /// Linked lists, one entry per bucket on average
+    Chain,
+    /// SwissTable-style open addressing, with multiple entries per bucket (shard)
+    Swiss,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Queue {
+    /// Ring buffer guarded by a process-shared mutex
+    Mutex,
+    /// Lock-free ring buffer, which only blocks while it is empty or full
+    LockFree,
+}
+
+impl From<Queue> for QueueKind {
+    fn from(queue: Queue) -> Self {
+        match queue {
+            Queue::Mutex => QueueKind::Mutex,
+            Queue::LockFree => QueueKind::LockFree,
+        }
+    }
 }
@@ -1,116 +1,1124 @@
 use std::{
     collections::LinkedList,
-    fmt::Debug,
-    hash::{BuildHasher, Hash, Hasher, RandomState},
+    error::Error,
+    fmt::{self, Debug},
+    hash::{BuildHasher, Hash, RandomState},
     iter::repeat_with,
-    sync::{RwLock, RwLockReadGuard},
+    marker::PhantomData,
+    mem, slice,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
-pub type Bucket<K, V> = LinkedList<Node<K, V>>;
+pub mod eviction;
+pub mod swiss;
 
-pub struct HashTable<K, V, S = RandomState>
+use eviction::{Access, Eviction, EVICTION_ATTEMPTS, EVICTION_SAMPLES};
+use rand::Rng;
+pub use swiss::SwissBucket;
+
+/// Storage of a single bucket (or shard) of the table, protected by its own lock
+///
+/// All methods receive the full hash of the key, backends are free to ignore it
+pub trait Bucket<K, V>: Default + Send + Sync {
+    /// Average number of entries per bucket the table aims for
+    const LOAD_FACTOR: f64;
+    /// Approximate memory used per entry, in bytes
+    const ENTRY_SIZE: usize;
+
+    fn find(&self, hash: u64, key: &K) -> Option<&Node<K, V>>;
+    fn find_mut(&mut self, hash: u64, key: &K) -> Option<&mut Node<K, V>>;
+    /// Add a node, the key must not be present yet
+    fn push(&mut self, hash: u64, node: Node<K, V>);
+    fn remove(&mut self, hash: u64, key: &K) -> Option<Node<K, V>>;
+    /// Keep only the nodes matching `f`, returns the number of removed nodes
+    fn retain(&mut self, f: impl FnMut(&Node<K, V>) -> bool) -> usize;
+    fn iter<'a>(&'a self) -> impl Iterator<Item = &'a Node<K, V>>
+    where
+        K: 'a,
+        V: 'a;
+    /// Take all nodes out of the bucket
+    fn drain(&mut self) -> impl Iterator<Item = Node<K, V>>;
+    fn is_empty(&self) -> bool;
+}
+
+/// Memory a value owns outside of its node, it counts against [`Eviction::max_bytes`]
+pub trait HeapSize {
+    fn heap_size(&self) -> usize {
+        0
+    }
+}
+
+impl HeapSize for u32 {}
+impl HeapSize for &str {}
+
+pub type ChainBucket<K, V> = LinkedList<Node<K, V>>;
+
+impl<K, V> Bucket<K, V> for ChainBucket<K, V>
+where
+    K: Eq + Send + Sync,
+    V: Send + Sync,
+{
+    const LOAD_FACTOR: f64 = 1.0;
+    // List node with two links
+    const ENTRY_SIZE: usize = size_of::<Node<K, V>>() + 2 * size_of::<usize>();
+
+    fn find(&self, _hash: u64, key: &K) -> Option<&Node<K, V>> {
+        LinkedList::iter(self).find(|n| n.k == *key)
+    }
+
+    fn find_mut(&mut self, _hash: u64, key: &K) -> Option<&mut Node<K, V>> {
+        self.iter_mut().find(|n| n.k == *key)
+    }
+
+    fn push(&mut self, _hash: u64, node: Node<K, V>) {
+        self.push_front(node);
+    }
+
+    fn remove(&mut self, _hash: u64, key: &K) -> Option<Node<K, V>> {
+        let split_index = LinkedList::iter(self).position(|n| n.k == *key)?;
+        let mut tail = self.split_off(split_index);
+        let node = tail.pop_front().expect("list should have item");
+        self.append(&mut tail);
+        Some(node)
+    }
+
+    fn retain(&mut self, f: impl FnMut(&Node<K, V>) -> bool) -> usize {
+        let len = self.len();
+        *self = mem::take(self).into_iter().filter(f).collect();
+        len - self.len()
+    }
+
+    fn iter<'a>(&'a self) -> impl Iterator<Item = &'a Node<K, V>>
+    where
+        K: 'a,
+        V: 'a,
+    {
+        LinkedList::iter(self)
+    }
+
+    fn drain(&mut self) -> impl Iterator<Item = Node<K, V>> {
+        mem::take(self).into_iter()
+    }
+
+    fn is_empty(&self) -> bool {
+        LinkedList::is_empty(self)
+    }
+}
+
+type Table<B> = Box<[RwLock<B>]>;
+
+/// Shrink once less than a quarter of the target load is reached
+const SHRINK_LOAD_RATIO: f64 = 0.25;
+/// Number of old buckets every operation moves while a resize is running
+const MIGRATE_PER_OP: usize = 2;
+/// Number of buckets scanned for expired entries at once
+const REAP_CHUNK: usize = 256;
+
+pub struct HashTable<K, V, S = RandomState, B = ChainBucket<K, V>>
 where
     K: Hash + Eq,
     S: BuildHasher,
+    B: Bucket<K, V>,
 {
-    content: Vec<RwLock<Bucket<K, V>>>,
+    tables: RwLock<Tables<B>>,
+    len: AtomicUsize,
+    /// Sum of the [`HeapSize`] of all values
+    heap_size: AtomicUsize,
+    min_size: usize,
+    eviction: Option<Eviction>,
+    evictions: AtomicU64,
+    /// Start of the table, access times are stored relative to it
+    created: Instant,
     state: S,
+    _marker: PhantomData<(K, V)>,
+}
+
+/// The active table, and the table it replaces while a resize is running
+///
+/// The outer lock is only taken for writing to swap tables, which is O(1).
+/// Rehashing is done bucket by bucket by the operations themselves.
+///
+/// Poisoned locks are recovered, a request that panicked must not break the table for
+/// all later ones. The server keeps its workers running after panics.
+struct Tables<B> {
+    current: Table<B>,
+    migration: Option<Migration<B>>,
+}
+
+struct Migration<B> {
+    from: Table<B>,
+    /// Set (under the bucket's write lock) once a bucket has been emptied
+    moved: Box<[AtomicBool]>,
+    /// Next bucket to be claimed by a helping operation
+    cursor: AtomicUsize,
+    remaining: AtomicUsize,
 }
 
-impl<K, V> HashTable<K, V, RandomState>
+impl<K, V, B> HashTable<K, V, RandomState, B>
 where
-    K: Hash + Eq,
-    V: Clone,
+    K: Hash + Eq + Clone,
+    V: Clone + HeapSize,
+    B: Bucket<K, V>,
 {
+    /// Create a table with `size` buckets, it never shrinks below that size
     pub fn new(size: usize) -> Self {
+        let size = size.max(1);
         Self {
-            content: repeat_with(|| RwLock::new(LinkedList::new()))
-                .take(size)
-                .collect(),
+            tables: RwLock::new(Tables {
+                current: new_table(size),
+                migration: None,
+            }),
+            len: AtomicUsize::new(0),
+            heap_size: AtomicUsize::new(0),
+            min_size: size,
+            eviction: None,
+            evictions: AtomicU64::new(0),
+            created: Instant::now(),
             state: RandomState::new(),
+            _marker: PhantomData,
         }
     }
 
-    pub fn insert(&self, key: K, val: V) {
+    /// Limit the number of entries or their memory, further inserts evict entries chosen by the policy
+    pub fn with_eviction(mut self, eviction: Eviction) -> Self {
+        self.eviction = Some(eviction);
+        self
+    }
+
+    /// Returns the keys of the entries that had to be evicted for the insertion
+    pub fn insert(&self, key: K, val: V) -> Vec<K> {
+        self.insert_expiring(key, val, None)
+    }
+
+    /// Insert (or overwrite) an entry, which expires after `ttl` if set
+    ///
+    /// Returns the keys of the entries that had to be evicted for the insertion
+    pub fn insert_with_ttl(
+        &self,
+        key: K,
+        val: V,
+        ttl: Option<Duration>,
+    ) -> Result<Vec<K>, TtlOverflow> {
+        let expires = expiry(Instant::now(), ttl)?;
+        Ok(self.insert_expiring(key, val, expires))
+    }
+
+    fn insert_expiring(&self, key: K, val: V, expires: Option<Instant>) -> Vec<K> {
         let h = self.hash(&key);
-        let index = self.get_index(h);
-        let mut target = self.content[index].write().unwrap();
-        let existing = target.iter_mut().find(|n| n.k == key);
-        if let Some(existing) = existing {
-            existing.v = val;
-        } else {
-            target.push_front(Node { k: key, v: val });
+        let tick = self.tick();
+        // The new entry must not be evicted right away
+        let inserted = self.eviction.is_some().then(|| key.clone());
+        self.update(h, |target| {
+            let existing = target.find_mut(h, &key);
+            if let Some(existing) = existing {
+                self.set_value(existing, val);
+                existing.expires = expires;
+                self.record_access(existing);
+            } else {
+                let node = Node {
+                    k: key,
+                    v: val,
+                    expires,
+                    access: Access::new(tick),
+                };
+                self.added(&node);
+                target.push(h, node);
+            }
+        });
+
+        self.evict_if_full(inserted)
+    }
+
+    /// Insert an entry only if there is no (live) entry for `key` yet
+    pub fn insert_if_absent(&self, key: K, val: V) -> Conditional<K, V> {
+        self.conditional(key, |current| current.is_none().then_some(val))
+    }
+
+    /// Overwrite the value of an existing entry, keeping its expiry
+    pub fn replace_if_present(&self, key: K, val: V) -> Conditional<K, V> {
+        self.conditional(key, |current| current.is_some().then_some(val))
+    }
+
+    /// Overwrite the value of an existing entry if it is `expected`, keeping its expiry
+    pub fn compare_and_swap(&self, key: K, expected: V, new: V) -> Conditional<K, V>
+    where
+        V: PartialEq,
+    {
+        self.conditional(key, |current| (current == Some(&expected)).then_some(new))
+    }
+
+    /// Replace the value with `f(current)` under the bucket's write lock,
+    /// missing entries are created with `f(default)` and do not expire
+    ///
+    /// If `f` returns `None`, the table is left unchanged and `None` is returned
+    pub fn fetch_update(
+        &self,
+        key: K,
+        default: V,
+        f: impl FnOnce(&V) -> Option<V>,
+    ) -> Option<Fetched<K, V>> {
+        let (result, evicted) = self.modify(key, |current| match f(current.unwrap_or(&default)) {
+            Some(new) => (Some(new.clone()), Some((current.cloned(), new))),
+            None => (None, None),
+        });
+        let (previous, current) = result?;
+        Some(Fetched {
+            previous,
+            current,
+            evicted,
+        })
+    }
+
+    /// Store the value returned by `f` for the current one, if any
+    fn conditional(&self, key: K, f: impl FnOnce(Option<&V>) -> Option<V>) -> Conditional<K, V> {
+        let ((success, current), evicted) = self.modify(key, |current| match f(current) {
+            Some(new) => (Some(new.clone()), (true, Some(new))),
+            None => (None, (false, current.cloned())),
+        });
+        Conditional {
+            success,
+            current,
+            evicted,
         }
     }
 
+    /// Decide on a new value based on the current (live) one, under the bucket's write lock
+    ///
+    /// `f` returns the value to store (`None` leaves the table unchanged) and a result.
+    /// New entries do not expire, replaced values keep the expiry of their entry.
+    /// Returns the result, and the keys of the entries that had to be evicted
+    fn modify<R>(&self, key: K, f: impl FnOnce(Option<&V>) -> (Option<V>, R)) -> (R, Vec<K>) {
+        let h = self.hash(&key);
+        let now = Instant::now();
+        let tick = self.tick();
+        let inserted = self.eviction.is_some().then(|| key.clone());
+        let result = self.update(h, |target| {
+            // Expired entries are treated as absent
+            self.remove_expired_node(target, h, &key, now);
+
+            if let Some(existing) = target.find_mut(h, &key) {
+                let (new, result) = f(Some(&existing.v));
+                if let Some(new) = new {
+                    self.set_value(existing, new);
+                }
+                self.record_access(existing);
+                return result;
+            }
+
+            let (new, result) = f(None);
+            let Some(new) = new else {
+                return result;
+            };
+            let node = Node {
+                k: key,
+                v: new,
+                expires: None,
+                access: Access::new(tick),
+            };
+            self.added(&node);
+            target.push(h, node);
+            result
+        });
+        (result, self.evict_if_full(inserted))
+    }
+
+    /// Evict entries (but not the `inserted` one), until the table is within its bounds again
+    fn evict_if_full(&self, inserted: Option<K>) -> Vec<K> {
+        match (self.eviction, inserted) {
+            (Some(eviction), Some(inserted)) => {
+                self.evict_while_full(&eviction, slice::from_ref(&inserted))
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Evict entries (but none of `except`) while the table exceeds a bound of `eviction`
+    fn evict_while_full(&self, eviction: &Eviction, except: &[K]) -> Vec<K> {
+        let mut evicted = Vec::new();
+        while eviction.max_entries.is_some_and(|max| self.len() > max)
+            || eviction.max_bytes.is_some_and(|max| self.bytes() > max)
+        {
+            match self.evict(except) {
+                Some(key) => evicted.push(key),
+                None => break,
+            }
+        }
+        evicted
+    }
+
+    /// Run `f` with exclusive access to the entries of `keys`, its writes are applied atomically
+    /// if it returns `Ok`, and discarded otherwise
+    ///
+    /// The buckets of all keys are write locked in ascending order,
+    /// so concurrent transactions cannot deadlock. Returns the result of `f`,
+    /// and the keys of the entries that had to be evicted for its insertions
+    pub fn transaction<R, E>(
+        &self,
+        keys: &[K],
+        f: impl FnOnce(&mut Transaction<'_, K, V, B>) -> Result<R, E>,
+    ) -> Result<(R, Vec<K>), E> {
+        let tables = self.tables.read().unwrap_or_else(PoisonError::into_inner);
+        let mut indices: Vec<_> = keys
+            .iter()
+            .map(|k| tables.prepare(self.hash(k), &self.state))
+            .collect();
+        indices.sort_unstable();
+        indices.dedup();
+
+        let mut transaction = Transaction {
+            buckets: indices
+                .into_iter()
+                .map(|i| {
+                    (
+                        i,
+                        tables.current[i]
+                            .write()
+                            .unwrap_or_else(PoisonError::into_inner),
+                    )
+                })
+                .collect(),
+            num_buckets: tables.current.len(),
+            state: &self.state,
+            now: Instant::now(),
+            staged: Vec::new(),
+        };
+        let result = f(&mut transaction);
+        let inserted = match &result {
+            Ok(_) => transaction.commit(self),
+            Err(_) => Vec::new(),
+        };
+        drop(transaction);
+        self.finish_op(tables);
+
+        let result = result?;
+        let evicted = match self.eviction {
+            Some(eviction) => self.evict_while_full(&eviction, &inserted),
+            None => Vec::new(),
+        };
+        Ok((result, evicted))
+    }
+
     pub fn get(&self, key: K) -> Option<V> {
         let h = self.hash(&key);
-        let index = self.get_index(h);
-        let target = self.content[index].read().unwrap();
-        target.iter().find(|n| n.k == key).map(|n| n.v.clone())
+        let now = Instant::now();
+        let tables = self.tables.read().unwrap_or_else(PoisonError::into_inner);
+
+        // Some(None) if the entry has expired
+        let found = 'found: {
+            // Reads are served from the old table if the bucket was not moved yet
+            if let Some(migration) = &tables.migration {
+                let source = get_index(h, migration.from.len());
+                let old = migration.from[source]
+                    .read()
+                    .unwrap_or_else(PoisonError::into_inner);
+                if !migration.moved[source].load(Ordering::Relaxed) {
+                    break 'found old.find(h, &key).map(|n| self.read_live(n, now));
+                }
+            }
+
+            let target = tables.current[get_index(h, tables.current.len())]
+                .read()
+                .unwrap_or_else(PoisonError::into_inner);
+            target.find(h, &key).map(|n| self.read_live(n, now))
+        };
+        drop(tables);
+
+        if let Some(None) = found {
+            self.update(h, |target| self.remove_expired_node(target, h, &key, now));
+        }
+        found.flatten()
     }
 
-    pub fn read_bucket(&self, key: K) -> RwLockReadGuard<'_, Bucket<K, V>> {
+    /// Entries of the bucket that contains `key`, without expired ones
+    pub fn read_bucket(&self, key: K) -> Vec<(K, V)> {
         let h = self.hash(&key);
-        let index = self.get_index(h);
-        self.content[index].read().unwrap()
+        let now = Instant::now();
+        let tables = self.tables.read().unwrap_or_else(PoisonError::into_inner);
+        let index = get_index(h, tables.current.len());
+
+        // The whole bucket has to be present in the current table
+        if let Some(migration) = &tables.migration {
+            for source in migration.sources(index, tables.current.len()) {
+                migration.move_bucket(source, &tables.current, &self.state);
+            }
+        }
+
+        let target = tables.current[index]
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        let entries: Vec<_> = target
+            .iter()
+            .filter(|n| n.live(now))
+            .map(|n| (n.k.clone(), n.v.clone()))
+            .collect();
+        let has_expired = entries.len() < target.iter().count();
+        drop(target);
+        drop(tables);
+
+        if has_expired {
+            self.update(h, |target| self.retain_live_nodes(target, now));
+        }
+        entries
     }
 
     pub fn remove(&self, key: K) -> Option<V> {
+        let now = Instant::now();
+        self.take(&key).filter(|n| n.live(now)).map(|n| n.v)
+    }
+
+    /// Remove an entry, even if it has expired already
+    fn take(&self, key: &K) -> Option<Node<K, V>> {
+        let h = self.hash(key);
+        let removed = self.update(h, |target| target.remove(h, key));
+        if let Some(node) = &removed {
+            self.removed(node);
+        }
+        removed
+    }
+
+    /// Set (or clear) the time-to-live of an entry, returns false if there is no such entry
+    pub fn touch(&self, key: K, ttl: Option<Duration>) -> Result<bool, TtlOverflow> {
         let h = self.hash(&key);
-        let index = self.get_index(h);
-        let mut target = self.content[index].write().unwrap();
-        let item = target.iter().enumerate().find(|(_, n)| n.k == key)?;
-        let split_index = item.0;
-        let mut tail = target.split_off(split_index);
-        let value = tail.pop_front().expect("list should have item");
-        target.append(&mut tail);
-        Some(value.v)
+        let now = Instant::now();
+        // Computed before taking the lock, a panic must not poison the bucket
+        let expires = expiry(now, ttl)?;
+        Ok(self.update(h, |target| {
+            match target.find_mut(h, &key) {
+                None => return false,
+                Some(node) if node.live(now) => {
+                    node.expires = expires;
+                    self.record_access(node);
+                    return true;
+                }
+                Some(_) => {}
+            }
+            self.remove_expired_node(target, h, &key, now);
+            false
+        }))
+    }
+
+    /// Remove all expired entries, returns how many were removed
+    ///
+    /// The table is scanned in chunks, so resizes are not blocked for the whole pass.
+    /// During a migration, the old buckets that have not been claimed yet are scanned too.
+    pub fn remove_expired(&self) -> usize {
+        let now = Instant::now();
+        let mut removed = 0;
+        let mut index = None;
+        loop {
+            let tables = self.tables.read().unwrap_or_else(PoisonError::into_inner);
+            let Some(migration) = &tables.migration else {
+                break;
+            };
+            // Buckets before the cursor end up in the current table
+            let start = *index.get_or_insert(migration.cursor.load(Ordering::Relaxed));
+            let end = (start + REAP_CHUNK).min(migration.from.len());
+            if start >= end {
+                break;
+            }
+            removed += self.retain_live(&migration.from[start..end], now);
+            index = Some(end);
+        }
+
+        let mut index = 0;
+        loop {
+            let tables = self.tables.read().unwrap_or_else(PoisonError::into_inner);
+            let end = (index + REAP_CHUNK).min(tables.current.len());
+            if index >= end {
+                break;
+            }
+            removed += self.retain_live(&tables.current[index..end], now);
+            index = end;
+        }
+        removed
+    }
+
+    fn retain_live(&self, buckets: &[RwLock<B>], now: Instant) -> usize {
+        let mut removed = 0;
+        for bucket in buckets {
+            if bucket
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)
+                .iter()
+                .all(|n| n.live(now))
+            {
+                continue;
+            }
+            removed += self.retain_live_nodes(
+                &mut bucket.write().unwrap_or_else(PoisonError::into_inner),
+                now,
+            );
+        }
+        removed
+    }
+
+    /// Remove the expired nodes of a write locked bucket, returns how many were removed
+    fn retain_live_nodes(&self, bucket: &mut B, now: Instant) -> usize {
+        let heap_size: usize = bucket
+            .iter()
+            .filter(|n| !n.live(now))
+            .map(|n| n.v.heap_size())
+            .sum();
+        let removed = bucket.retain(|n| n.live(now));
+        self.len.fetch_sub(removed, Ordering::Relaxed);
+        self.heap_size.fetch_sub(heap_size, Ordering::Relaxed);
+        removed
+    }
+
+    /// Remove the node of `key` from a write locked bucket, if it has expired
+    fn remove_expired_node(&self, bucket: &mut B, hash: u64, key: &K, now: Instant) {
+        if bucket.find(hash, key).is_some_and(|n| !n.live(now)) {
+            if let Some(node) = bucket.remove(hash, key) {
+                self.removed(&node);
+            }
+        }
+    }
+
+    /// Count a node that is about to be added to the table
+    fn added(&self, node: &Node<K, V>) {
+        self.len.fetch_add(1, Ordering::Relaxed);
+        self.heap_size
+            .fetch_add(node.v.heap_size(), Ordering::Relaxed);
+    }
+
+    /// Stop counting a node that was removed from the table
+    fn removed(&self, node: &Node<K, V>) {
+        self.len.fetch_sub(1, Ordering::Relaxed);
+        self.heap_size
+            .fetch_sub(node.v.heap_size(), Ordering::Relaxed);
+    }
+
+    /// Replace the value of a node in the table
+    fn set_value(&self, node: &mut Node<K, V>, val: V) {
+        self.heap_size.fetch_add(val.heap_size(), Ordering::Relaxed);
+        self.heap_size
+            .fetch_sub(node.v.heap_size(), Ordering::Relaxed);
+        node.v = val;
+    }
+
+    /// Copy of all live entries, taken at a single point in time
+    ///
+    /// All buckets are read locked at once (old ones first, like migrations do),
+    /// so writers wait for the copy to finish, while readers are not blocked.
+    pub fn snapshot(&self) -> Vec<(K, V, Option<SystemTime>)> {
+        let tables = self.tables.read().unwrap_or_else(PoisonError::into_inner);
+        let old: Vec<_> = tables
+            .migration
+            .iter()
+            .flat_map(|m| m.from.iter())
+            .map(|b| b.read().unwrap_or_else(PoisonError::into_inner))
+            .collect();
+        let current: Vec<_> = tables
+            .current
+            .iter()
+            .map(|b| b.read().unwrap_or_else(PoisonError::into_inner))
+            .collect();
+
+        let now = Instant::now();
+        let system_now = SystemTime::now();
+        old.iter()
+            .chain(current.iter())
+            .flat_map(|b| b.iter())
+            .filter(|n| n.live(now))
+            .map(|n| {
+                let expires = n.expires.map(|e| system_now + (e - now));
+                (n.k.clone(), n.v.clone(), expires)
+            })
+            .collect()
+    }
+
+    /// Insert entries of a snapshot, skipping those which expired in the meantime
+    ///
+    /// Returns the number of inserted entries
+    pub fn restore(&self, entries: impl IntoIterator<Item = (K, V, Option<SystemTime>)>) -> usize {
+        let now = SystemTime::now();
+        let mut restored = 0;
+        for (k, v, expires) in entries {
+            let ttl = match expires.map(|e| e.duration_since(now)) {
+                Some(Err(_)) => continue,
+                ttl => ttl.map(Result::unwrap),
+            };
+            // Expiry times that fit into a `SystemTime` also fit into an `Instant`
+            if self.insert_with_ttl(k, v, ttl).is_ok() {
+                restored += 1;
+            }
+        }
+        restored
+    }
+
+    /// Evict one entry (but none of `except`), chosen among a few random samples
+    ///
+    /// Samples are taken from consecutive buckets after a random start, so they are distinct
+    fn evict(&self, except: &[K]) -> Option<K> {
+        let policy = self.eviction?.policy;
+        let mut rng = rand::thread_rng();
+        let mut victim: Option<(K, u64)> = None;
+        let mut sampled = 0;
+
+        let tables = self.tables.read().unwrap_or_else(PoisonError::into_inner);
+        // Unmigrated entries can still be in the old table
+        let old = tables
+            .migration
+            .as_ref()
+            .map(|m| &m.from[..])
+            .unwrap_or(&[]);
+        let total = tables.current.len() + old.len();
+        let start = rng.gen_range(0..total);
+        for offset in 0..EVICTION_ATTEMPTS.min(total) {
+            let index = (start + offset) % total;
+            let bucket = match index.checked_sub(tables.current.len()) {
+                Some(index) => old[index].read().unwrap_or_else(PoisonError::into_inner),
+                None => tables.current[index]
+                    .read()
+                    .unwrap_or_else(PoisonError::into_inner),
+            };
+            for node in bucket.iter().filter(|n| !except.contains(&n.k)) {
+                let score = node.access.score(policy, rng.gen());
+                if victim.as_ref().is_none_or(|(_, s)| score < *s) {
+                    victim = Some((node.k.clone(), score));
+                }
+                sampled += 1;
+            }
+            if sampled >= EVICTION_SAMPLES {
+                break;
+            }
+        }
+        drop(tables);
+
+        let (key, _) = victim?;
+        self.take(&key)?;
+        self.evictions.fetch_add(1, Ordering::Relaxed);
+        Some(key)
+    }
+
+    /// Number of entries evicted so far
+    pub fn evictions(&self) -> u64 {
+        self.evictions.load(Ordering::Relaxed)
+    }
+
+    /// Clone the value of a node if it is still live
+    fn read_live(&self, node: &Node<K, V>, now: Instant) -> Option<V> {
+        if !node.live(now) {
+            return None;
+        }
+        self.record_access(node);
+        Some(node.v.clone())
+    }
+
+    fn record_access(&self, node: &Node<K, V>) {
+        // Keep reads free of shared writes unless the statistics are needed
+        if self.eviction.is_some() {
+            node.access.record(self.tick());
+        }
+    }
+
+    fn tick(&self) -> u64 {
+        self.created.elapsed().as_nanos() as u64
+    }
+
+    /// Number of entries in the table
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Approximate memory used by the entries, including the memory their values own
+    pub fn bytes(&self) -> usize {
+        self.len() * B::ENTRY_SIZE + self.heap_size.load(Ordering::Relaxed)
+    }
+
+    /// Number of buckets in the active table
+    pub fn num_buckets(&self) -> usize {
+        self.tables
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .current
+            .len()
     }
 
     fn hash(&self, key: &K) -> u64 {
-        let mut hasher = self.state.build_hasher();
-        key.hash(&mut hasher);
-        hasher.finish()
+        self.state.hash_one(key)
     }
 
-    fn get_index(&self, hash: u64) -> usize {
-        (hash % self.content.len() as u64) as usize
+    /// Run `f` on the (write locked) bucket for `hash`
+    fn update<R>(&self, hash: u64, f: impl FnOnce(&mut B) -> R) -> R {
+        let tables = self.tables.read().unwrap_or_else(PoisonError::into_inner);
+        let index = tables.prepare(hash, &self.state);
+        let mut target = tables.current[index]
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        let result = f(&mut target);
+        drop(target);
+        self.finish_op(tables);
+        result
+    }
+
+    /// Help with a running migration, and start or complete a resize if necessary
+    fn finish_op(&self, tables: RwLockReadGuard<'_, Tables<B>>) {
+        let Some(migration) = &tables.migration else {
+            let target = self.target_size(tables.current.len());
+            drop(tables);
+            if let Some(size) = target {
+                self.start_resize(size);
+            }
+            return;
+        };
+
+        for _ in 0..MIGRATE_PER_OP {
+            let index = migration.cursor.fetch_add(1, Ordering::Relaxed);
+            if index >= migration.from.len() {
+                break;
+            }
+            migration.move_bucket(index, &tables.current, &self.state);
+        }
+
+        if migration.remaining.load(Ordering::Acquire) == 0 {
+            drop(tables);
+            let mut tables = self.tables.write().unwrap_or_else(PoisonError::into_inner);
+            let finished = tables
+                .migration
+                .take_if(|m| m.remaining.load(Ordering::Acquire) == 0);
+            drop(tables);
+            // Free the old (empty) buckets outside of the lock
+            drop(finished);
+        }
+    }
+
+    fn target_size(&self, buckets: usize) -> Option<usize> {
+        let load = self.len() as f64 / buckets as f64;
+        if load > B::LOAD_FACTOR {
+            Some(buckets * 2)
+        } else if load < B::LOAD_FACTOR * SHRINK_LOAD_RATIO && buckets > self.min_size {
+            Some(buckets / 2)
+        } else {
+            None
+        }
+    }
+
+    fn start_resize(&self, size: usize) {
+        let mut table = new_table(size);
+
+        let mut tables = self.tables.write().unwrap_or_else(PoisonError::into_inner);
+        // Another thread might have been faster
+        if tables.migration.is_some() || self.target_size(tables.current.len()) != Some(size) {
+            return;
+        }
+        mem::swap(&mut tables.current, &mut table);
+        tables.migration = Some(Migration::new(table));
+    }
+}
+
+impl<B> Tables<B> {
+    /// Make sure the bucket for `hash` is in the current table, returns its index
+    fn prepare<K: Hash, V>(&self, hash: u64, state: &impl BuildHasher) -> usize
+    where
+        B: Bucket<K, V>,
+    {
+        if let Some(migration) = &self.migration {
+            let source = get_index(hash, migration.from.len());
+            migration.move_bucket(source, &self.current, state);
+        }
+        get_index(hash, self.current.len())
     }
 }
 
-impl<K, V> Debug for HashTable<K, V, RandomState>
+impl<B> Migration<B> {
+    fn new(from: Table<B>) -> Self {
+        let len = from.len();
+        Self {
+            from,
+            moved: repeat_with(|| AtomicBool::new(false)).take(len).collect(),
+            cursor: AtomicUsize::new(0),
+            remaining: AtomicUsize::new(len),
+        }
+    }
+
+    /// Move all nodes of old bucket `index` to the table `to`
+    ///
+    /// Old buckets are always locked before new ones, so this cannot deadlock
+    /// with operations, which only ever hold locks of the current table.
+    fn move_bucket<K: Hash, V>(&self, index: usize, to: &Table<B>, state: &impl BuildHasher)
+    where
+        B: Bucket<K, V>,
+    {
+        if self.moved[index].load(Ordering::Relaxed) {
+            return;
+        }
+        let mut source = self.from[index]
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        if self.moved[index].load(Ordering::Relaxed) {
+            return;
+        }
+        for node in source.drain() {
+            let hash = state.hash_one(&node.k);
+            to[get_index(hash, to.len())]
+                .write()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push(hash, node);
+        }
+        self.moved[index].store(true, Ordering::Relaxed);
+        self.remaining.fetch_sub(1, Ordering::Release);
+    }
+
+    /// Old buckets that contain entries for the new bucket `index`
+    ///
+    /// Tables only ever double or halve in size
+    fn sources(&self, index: usize, new_len: usize) -> impl Iterator<Item = usize> {
+        let old_len = self.from.len();
+        let (first, second) = if old_len < new_len {
+            (index % old_len, None)
+        } else {
+            (index, Some(index + new_len).filter(|i| *i < old_len))
+        };
+        [Some(first), second].into_iter().flatten()
+    }
+}
+
+fn new_table<B: Default>(size: usize) -> Table<B> {
+    repeat_with(|| RwLock::new(B::default()))
+        .take(size)
+        .collect()
+}
+
+fn get_index(hash: u64, len: usize) -> usize {
+    (hash % len as u64) as usize
+}
+
+impl<K, V, B> Debug for HashTable<K, V, RandomState, B>
 where
     K: Hash + Eq + Debug,
     V: Debug,
+    B: Bucket<K, V>,
 {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let tables = self.tables.read().unwrap_or_else(PoisonError::into_inner);
         writeln!(f, "HashTable {{")?;
-        for (id, bucket) in self.content.iter().enumerate() {
-            let bucket = bucket.read().unwrap();
+        writeln!(
+            f,
+            "  Entries: {}, Evictions: {}",
+            self.len.load(Ordering::Relaxed),
+            self.evictions.load(Ordering::Relaxed)
+        )?;
+        for (id, bucket) in tables.current.iter().enumerate() {
+            let bucket = bucket.read().unwrap_or_else(PoisonError::into_inner);
             if bucket.is_empty() {
                 continue;
             }
-            writeln!(f, "  Bucket {id}: {:?}", &*bucket)?;
+            writeln!(f, "  Bucket {id}: {:?}", bucket.iter().collect::<Vec<_>>())?;
+        }
+        if let Some(migration) = &tables.migration {
+            for (id, bucket) in migration.from.iter().enumerate() {
+                let bucket = bucket.read().unwrap_or_else(PoisonError::into_inner);
+                if bucket.is_empty() {
+                    continue;
+                }
+                writeln!(
+                    f,
+                    "  Unmigrated Bucket {id}: {:?}",
+                    bucket.iter().collect::<Vec<_>>()
+                )?;
+            }
         }
         writeln!(f, "}}")?;
         Ok(())
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+/// Exclusive access to the entries of a set of keys, see [`HashTable::transaction`]
+///
+/// Writes are staged until the transaction commits, reads see the staged writes
+pub struct Transaction<'a, K, V, B> {
+    /// Write locked buckets, sorted by index
+    buckets: Vec<(usize, RwLockWriteGuard<'a, B>)>,
+    num_buckets: usize,
+    state: &'a RandomState,
+    now: Instant,
+    staged: Vec<(K, Staged<V>)>,
+}
+
+enum Staged<V> {
+    /// Overwrite the entry, and clear its expiry
+    Insert(V),
+    /// Overwrite the value, keeping the expiry
+    Update(V),
+    Remove,
+}
+
+impl<K, V, B> Transaction<'_, K, V, B>
+where
+    K: Hash + Eq + Clone,
+    V: Clone + HeapSize,
+    B: Bucket<K, V>,
+{
+    /// Current value of `key`, which has to be one of the keys of the transaction
+    pub fn get(&self, key: &K) -> Option<V> {
+        match self.staged.iter().find(|(k, _)| k == key) {
+            Some((_, Staged::Insert(v) | Staged::Update(v))) => Some(v.clone()),
+            Some((_, Staged::Remove)) => None,
+            None => {
+                let (hash, bucket) = self.bucket(key);
+                let node = self.buckets[bucket].1.find(hash, key)?;
+                node.live(self.now).then(|| node.v.clone())
+            }
+        }
+    }
+
+    /// Insert (or overwrite) an entry without expiry, returns the previous value
+    pub fn insert(&mut self, key: K, val: V) -> Option<V> {
+        self.stage(key, Staged::Insert(val))
+    }
+
+    /// Set the value of an entry, existing entries keep their expiry. Returns the previous value
+    pub fn update(&mut self, key: K, val: V) -> Option<V> {
+        self.stage(key, Staged::Update(val))
+    }
+
+    /// Remove an entry, returns its value
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        self.stage(key.clone(), Staged::Remove)
+    }
+
+    fn stage(&mut self, key: K, write: Staged<V>) -> Option<V> {
+        let previous = self.get(&key);
+        match self.staged.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, staged)) => *staged = write,
+            None => self.staged.push((key, write)),
+        }
+        previous
+    }
+
+    /// Hash of `key`, and the position of its bucket in `buckets`
+    fn bucket(&self, key: &K) -> (u64, usize) {
+        let hash = self.state.hash_one(key);
+        let index = get_index(hash, self.num_buckets);
+        let bucket = self
+            .buckets
+            .binary_search_by_key(&index, |(i, _)| *i)
+            .expect("key should be part of the transaction");
+        (hash, bucket)
+    }
+
+    /// Apply the staged writes, returns the keys of new entries
+    fn commit(&mut self, table: &HashTable<K, V, RandomState, B>) -> Vec<K> {
+        let mut inserted = Vec::new();
+        for (key, write) in mem::take(&mut self.staged) {
+            let (hash, bucket) = self.bucket(&key);
+            let bucket = &mut self.buckets[bucket].1;
+            let (val, keep_expiry) = match write {
+                Staged::Insert(val) => (val, false),
+                Staged::Update(val) => (val, true),
+                Staged::Remove => {
+                    if let Some(node) = bucket.remove(hash, &key) {
+                        table.removed(&node);
+                    }
+                    continue;
+                }
+            };
+
+            match bucket.find_mut(hash, &key) {
+                Some(node) => {
+                    // Expired entries are replaced as a whole
+                    if !keep_expiry || !node.live(self.now) {
+                        node.expires = None;
+                    }
+                    table.set_value(node, val);
+                    table.record_access(node);
+                }
+                None => {
+                    let node = Node {
+                        k: key.clone(),
+                        v: val,
+                        expires: None,
+                        access: Access::new(table.tick()),
+                    };
+                    table.added(&node);
+                    bucket.push(hash, node);
+                    inserted.push(key);
+                }
+            }
+        }
+        inserted
+    }
+}
+
+/// A time-to-live is too large to compute the expiry time of an entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TtlOverflow;
+
+impl fmt::Display for TtlOverflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("time-to-live is too large")
+    }
+}
+
+impl Error for TtlOverflow {}
+
+/// Expiry time of an entry with the time-to-live `ttl`, `None` if it does not expire
+fn expiry(now: Instant, ttl: Option<Duration>) -> Result<Option<Instant>, TtlOverflow> {
+    ttl.map(|ttl| now.checked_add(ttl).ok_or(TtlOverflow))
+        .transpose()
+}
+
+/// Outcome of a conditional operation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conditional<K, V> {
+    pub success: bool,
+    /// Value after the operation, `None` if there is no entry
+    pub current: Option<V>,
+    /// Keys of the entries that had to be evicted for an insertion
+    pub evicted: Vec<K>,
+}
+
+/// Outcome of [`HashTable::fetch_update`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fetched<K, V> {
+    /// `None` if the entry was created
+    pub previous: Option<V>,
+    pub current: V,
+    /// Keys of the entries that had to be evicted for an insertion
+    pub evicted: Vec<K>,
+}
+
+#[derive(Debug)]
 pub struct Node<K, V> {
     pub k: K,
     pub v: V,
+    pub expires: Option<Instant>,
+    pub access: Access,
+}
+
+impl<K, V> Node<K, V> {
+    pub fn live(&self, now: Instant) -> bool {
+        self.expires.is_none_or(|expires| expires > now)
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use super::HashTable;
+    use std::{hash::RandomState, thread, time::Duration};
+
+    use shared::KeyType;
+
+    use crate::value::Value;
+
+    use super::{
+        eviction::{Eviction, EvictionPolicy},
+        Bucket, ChainBucket, Conditional, HashTable, SwissBucket, TtlOverflow,
+    };
+
+    type Table<B> = HashTable<u32, u32, RandomState, B>;
 
     #[test]
     fn basic() {
-        let ht = HashTable::new(100);
+        let ht: HashTable<_, _> = HashTable::new(100);
         ht.insert(1, "hello");
         ht.insert(8, "world");
 
@@ -120,4 +1128,340 @@ mod test {
         ht.remove(8);
         assert_eq!(ht.remove(1), Some("hello"));
     }
+
+    fn resize<B: Bucket<u32, u32>>() {
+        let ht: Table<B> = HashTable::new(4);
+        for i in 0..1000 {
+            ht.insert(i, i * 2);
+        }
+        assert_eq!(ht.len(), 1000);
+        assert!(ht.num_buckets() as f64 >= 500.0 / B::LOAD_FACTOR);
+        for i in 0..1000 {
+            assert_eq!(ht.get(i), Some(i * 2));
+            assert!(ht.read_bucket(i).contains(&(i, i * 2)));
+        }
+
+        let grown = ht.num_buckets();
+        for i in 0..1000 {
+            assert_eq!(ht.remove(i), Some(i * 2));
+        }
+        assert!(ht.is_empty());
+        assert!(ht.num_buckets() < grown);
+    }
+
+    fn binary_keys<B: Bucket<KeyType, u32>>() {
+        let ht: HashTable<KeyType, u32, RandomState, B> = HashTable::new(1);
+        // Prefixes of each other, and invalid UTF-8
+        let keys: [&[u8]; 5] = [b"", b"\0", b"\0\0", b"\xff\xfe", b"\xc3\x28"];
+        let keys = keys.map(|k| KeyType::try_from(k).unwrap());
+        for (i, &k) in keys.iter().enumerate() {
+            ht.insert(k, i as u32);
+        }
+        assert_eq!(ht.len(), keys.len());
+        for (i, &k) in keys.iter().enumerate() {
+            assert_eq!(ht.get(k), Some(i as u32));
+            assert!(ht.read_bucket(k).contains(&(k, i as u32)));
+        }
+        assert_eq!(ht.remove(keys[1]), Some(1));
+        assert_eq!(ht.get(keys[2]), Some(2));
+    }
+
+    fn concurrent_resize<B: Bucket<u32, u32>>() {
+        let ht: Table<B> = HashTable::new(1);
+        thread::scope(|s| {
+            for t in 0..8 {
+                let ht = &ht;
+                s.spawn(move || {
+                    for i in (t * 1000)..((t + 1) * 1000) {
+                        ht.insert(i, i);
+                        assert_eq!(ht.get(i), Some(i));
+                    }
+                });
+            }
+        });
+        assert_eq!(ht.len(), 8000);
+        for i in 0..8000 {
+            assert_eq!(ht.get(i), Some(i));
+        }
+    }
+
+    #[test]
+    fn conditional() {
+        let ht: Table<ChainBucket<_, _>> = HashTable::new(4);
+        assert!(!ht.replace_if_present(1, 1).success);
+        assert_eq!(ht.get(1), None);
+
+        assert!(ht.insert_if_absent(1, 1).success);
+        let inserted = ht.insert_if_absent(1, 2);
+        assert!(!inserted.success);
+        assert_eq!(inserted.current, Some(1));
+
+        let swapped = ht.compare_and_swap(1, 2, 3);
+        assert!(!swapped.success);
+        assert_eq!(swapped.current, Some(1));
+        let swapped = ht.compare_and_swap(1, 1, 3);
+        assert!(swapped.success);
+        assert_eq!(swapped.current, Some(3));
+
+        ht.touch(1, Some(Duration::from_millis(50))).unwrap();
+        assert!(ht.replace_if_present(1, 4).success);
+        thread::sleep(Duration::from_millis(100));
+        // Replacing kept the expiry, expired entries are absent
+        assert!(!ht.compare_and_swap(1, 4, 5).success);
+        assert!(ht.insert_if_absent(1, 6).success);
+        assert_eq!(ht.len(), 1);
+
+        // Concurrent increments do not lose updates
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        let mut current = ht.get(1).unwrap();
+                        while let Conditional {
+                            success: false,
+                            current: Some(actual),
+                            ..
+                        } = ht.compare_and_swap(1, current, current + 1)
+                        {
+                            current = actual;
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(ht.get(1), Some(4006));
+    }
+
+    #[test]
+    fn fetch_update() {
+        let ht: Table<SwissBucket<_, _>> = HashTable::new(4);
+        let fetched = ht.fetch_update(1, 10, |v| Some(v + 1)).unwrap();
+        assert_eq!((fetched.previous, fetched.current), (None, 11));
+        let fetched = ht.fetch_update(1, 10, |v| Some(v * 2)).unwrap();
+        assert_eq!((fetched.previous, fetched.current), (Some(11), 22));
+        assert_eq!(ht.fetch_update(1, 10, |_| None), None);
+        assert_eq!(ht.fetch_update(3, 10, |_| None), None);
+        assert_eq!((ht.get(1), ht.get(3)), (Some(22), None));
+
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        ht.fetch_update(2, 0, |v| Some(v + 1));
+                    }
+                });
+            }
+        });
+        assert_eq!(ht.get(2), Some(4000));
+    }
+
+    #[test]
+    fn transaction() {
+        let ht: Table<ChainBucket<_, _>> = HashTable::new(1);
+        for i in 0..8 {
+            ht.insert(i, 100);
+        }
+
+        // Move quota between keys, while the table is resizing
+        thread::scope(|s| {
+            for t in 0..4 {
+                let ht = &ht;
+                s.spawn(move || {
+                    for i in 0..1000 {
+                        let (from, to) = ((i + t) % 8, (i * 3 + t + 1) % 8);
+                        let _ = ht.transaction(&[from, to], |tx| {
+                            let amount = tx.get(&from).unwrap();
+                            if amount == 0 || from == to {
+                                return Err(());
+                            }
+                            tx.update(from, amount - 1);
+                            tx.update(to, tx.get(&to).unwrap() + 1);
+                            Ok(())
+                        });
+                        ht.insert(1000 + t * 1000 + i, 0);
+                    }
+                });
+            }
+        });
+        assert_eq!((0..8).map(|i| ht.get(i).unwrap()).sum::<u32>(), 800);
+
+        let aborted = ht.transaction(&[0, 1], |tx| {
+            assert!(tx.remove(&0).is_some());
+            assert_eq!(tx.get(&0), None);
+            tx.insert(1, 5);
+            Err::<(), _>("abort")
+        });
+        assert_eq!(aborted, Err("abort"));
+        assert!(ht.get(0).is_some());
+
+        let ((), evicted) = ht
+            .transaction(&[0, 20_000], |tx| {
+                tx.remove(&0);
+                tx.insert(20_000, 1);
+                Ok::<_, ()>(())
+            })
+            .unwrap();
+        assert!(evicted.is_empty());
+        assert_eq!(ht.get(0), None);
+        assert_eq!(ht.get(20_000), Some(1));
+    }
+
+    #[test]
+    fn expiry() {
+        let ttl = Some(Duration::from_millis(100));
+        let ht: Table<ChainBucket<_, _>> = HashTable::new(1);
+        ht.insert_with_ttl(1, 1, ttl).unwrap();
+        ht.insert_with_ttl(2, 2, ttl).unwrap();
+        ht.insert_with_ttl(3, 3, ttl).unwrap();
+        ht.insert(4, 4);
+        assert!(ht.touch(3, None).unwrap());
+        // Neither panics nor poisons the bucket
+        assert_eq!(ht.touch(4, Some(Duration::MAX)), Err(TtlOverflow));
+        assert_eq!(
+            ht.insert_with_ttl(6, 6, Some(Duration::MAX)),
+            Err(TtlOverflow)
+        );
+        assert_eq!(ht.get(4), Some(4));
+        assert_eq!(ht.get(1), Some(1));
+
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(ht.get(1), None);
+        assert!(ht.read_bucket(2).iter().all(|(k, _)| *k != 2));
+        assert!(!ht.touch(2, ttl).unwrap());
+        assert_eq!(ht.get(3), Some(3));
+        assert_eq!(ht.len(), 2);
+
+        ht.insert_with_ttl(5, 5, ttl).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(ht.remove_expired(), 1);
+        assert_eq!(ht.remove(5), None);
+        assert_eq!(ht.len(), 2);
+    }
+
+    #[test]
+    fn expiry_during_resize() {
+        let ht: Table<ChainBucket<_, _>> = HashTable::new(64);
+        for i in 0..65 {
+            ht.insert_with_ttl(i, i, Some(Duration::ZERO)).unwrap();
+        }
+        // The last insertion started a resize, no bucket has been moved yet
+        assert!(ht.tables.read().unwrap().migration.is_some());
+        assert_eq!(ht.remove_expired(), 65);
+        assert_eq!(ht.len(), 0);
+    }
+
+    #[test]
+    fn snapshot() {
+        let ht: Table<SwissBucket<_, _>> = HashTable::new(1);
+        for i in 0..100 {
+            ht.insert(i, i);
+        }
+        ht.insert_with_ttl(100, 100, Some(Duration::from_secs(60)))
+            .unwrap();
+        ht.insert_with_ttl(101, 101, Some(Duration::ZERO)).unwrap();
+
+        let mut snapshot = ht.snapshot();
+        snapshot.sort_by_key(|e| e.0);
+        assert_eq!(snapshot.len(), 101);
+        assert!(snapshot[..100]
+            .iter()
+            .all(|(k, v, e)| k == v && e.is_none()));
+        assert!(snapshot[100].2.is_some());
+
+        let restored: Table<ChainBucket<_, _>> = HashTable::new(1);
+        assert_eq!(restored.restore(snapshot), 101);
+        assert_eq!(restored.get(50), Some(50));
+        assert!(restored.touch(100, None).unwrap());
+    }
+
+    #[test]
+    fn eviction() {
+        let ht: Table<ChainBucket<_, _>> = HashTable::new(4).with_eviction(Eviction {
+            max_entries: Some(10),
+            max_bytes: None,
+            policy: EvictionPolicy::Lfu,
+        });
+        for i in 0..10 {
+            assert!(ht.insert(i, i).is_empty());
+        }
+        for _ in 0..10 {
+            assert_eq!(ht.get(0), Some(0));
+        }
+
+        for i in 10..100 {
+            let evicted = ht.insert(i, i);
+            assert_eq!(evicted.len(), 1, "table should be full");
+            let evicted = evicted[0];
+            assert_ne!(evicted, i);
+            assert_eq!(ht.get(evicted), None);
+        }
+        assert_eq!(ht.len(), 10);
+        assert_eq!(ht.evictions(), 90);
+        // The most frequently used entry stays
+        assert_eq!(ht.get(0), Some(0));
+    }
+
+    #[test]
+    fn eviction_by_bytes() {
+        let entry = ChainBucket::<u32, Value>::ENTRY_SIZE;
+        let bytes = |len| Value::Bytes(vec![0; len].into());
+        let ht: HashTable<u32, Value> = HashTable::new(4).with_eviction(Eviction {
+            max_entries: None,
+            max_bytes: Some(10 * entry + 1000),
+            policy: EvictionPolicy::Lru,
+        });
+        for i in 0..10 {
+            assert!(ht.insert(i, Value::Int(i)).is_empty());
+        }
+        assert_eq!(ht.bytes(), 10 * entry);
+
+        // A large value evicts as many entries as needed to make room for it
+        let evicted = ht.insert(10, bytes(1000 + 2 * entry));
+        assert_eq!(evicted.len(), 3);
+        assert!(!evicted.contains(&10));
+        assert_eq!(ht.bytes(), 10 * entry + 1000);
+
+        // Overwriting, removing and expiring values frees their bytes
+        ht.insert(10, bytes(10));
+        assert_eq!(ht.bytes(), 8 * entry + 10);
+        ht.insert(10, Value::Int(10));
+        assert_eq!(ht.bytes(), 8 * entry);
+        ht.insert_with_ttl(11, bytes(100), Some(Duration::ZERO))
+            .unwrap();
+        assert_eq!(ht.remove_expired(), 1);
+        assert_eq!(ht.bytes(), 8 * entry);
+        ht.insert(11, bytes(100));
+        assert_eq!(ht.remove(11), Some(bytes(100)));
+        assert_eq!(ht.bytes(), 8 * entry);
+    }
+
+    #[test]
+    fn resize_chain() {
+        resize::<ChainBucket<_, _>>();
+    }
+
+    #[test]
+    fn resize_swiss() {
+        resize::<SwissBucket<_, _>>();
+    }
+
+    #[test]
+    fn concurrent_resize_chain() {
+        concurrent_resize::<ChainBucket<_, _>>();
+    }
+
+    #[test]
+    fn concurrent_resize_swiss() {
+        concurrent_resize::<SwissBucket<_, _>>();
+    }
+
+    #[test]
+    fn binary_keys_chain() {
+        binary_keys::<ChainBucket<_, _>>();
+    }
+
+    #[test]
+    fn binary_keys_swiss() {
+        binary_keys::<SwissBucket<_, _>>();
+    }
 }
@@ -0,0 +1,65 @@
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use clap::ValueEnum;
+
+/// Number of entries compared to pick a victim
+pub const EVICTION_SAMPLES: usize = 5;
+/// Maximum number of buckets visited to collect the samples
+pub const EVICTION_ATTEMPTS: usize = 64;
+
+/// Upper bounds of the table, enforced on insertion
+#[derive(Debug, Clone, Copy)]
+pub struct Eviction {
+    pub max_entries: Option<usize>,
+    /// Bound of [`HashTable::bytes`](super::HashTable::bytes)
+    pub max_bytes: Option<usize>,
+    pub policy: EvictionPolicy,
+}
+
+/// Which entry to evict once the table is full
+///
+/// Victims are chosen among a few randomly sampled entries, so LRU and LFU are approximations
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum EvictionPolicy {
+    /// Least recently used
+    Lru,
+    /// Least frequently used
+    Lfu,
+    /// Uniformly random
+    Random,
+}
+
+/// Access statistics of an entry, only updated if eviction is enabled
+///
+/// Atomics, as entries are also accessed under read locks
+#[derive(Debug, Default)]
+pub struct Access {
+    /// Time of the last access, in ticks of the table
+    last: AtomicU64,
+    hits: AtomicU32,
+}
+
+impl Access {
+    pub fn new(tick: u64) -> Self {
+        Self {
+            last: AtomicU64::new(tick),
+            hits: AtomicU32::new(0),
+        }
+    }
+
+    pub fn record(&self, tick: u64) {
+        self.last.store(tick, Ordering::Relaxed);
+        let _ = self
+            .hits
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |h| h.checked_add(1));
+    }
+
+    /// Lower scores are evicted first, `random` is used by [`EvictionPolicy::Random`]
+    pub fn score(&self, policy: EvictionPolicy, random: u64) -> u64 {
+        match policy {
+            EvictionPolicy::Lru => self.last.load(Ordering::Relaxed),
+            EvictionPolicy::Lfu => self.hits.load(Ordering::Relaxed) as u64,
+            EvictionPolicy::Random => random,
+        }
+    }
+}
@@ -0,0 +1,298 @@
+use std::mem;
+
+use super::{Bucket, Node};
+
+/// Number of control bytes scanned at once
+const GROUP_WIDTH: usize = 8;
+
+const EMPTY: u8 = 0xFF;
+const DELETED: u8 = 0x80;
+
+const LSB: u64 = 0x0101_0101_0101_0101;
+const MSB: u64 = 0x8080_8080_8080_8080;
+
+/// An open addressing table in the style of SwissTable, used as a shard of [`super::HashTable`]
+///
+/// Every slot has a control byte, which is either `EMPTY`, `DELETED` or the top
+/// 7 bits of the hash of its key. Lookups scan a whole group of control bytes
+/// at once, and only compare keys for slots with a matching control byte.
+/// Groups are probed quadratically.
+#[derive(Debug)]
+pub struct SwissBucket<K, V> {
+    ctrl: Vec<u8>,
+    slots: Vec<Option<(u64, Node<K, V>)>>,
+    items: usize,
+    /// Tombstones still lengthen probe sequences, so they count towards the load
+    deleted: usize,
+}
+
+impl<K, V> Default for SwissBucket<K, V> {
+    fn default() -> Self {
+        Self {
+            ctrl: Vec::new(),
+            slots: Vec::new(),
+            items: 0,
+            deleted: 0,
+        }
+    }
+}
+
+impl<K, V> Bucket<K, V> for SwissBucket<K, V>
+where
+    K: Eq + Send + Sync,
+    V: Send + Sync,
+{
+    const LOAD_FACTOR: f64 = 8.0;
+    // Slot and control byte, at a load of 1/2 to 7/8
+    const ENTRY_SIZE: usize = (size_of::<Option<(u64, Node<K, V>)>>() + 1) * 3 / 2;
+
+    fn find(&self, hash: u64, key: &K) -> Option<&Node<K, V>> {
+        let index = self.find_index(hash, key)?;
+        self.slots[index].as_ref().map(|(_, n)| n)
+    }
+
+    fn find_mut(&mut self, hash: u64, key: &K) -> Option<&mut Node<K, V>> {
+        let index = self.find_index(hash, key)?;
+        self.slots[index].as_mut().map(|(_, n)| n)
+    }
+
+    fn push(&mut self, hash: u64, node: Node<K, V>) {
+        // Keep the load (including tombstones) at or below 7/8
+        if (self.items + self.deleted + 1) * 8 > self.slots.len() * 7 {
+            self.rehash();
+        }
+        let index = self.find_insert_slot(hash);
+        if self.ctrl[index] == DELETED {
+            self.deleted -= 1;
+        }
+        self.ctrl[index] = h2(hash);
+        self.slots[index] = Some((hash, node));
+        self.items += 1;
+    }
+
+    fn remove(&mut self, hash: u64, key: &K) -> Option<Node<K, V>> {
+        let index = self.find_index(hash, key)?;
+        Some(self.erase(index))
+    }
+
+    fn retain(&mut self, mut f: impl FnMut(&Node<K, V>) -> bool) -> usize {
+        let mut removed = 0;
+        let mut index = 0;
+        // Erasing the last item resets the slots
+        while index < self.slots.len() {
+            if self.slots[index].as_ref().is_some_and(|(_, n)| !f(n)) {
+                self.erase(index);
+                removed += 1;
+            }
+            index += 1;
+        }
+        removed
+    }
+
+    fn iter<'a>(&'a self) -> impl Iterator<Item = &'a Node<K, V>>
+    where
+        K: 'a,
+        V: 'a,
+    {
+        self.slots.iter().flatten().map(|(_, n)| n)
+    }
+
+    fn drain(&mut self) -> impl Iterator<Item = Node<K, V>> {
+        mem::take(self).slots.into_iter().flatten().map(|(_, n)| n)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.items == 0
+    }
+}
+
+impl<K, V> SwissBucket<K, V>
+where
+    K: Eq,
+{
+    fn find_index(&self, hash: u64, key: &K) -> Option<usize> {
+        if self.items == 0 {
+            return None;
+        }
+        let h2 = h2(hash);
+        for group_index in self.probe(hash) {
+            let group = Group::load(&self.ctrl, group_index);
+            for offset in group.match_byte(h2) {
+                let index = group_index * GROUP_WIDTH + offset;
+                if let Some((h, node)) = &self.slots[index] {
+                    if *h == hash && node.k == *key {
+                        return Some(index);
+                    }
+                }
+            }
+            if group.match_empty().any() {
+                return None;
+            }
+        }
+        None
+    }
+
+    /// Remove the node in the (full) slot `index`
+    fn erase(&mut self, index: usize) -> Node<K, V> {
+        let (_, node) = self.slots[index].take().expect("slot should be full");
+        self.items -= 1;
+
+        if self.items == 0 {
+            // Free the memory (and all tombstones) of empty shards
+            *self = Self::default();
+            return node;
+        }
+
+        // Probing stops at groups with an empty slot, so if the group already has one,
+        // no probe sequence continues past it and the slot can become empty again
+        let group = Group::load(&self.ctrl, index / GROUP_WIDTH);
+        if group.match_empty().any() {
+            self.ctrl[index] = EMPTY;
+        } else {
+            self.ctrl[index] = DELETED;
+            self.deleted += 1;
+        }
+        node
+    }
+
+    fn find_insert_slot(&self, hash: u64) -> usize {
+        for group_index in self.probe(hash) {
+            let group = Group::load(&self.ctrl, group_index);
+            if let Some(offset) = group.match_empty_or_deleted().next() {
+                return group_index * GROUP_WIDTH + offset;
+            }
+        }
+        unreachable!("load factor guarantees a free slot");
+    }
+
+    /// Rebuild the table, sized for a load of at most 1/2 after the next insert
+    fn rehash(&mut self) {
+        let num_groups = ((self.items + 1) * 2)
+            .div_ceil(GROUP_WIDTH)
+            .next_power_of_two();
+        let num_slots = num_groups * GROUP_WIDTH;
+
+        let old = mem::replace(&mut self.slots, (0..num_slots).map(|_| None).collect());
+        self.ctrl = vec![EMPTY; num_slots];
+        self.deleted = 0;
+
+        for (hash, node) in old.into_iter().flatten() {
+            let index = self.find_insert_slot(hash);
+            self.ctrl[index] = h2(hash);
+            self.slots[index] = Some((hash, node));
+        }
+    }
+
+    /// Group indices in (triangular) probe order, visits every group exactly once
+    fn probe(&self, hash: u64) -> impl Iterator<Item = usize> {
+        let num_groups = self.ctrl.len() / GROUP_WIDTH;
+        let mask = num_groups.wrapping_sub(1);
+        // The low bits of the hash already select the shard
+        let mut pos = (hash >> 32) as usize & mask;
+        (0..num_groups).map(move |stride| {
+            pos = (pos + stride) & mask;
+            pos
+        })
+    }
+}
+
+/// Top 7 bits of the hash, stored in the control byte of full slots
+fn h2(hash: u64) -> u8 {
+    (hash >> 57) as u8
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Group(u64);
+
+impl Group {
+    fn load(ctrl: &[u8], group_index: usize) -> Self {
+        let start = group_index * GROUP_WIDTH;
+        let bytes = ctrl[start..start + GROUP_WIDTH].try_into().unwrap();
+        Self(u64::from_le_bytes(bytes))
+    }
+
+    /// Slots whose control byte is `h2`, can contain false positives (but only full slots)
+    fn match_byte(self, h2: u8) -> BitMask {
+        let cmp = self.0 ^ (LSB * h2 as u64);
+        BitMask(cmp.wrapping_sub(LSB) & !cmp & MSB)
+    }
+
+    /// `EMPTY` is the only control byte with both of the top bits set
+    fn match_empty(self) -> BitMask {
+        BitMask(self.0 & (self.0 << 1) & MSB)
+    }
+
+    fn match_empty_or_deleted(self) -> BitMask {
+        BitMask(self.0 & MSB)
+    }
+}
+
+/// Iterator over the slot offsets within a group that have their top bit set
+#[derive(Debug, Clone, Copy)]
+struct BitMask(u64);
+
+impl BitMask {
+    fn any(self) -> bool {
+        self.0 != 0
+    }
+}
+
+impl Iterator for BitMask {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        if self.0 == 0 {
+            return None;
+        }
+        let offset = self.0.trailing_zeros() as usize / 8;
+        self.0 &= self.0 - 1;
+        Some(offset)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Bucket, Node, SwissBucket};
+    use crate::hash_table::eviction::Access;
+
+    #[test]
+    fn insert_remove() {
+        let mut bucket = SwissBucket::default();
+        // Colliding control bytes and probe positions
+        let hash = |i: u64| i << 40;
+
+        for i in 0..100 {
+            bucket.push(
+                hash(i),
+                Node {
+                    k: i,
+                    v: i * 3,
+                    expires: None,
+                    access: Access::default(),
+                },
+            );
+        }
+        for i in 0..100 {
+            assert_eq!(bucket.find(hash(i), &i).map(|n| n.v), Some(i * 3));
+        }
+        assert_eq!(bucket.iter().count(), 100);
+
+        for i in (0..100).step_by(2) {
+            assert_eq!(bucket.remove(hash(i), &i).map(|n| n.v), Some(i * 3));
+            assert!(bucket.find(hash(i), &i).is_none());
+        }
+        for i in (1..100).step_by(2) {
+            bucket.find_mut(hash(i), &i).unwrap().v = 0;
+        }
+        assert_eq!(bucket.iter().filter(|n| n.v == 0).count(), 50);
+
+        assert_eq!(bucket.retain(|n| n.k != 1), 1);
+        assert!(bucket.find(hash(1), &1).is_none());
+        assert!(bucket.find(hash(3), &3).is_some());
+
+        let drained: Vec<_> = bucket.drain().collect();
+        assert_eq!(drained.len(), 49);
+        assert!(bucket.is_empty());
+        assert!(bucket.find(hash(1), &1).is_none());
+    }
+}
@@ -1,134 +1,1189 @@
-use std::{process::exit, sync::atomic::Ordering, thread};
+use std::{
+    any::Any,
+    hash::RandomState,
+    io,
+    mem::MaybeUninit,
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+    process::exit,
+    ptr::null_mut,
+    thread,
+    time::{Duration, SystemTime},
+};
 
+use anyhow::bail;
 use clap::Parser;
+use libc::{
+    kill, pthread_sigmask, sigaddset, sigemptyset, sigset_t, sigwait, ESRCH, SIGHUP, SIGINT,
+    SIGTERM, SIG_BLOCK,
+};
 use rustix::shm::unlink;
 
 pub mod cli;
 pub mod hash_table;
+pub mod snapshot;
+pub mod value;
+pub mod wal;
 
-use cli::Args;
-use hash_table::HashTable;
+use cli::{Args, Backend};
+use hash_table::{
+    eviction::Eviction, Bucket, ChainBucket, Conditional, HashTable, SwissBucket, TtlOverflow,
+};
 use shared::{
-    shm::SharedMemory, HashtableMemory, KeyType, RequestData, RequestFrame, RequestPayload,
-    ResponseData, ResponseFrame, ResponsePayload, ValueType, DESCRIPTOR, REQ_BUFFER_SIZE,
-    RES_BUFFER_SIZE,
+    key::KeyTooLong,
+    shm::SharedMemory,
+    slab::{Slab, SERVER},
+    sync::{MutexGuard, OwnerDied},
+    wire::{EncodedRequest, MalformedRequest},
+    AbortReason, BatchResult, CheckOk, ErrorCode, Features, HashtableMemory, KeyType, MailboxQueue,
+    Overflow, QueueKind, RequestData, RequestFrame, RequestPayload, ResponseData, ResponsePayload,
+    TransactionOp, ValueType, BATCH_SIZE, BUCKET_CHUNK_SIZE, DESCRIPTOR, HEARTBEAT_INTERVAL,
+    MAX_KEY_SIZE, MAX_SHARDS, MAX_VALUE_SIZE, REQ_BUFFER_SIZE, TRANSACTION_SIZE,
 };
+use value::Value;
+use wal::{Record, Wal};
 
 fn main() -> anyhow::Result<()> {
     let args = Args::parse();
+    if !(1..=MAX_SHARDS).contains(&args.shards) || args.shards > args.num_threads {
+        bail!(
+            "The number of shards must be between 1 and {}, and at most the number of threads",
+            MAX_SHARDS
+        );
+    }
+    if args.max_value_size > MAX_VALUE_SIZE {
+        bail!("The maximum value size is at most {MAX_VALUE_SIZE} bytes");
+    }
+    if args.max_key_size > MAX_KEY_SIZE {
+        bail!("The maximum key size is at most {MAX_KEY_SIZE} bytes");
+    }
+    // The log only contains the mutations since the last snapshot
+    if let (Some(_), Some(snapshot), Some(restore)) = (&args.wal, &args.snapshot, &args.restore) {
+        if restore != snapshot {
+            bail!("With a write-ahead log, --restore has to be the --snapshot path");
+        }
+    }
 
-    let mem = SharedMemory::create(DESCRIPTOR, |mem| unsafe {
-        HashtableMemory::init_in_shm(mem.as_mut_ptr(), args.num_threads);
-    })?;
+    // Block the signals in all threads, they are handled by `handle_signals`.
+    // This has to happen before any other thread is spawned
+    let signals = signal_set(&[SIGINT, SIGTERM, SIGHUP]);
+    unsafe { pthread_sigmask(SIG_BLOCK, &signals, null_mut()) }.r("pthread_sigmask")?;
 
-    let hm: HashTable<KeyType, ValueType> = HashTable::new(args.size);
+    let features = match args.snapshot {
+        Some(_) => Features::ALL,
+        None => Features::ALL.difference(Features::SNAPSHOT),
+    };
+    let mem = SharedMemory::create(DESCRIPTOR, features.bits(), |mem| unsafe {
+        HashtableMemory::init_in_shm(
+            mem.as_mut_ptr(),
+            args.shards,
+            args.queue.into(),
+            args.max_value_size,
+        );
+    })?;
 
     println!("Initialized {}", DESCRIPTOR);
 
-    ctrlc::set_handler(move || {
-        println!("Terminating");
-        unlink(DESCRIPTOR).unwrap();
-        exit(0);
-    })?;
+    match args.backend {
+        Backend::Chain => serve::<ChainBucket<_, _>>(&args, &mem, &signals),
+        Backend::Swiss => serve::<SwissBucket<_, _>>(&args, &mem, &signals),
+    }
+}
+
+/// How long idle workers wait on their own shard before serving the other shards
+const STEAL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Interval of the background scans for expired entries
+const REAP_INTERVAL: Duration = Duration::from_secs(1);
+
+type Table<B> = HashTable<KeyType, Value, RandomState, B>;
+
+/// State shared by all threads of the server
+struct Server<'a, B>
+where
+    B: Bucket<KeyType, Value>,
+{
+    args: &'a Args,
+    hm: Table<B>,
+    wal: Option<Wal>,
+    /// Values of `InsertBytes` requests and `Bytes` responses
+    slab: &'a Slab,
+}
+
+fn serve<B>(
+    args: &Args,
+    mem: &SharedMemory<HashtableMemory>,
+    signals: &sigset_t,
+) -> anyhow::Result<()>
+where
+    B: Bucket<KeyType, Value>,
+{
+    // Restore before enabling eviction, evictions are part of the log
+    let (mut hm, wal) = load::<B>(args)?;
+
+    if args.max_entries.is_some() || args.max_bytes.is_some() {
+        println!(
+            "Limited to {:?} entries and {:?} bytes, evicting {:?}",
+            args.max_entries, args.max_bytes, args.eviction
+        );
+        hm = hm.with_eviction(Eviction {
+            max_entries: args.max_entries,
+            max_bytes: args.max_bytes,
+            policy: args.eviction,
+        });
+    }
+
+    let server = &Server {
+        args,
+        hm,
+        wal,
+        slab: &mem.get().slab,
+    };
 
     println!("Server is ready to accept connections");
 
     thread::scope(|s| {
         for i in 0..args.num_threads {
-            let _worker = format!("{i}");
-            s.spawn(|| {
+            s.spawn(move || {
                 let mem = mem.get();
+                let frames = &mem.request_frames[..args.shards];
+                let home = i % args.shards;
                 loop {
-                    let request = is_pop_item(&mem.request_frame);
-                    let response = process_request(request, &hm);
-                    os_push_item(response, &mem.response_frame);
+                    let frame = match mem.queue_kind {
+                        QueueKind::Mutex => pop_request(frames, home),
+                        QueueKind::LockFree => pop_request_lock_free(frames, home),
+                    };
+                    let request = match RequestData::decode(&frame) {
+                        Ok(request) => request,
+                        Err(malformed) => {
+                            reject_malformed(malformed, mem);
+                            continue;
+                        }
+                    };
+                    let respond = |response| os_push_item(response, request.mailbox, mem);
+                    // A failing request is answered with an error, instead of losing the worker.
+                    // The panic message was printed already
+                    let processed = panic::catch_unwind(AssertUnwindSafe(|| {
+                        process_request(request, server, respond)
+                    }));
+                    if let Err(panic) = processed {
+                        let detail = panic_message(&panic);
+                        let payload = ResponsePayload::error(ErrorCode::Internal, detail);
+                        let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+                            respond(ResponseData {
+                                client_id: request.client_id,
+                                request_id: request.request_id,
+                                payload,
+                            })
+                        }));
+                    }
                 }
             });
         }
 
+        // Clients consider the server gone once this stops
+        s.spawn(|| loop {
+            mem.beat();
+            thread::sleep(HEARTBEAT_INTERVAL);
+        });
+
+        // Expired entries are also removed lazily on access
+        s.spawn(|| loop {
+            thread::sleep(REAP_INTERVAL);
+            server.hm.remove_expired();
+            server.compact_wal();
+            reap_clients(mem.get());
+        });
+
+        if let Some(interval) = server.wal.as_ref().and_then(Wal::sync_interval) {
+            s.spawn(move || loop {
+                thread::sleep(interval);
+                if let Err(e) = server.wal.as_ref().unwrap().lock().sync() {
+                    eprintln!("Syncing the WAL failed: {e:?}");
+                }
+            });
+        }
+
+        s.spawn(|| handle_signals(signals, server));
+
         Ok(())
     })
 }
 
-fn process_request(request: RequestData, hm: &HashTable<KeyType, ValueType>) -> ResponseData {
+fn signal_set(signals: &[i32]) -> sigset_t {
+    let mut set = MaybeUninit::uninit();
+    unsafe {
+        sigemptyset(set.as_mut_ptr());
+        for &signal in signals {
+            sigaddset(set.as_mut_ptr(), signal);
+        }
+        set.assume_init()
+    }
+}
+
+/// SIGHUP writes a snapshot, SIGINT and SIGTERM write a snapshot and shut down the server
+fn handle_signals<B>(signals: &sigset_t, server: &Server<B>) -> !
+where
+    B: Bucket<KeyType, Value>,
+{
+    loop {
+        let mut signal = 0;
+        if unsafe { sigwait(signals, &mut signal) } != 0 {
+            continue;
+        }
+
+        match &server.args.snapshot {
+            Some(path) => match server.save_snapshot(path) {
+                Ok(entries) => println!("Wrote snapshot of {entries} entries to {path:?}"),
+                Err(e) => eprintln!("Writing snapshot failed: {e:?}"),
+            },
+            None if signal == SIGHUP => eprintln!("No snapshot path configured"),
+            None => {}
+        }
+
+        if signal != SIGHUP {
+            // Holding the log until the exit prevents further mutations
+            let mut log = server.wal.as_ref().map(Wal::lock);
+            if let Some(Err(e)) = log.as_mut().map(|log| log.sync()) {
+                eprintln!("Syncing the WAL failed: {e:?}");
+            }
+            println!("Terminating");
+            unlink(DESCRIPTOR).unwrap();
+            exit(0);
+        }
+    }
+}
+
+/// Restore the table from the snapshot and the WAL
+///
+/// Writing a snapshot truncates the WAL, so with both configured the snapshot is
+/// loaded even without `--restore` (unless none was written yet).
+fn load<B>(args: &Args) -> anyhow::Result<(Table<B>, Option<Wal>)>
+where
+    B: Bucket<KeyType, Value>,
+{
+    let hm: Table<B> = HashTable::new(args.size);
+
+    let restore = match (&args.restore, &args.wal, &args.snapshot) {
+        (Some(path), _, _) => Some(path),
+        (None, Some(_), Some(path)) if path.exists() => Some(path),
+        _ => None,
+    };
+    if let Some(path) = restore {
+        let entries = snapshot::read(path)?;
+        let restored = hm.restore(entries);
+        println!("Restored {restored} entries from {path:?}");
+    }
+
+    let wal = match &args.wal {
+        Some(path) => {
+            let (wal, records) = Wal::open(path, args.wal_sync)?;
+            println!("Replaying {} records from {path:?}", records.len());
+            replay(&hm, records);
+            Some(wal)
+        }
+        None => None,
+    };
+    Ok((hm, wal))
+}
+
+/// Apply the records of a WAL, in order
+fn replay<B>(hm: &Table<B>, records: Vec<Record>)
+where
+    B: Bucket<KeyType, Value>,
+{
+    for record in records {
+        match record {
+            Record::Insert(entry) => {
+                hm.restore([entry]);
+            }
+            Record::Delete(k) => {
+                hm.remove(k);
+            }
+            Record::Touch(k, expires) => match expires.map(|e| e.duration_since(SystemTime::now()))
+            {
+                Some(Err(_)) => {
+                    hm.remove(k);
+                }
+                ttl => {
+                    let _ = hm.touch(k, ttl.map(Result::unwrap));
+                }
+            },
+            Record::Replace(k, v) => {
+                hm.replace_if_present(k, v);
+            }
+        }
+    }
+}
+
+impl<B> Server<'_, B>
+where
+    B: Bucket<KeyType, Value>,
+{
+    /// Apply a mutation, and append the records describing its effect to the WAL (if enabled)
+    ///
+    /// Fails if the records could not be appended, or synced with [`wal::SyncPolicy::Always`].
+    /// The mutation is still applied in memory then, but it is not durable.
+    fn mutate<R>(
+        &self,
+        apply: impl FnOnce(&Table<B>) -> R,
+        records: impl FnOnce(&R) -> Vec<Record>,
+    ) -> anyhow::Result<R> {
+        let Some(wal) = &self.wal else {
+            return Ok(apply(&self.hm));
+        };
+        let mut log = wal.lock();
+        let result = apply(&self.hm);
+        log.append(&records(&result))?;
+        Ok(result)
+    }
+
+    /// Write a snapshot, and remove all records it contains from the WAL
+    fn save_snapshot(&self, path: &Path) -> anyhow::Result<usize> {
+        // Mutations are blocked until the log is truncated
+        let log = self.wal.as_ref().map(Wal::lock);
+        let entries = self.hm.snapshot();
+        snapshot::write(path, &entries)?;
+        if let Some(mut log) = log {
+            log.truncate()?;
+        }
+        Ok(entries.len())
+    }
+
+    /// Fold the WAL into a snapshot, once it reached the configured size
+    fn compact_wal(&self) {
+        let (Some(wal), Some(path)) = (&self.wal, &self.args.snapshot) else {
+            return;
+        };
+        if wal.lock().len() < self.args.wal_compact_size {
+            return;
+        }
+        match self.save_snapshot(path) {
+            Ok(entries) => println!("Compacted WAL into snapshot of {entries} entries"),
+            Err(e) => eprintln!("Compacting the WAL failed: {e:?}"),
+        }
+    }
+}
+
+/// Response to a mutation that could not be written to the WAL
+fn not_durable(e: anyhow::Error) -> ResponsePayload {
+    eprintln!("Appending to the WAL failed: {e:?}");
+    ResponsePayload::error(
+        ErrorCode::Internal,
+        &format!("the write is not durable: {e:#}"),
+    )
+}
+
+/// Expiry time of an entry in the WAL
+fn wal_expiry(ttl: Option<Duration>) -> Result<Option<SystemTime>, TtlOverflow> {
+    ttl.map(|ttl| SystemTime::now().checked_add(ttl).ok_or(TtlOverflow))
+        .transpose()
+}
+
+fn ttl_overflow(e: TtlOverflow) -> ResponsePayload {
+    ResponsePayload::error(ErrorCode::InvalidArgument, &e.to_string())
+}
+
+/// Insert (or overwrite) an entry, which expires after `ttl` if set
+fn insert<B>(server: &Server<B>, k: KeyType, v: Value, ttl: Option<Duration>) -> ResponsePayload
+where
+    B: Bucket<KeyType, Value>,
+{
+    let expires = match wal_expiry(ttl) {
+        Ok(expires) => expires,
+        Err(e) => return ttl_overflow(e),
+    };
+    let evicted = server.mutate(
+        |hm| hm.insert_with_ttl(k, v.clone(), ttl),
+        |evicted| {
+            let Ok(evicted) = evicted else {
+                return Vec::new();
+            };
+            let mut records = vec![Record::Insert((k, v.clone(), expires))];
+            records.extend(evicted.iter().copied().map(Record::Delete));
+            records
+        },
+    );
+    match evicted {
+        Ok(Ok(evicted)) => match evicted.first() {
+            Some(&evicted) => ResponsePayload::InsertedEvicted(evicted),
+            None => ResponsePayload::Inserted,
+        },
+        Ok(Err(e)) => ttl_overflow(e),
+        Err(e) => not_durable(e),
+    }
+}
+
+/// Records of a conditional operation, `record` describes its effect if it succeeded
+fn conditional(
+    result: &Conditional<KeyType, Value>,
+    record: impl FnOnce() -> Record,
+) -> Vec<Record> {
+    let mut records: Vec<_> = result.success.then(record).into_iter().collect();
+    records.extend(result.evicted.iter().copied().map(Record::Delete));
+    records
+}
+
+/// Conditional operations only succeed on numbers, they fail on entries holding bytes
+fn conditional_response(result: Conditional<KeyType, Value>) -> ResponsePayload {
+    match result.current {
+        Some(Value::Bytes(_)) => wrong_type(),
+        current => ResponsePayload::Conditional {
+            success: result.success,
+            current: current.as_ref().and_then(Value::as_int),
+        },
+    }
+}
+
+fn wrong_type() -> ResponsePayload {
+    ResponsePayload::error(ErrorCode::WrongType, "the entry holds bytes, not a number")
+}
+
+/// Update a numeric value, responds with the previous value (`default` for new entries)
+fn fetch<B>(
+    server: &Server<B>,
+    k: KeyType,
+    default: ValueType,
+    f: impl FnOnce(ValueType) -> ValueType,
+) -> ResponsePayload
+where
+    B: Bucket<KeyType, Value>,
+{
+    let fetched = server.mutate(
+        |hm| {
+            hm.fetch_update(k, Value::Int(default), |v| {
+                v.as_int().map(|v| Value::Int(f(v)))
+            })
+        },
+        |fetched| {
+            let Some(fetched) = fetched else {
+                return Vec::new();
+            };
+            let mut records = vec![match fetched.previous {
+                Some(_) => Record::Replace(k, fetched.current.clone()),
+                None => Record::Insert((k, fetched.current.clone(), None)),
+            }];
+            records.extend(fetched.evicted.iter().copied().map(Record::Delete));
+            records
+        },
+    );
+    match fetched {
+        Ok(Some(fetched)) => ResponsePayload::Fetched(
+            fetched
+                .previous
+                .as_ref()
+                .and_then(Value::as_int)
+                .unwrap_or(default),
+        ),
+        Ok(None) => wrong_type(),
+        Err(e) => not_durable(e),
+    }
+}
+
+/// Value of an entry read by a transaction, transactions only operate on numbers
+fn numeric(value: Option<Value>) -> Result<Option<ValueType>, AbortReason> {
+    match value {
+        Some(Value::Bytes(_)) => Err(AbortReason::WrongType),
+        value => Ok(value.as_ref().and_then(Value::as_int)),
+    }
+}
+
+/// Execute the operations of a transaction atomically
+fn transaction<B>(server: &Server<B>, len: usize, ops: &[TransactionOp]) -> ResponsePayload
+where
+    B: Bucket<KeyType, Value>,
+{
+    let Some(ops) = ops.get(..len) else {
+        return ResponsePayload::Aborted {
+            index: TRANSACTION_SIZE,
+            reason: AbortReason::TooLarge,
+        };
+    };
+    let keys: Vec<_> = ops.iter().map(TransactionOp::key).collect();
+
+    let result = server.mutate(
+        |hm| {
+            hm.transaction(&keys, |tx| {
+                let mut results = [None; TRANSACTION_SIZE];
+                for (index, (op, result)) in ops.iter().zip(&mut results).enumerate() {
+                    let abort = |reason| (index, reason);
+                    let current = numeric(tx.get(&op.key())).map_err(abort)?;
+                    *result = match *op {
+                        TransactionOp::Get(_) => current,
+                        TransactionOp::Insert(k, v) => {
+                            tx.insert(k, Value::Int(v));
+                            current
+                        }
+                        TransactionOp::Delete(k) => {
+                            tx.remove(&k);
+                            current
+                        }
+                        TransactionOp::Check(_, expected) => {
+                            if current != expected {
+                                return Err(abort(AbortReason::CheckFailed(current)));
+                            }
+                            current
+                        }
+                        TransactionOp::Add(k, delta) => {
+                            let new = i64::from(current.unwrap_or(0))
+                                .checked_add(delta)
+                                .and_then(|v| ValueType::try_from(v).ok())
+                                .ok_or(abort(AbortReason::Overflow))?;
+                            tx.update(k, Value::Int(new));
+                            current
+                        }
+                    };
+                }
+                Ok(results)
+            })
+        },
+        |result| {
+            let Ok((results, evicted)) = result else {
+                return Vec::new();
+            };
+            // Replaying the writes in order results in the same entries
+            let mut records: Vec<_> = ops
+                .iter()
+                .zip(results)
+                .filter_map(|(op, &previous)| match *op {
+                    TransactionOp::Insert(k, v) => Some(Record::Insert((k, Value::Int(v), None))),
+                    TransactionOp::Delete(k) => previous.map(|_| Record::Delete(k)),
+                    TransactionOp::Add(k, delta) => {
+                        let new =
+                            Value::Int((i64::from(previous.unwrap_or(0)) + delta) as ValueType);
+                        Some(match previous {
+                            Some(_) => Record::Replace(k, new),
+                            None => Record::Insert((k, new, None)),
+                        })
+                    }
+                    TransactionOp::Get(_) | TransactionOp::Check(..) => None,
+                })
+                .collect();
+            records.extend(evicted.iter().copied().map(Record::Delete));
+            records
+        },
+    );
+
+    match result {
+        Ok(Ok((results, _))) => ResponsePayload::Committed { len, results },
+        Ok(Err((index, reason))) => ResponsePayload::Aborted { index, reason },
+        Err(e) => not_durable(e),
+    }
+}
+
+/// Execute a request, and pass its response(s) to `respond`
+fn process_request<B>(
+    request: RequestData,
+    server: &Server<B>,
+    mut respond: impl FnMut(ResponseData),
+) where
+    B: Bucket<KeyType, Value>,
+{
+    let hm = &server.hm;
+    let response = |payload| ResponseData {
+        client_id: request.client_id,
+        request_id: request.request_id,
+        payload,
+    };
+
+    // Operations of batches are checked one by one, when they are processed
+    let max = server.args.max_key_size;
+    if let Some(key) = request.payload.keys().find(|key| key.len() > max) {
+        let error = KeyTooLong {
+            len: key.len(),
+            max,
+        };
+        respond(response(ResponsePayload::error(
+            error.code(),
+            &error.to_string(),
+        )));
+        return;
+    }
+
     let payload = match request.payload {
-        RequestPayload::Insert(k, v) => {
-            hm.insert(k, v);
-            ResponsePayload::Inserted
+        RequestPayload::Insert(k, v) => insert(server, k, Value::Int(v), None),
+        RequestPayload::InsertWithTtl(k, v, ttl) => insert(server, k, Value::Int(v), Some(ttl)),
+        RequestPayload::InsertBytes(k, blob) => match server.slab.take(blob, request.client_id) {
+            Ok(bytes) => insert(server, k, Value::Bytes(bytes.into()), None),
+            Err(e) => ResponsePayload::error(e.code(), &e.to_string()),
+        },
+        RequestPayload::Touch(k, ttl) => match wal_expiry(ttl) {
+            Ok(expires) => {
+                let touched = server.mutate(
+                    |hm| hm.touch(k, ttl),
+                    |&touched| match touched {
+                        Ok(true) => vec![Record::Touch(k, expires)],
+                        _ => vec![],
+                    },
+                );
+                match touched {
+                    Ok(Ok(true)) => ResponsePayload::Touched,
+                    Ok(Ok(false)) => ResponsePayload::NotFound,
+                    Ok(Err(e)) => ttl_overflow(e),
+                    Err(e) => not_durable(e),
+                }
+            }
+            Err(e) => ttl_overflow(e),
+        },
+        RequestPayload::InsertIfAbsent(k, v) => {
+            let result = server.mutate(
+                |hm| hm.insert_if_absent(k, Value::Int(v)),
+                |result| conditional(result, || Record::Insert((k, Value::Int(v), None))),
+            );
+            result.map_or_else(not_durable, conditional_response)
+        }
+        RequestPayload::ReplaceIfPresent(k, v) => {
+            let result = server.mutate(
+                |hm| hm.replace_if_present(k, Value::Int(v)),
+                |result| conditional(result, || Record::Replace(k, Value::Int(v))),
+            );
+            result.map_or_else(not_durable, conditional_response)
+        }
+        RequestPayload::CompareAndSwap { key, expected, new } => {
+            let result = server.mutate(
+                |hm| hm.compare_and_swap(key, Value::Int(expected), Value::Int(new)),
+                |result| conditional(result, || Record::Replace(key, Value::Int(new))),
+            );
+            result.map_or_else(not_durable, conditional_response)
         }
+        RequestPayload::FetchAdd(k, delta, overflow) => {
+            let add = match overflow {
+                Overflow::Saturating => ValueType::saturating_add,
+                Overflow::Wrapping => ValueType::wrapping_add,
+            };
+            fetch(server, k, 0, |v| add(v, delta))
+        }
+        RequestPayload::FetchSub(k, delta, overflow) => {
+            let sub = match overflow {
+                Overflow::Saturating => ValueType::saturating_sub,
+                Overflow::Wrapping => ValueType::wrapping_sub,
+            };
+            fetch(server, k, 0, |v| sub(v, delta))
+        }
+        RequestPayload::FetchMin(k, other) => fetch(server, k, ValueType::MAX, |v| v.min(other)),
+        RequestPayload::FetchMax(k, other) => fetch(server, k, 0, |v| v.max(other)),
+        RequestPayload::Batch { len, ops } => {
+            let mut results = [BatchResult::NotFound; BATCH_SIZE];
+            for (op, result) in ops[..len].iter().zip(&mut results) {
+                let request = RequestData {
+                    payload: (*op).into(),
+                    ..request
+                };
+                // Dynamic dispatch, to not instantiate `process_request` recursively
+                let respond: &mut dyn FnMut(ResponseData) = &mut |response| {
+                    *result = response
+                        .payload
+                        .try_into()
+                        .expect("batch operations have a single result");
+                };
+                process_request(request, server, respond);
+            }
+            ResponsePayload::Batch { len, results }
+        }
+        RequestPayload::Transaction { len, ops } => transaction(server, len, &ops),
+        RequestPayload::Get(k) => match hm.get(k) {
+            Some(Value::Int(v)) => ResponsePayload::Value(v),
+            // Handed over to the client when the response is written to its mailbox
+            Some(Value::Bytes(bytes)) => match server.slab.alloc(&bytes, SERVER) {
+                Ok(blob) => ResponsePayload::Bytes(blob),
+                Err(e) => ResponsePayload::error(e.code(), &e.to_string()),
+            },
+            None => ResponsePayload::NotFound,
+        },
         RequestPayload::ReadBucket(k) => {
-            let res = hm.read_bucket(k);
-            let list: Vec<(KeyType, u32)> = res.iter().map(|n| (n.k, n.v)).collect();
-            let len = list.len();
-            if len > 32 {
-                ResponsePayload::Overflow
-            } else {
-                let mut data = [(KeyType::new(), 0); 32];
-                data[..len].copy_from_slice(&list);
-                ResponsePayload::BucketContent { len, data }
+            let list: Vec<_> = hm
+                .read_bucket(k)
+                .into_iter()
+                .filter_map(|(k, v)| Some((k, v.as_int()?)))
+                .collect();
+            // Large buckets are streamed as multiple responses, all but the last have `more` set
+            let mut chunks = list.chunks(BUCKET_CHUNK_SIZE).peekable();
+            loop {
+                let chunk = chunks.next().unwrap_or_default();
+                let len = chunk.len();
+                let more = chunks.peek().is_some();
+                let mut data = [(KeyType::new(), 0); BUCKET_CHUNK_SIZE];
+                data[..len].copy_from_slice(chunk);
+                let payload = ResponsePayload::BucketContent { len, more, data };
+                if !more {
+                    break payload;
+                }
+                respond(response(payload));
             }
         }
         RequestPayload::Delete(k) => {
-            if let Some(_v) = hm.remove(k) {
-                ResponsePayload::Deleted
-            } else {
-                ResponsePayload::NotFound
+            let removed = server.mutate(
+                |hm| hm.remove(k),
+                |removed| removed.iter().map(|_| Record::Delete(k)).collect(),
+            );
+            match removed {
+                Ok(Some(_v)) => ResponsePayload::Deleted,
+                Ok(None) => ResponsePayload::NotFound,
+                Err(e) => not_durable(e),
             }
         }
+        RequestPayload::Snapshot => match &server.args.snapshot {
+            Some(path) => match server.save_snapshot(path) {
+                Ok(entries) => ResponsePayload::Snapshotted {
+                    entries: entries as u64,
+                },
+                Err(e) => {
+                    eprintln!("Writing snapshot failed: {e:?}");
+                    ResponsePayload::error(ErrorCode::Internal, &format!("{e:#}"))
+                }
+            },
+            None => ResponsePayload::error(
+                ErrorCode::Unsupported,
+                "the server has no snapshot path configured",
+            ),
+        },
         RequestPayload::PrintHashmap => {
             println!("{:?}", hm);
             ResponsePayload::Printed
         }
     };
 
-    let response = ResponseData {
-        client_id: request.client_id,
-        request_id: request.request_id,
-        payload,
-    };
-    response
+    respond(response(payload));
+}
+
+/// Message of a caught panic, if it has one
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => panic
+            .downcast_ref::<String>()
+            .map_or("the server panicked", String::as_str),
+    }
 }
 
-fn is_pop_item(is: &RequestFrame) -> RequestData {
-    is.count.wait();
+/// Pop a request from the `home` shard, or steal one from the other shards if it is empty
+fn pop_request(frames: &[RequestFrame], home: usize) -> EncodedRequest {
+    if frames.len() == 1 {
+        return is_pop_item(&frames[home]);
+    }
+    let stealing_order = || frames.iter().cycle().skip(home).take(frames.len());
+    loop {
+        let stolen = stealing_order()
+            .filter(|frame| frame.count.try_wait().is_ok())
+            .find_map(is_take_item);
+        if let Some(request) = stolen {
+            return request;
+        }
+        if frames[home].count.wait_timeout(STEAL_INTERVAL).is_ok() {
+            if let Some(request) = is_take_item(&frames[home]) {
+                return request;
+            }
+        }
+    }
+}
 
-    let mut queue = is.queue.lock();
+/// Same as [`pop_request`], for the lock-free rings
+fn pop_request_lock_free(frames: &[RequestFrame], home: usize) -> EncodedRequest {
+    if frames.len() == 1 {
+        return frames[home].ring.pop();
+    }
+    let stealing_order = || frames.iter().cycle().skip(home).take(frames.len());
+    loop {
+        if let Some(request) = stealing_order().find_map(|frame| frame.ring.try_pop()) {
+            return request;
+        }
+        if let Some(request) = frames[home].ring.pop_timeout(STEAL_INTERVAL) {
+            return request;
+        }
+    }
+}
 
-    let id = queue.read & (REQ_BUFFER_SIZE - 1);
-    let item = &mut queue.buffer[id];
+fn is_pop_item(is: &RequestFrame) -> EncodedRequest {
+    loop {
+        is.count.wait();
+        if let Some(request) = is_take_item(is) {
+            return request;
+        }
+    }
+}
 
-    let data = unsafe { item.assume_init() };
+/// Take an item out of the queue, after a successful wait on `count`
+///
+/// Returns `None` if the queue is empty, which happens if `count` was posted
+/// twice for an item, while repairing the queue after a client died
+fn is_take_item(is: &RequestFrame) -> Option<EncodedRequest> {
+    let mut queue = is.lock_queue();
+    if queue.read == queue.write {
+        return None;
+    }
+
+    let id = queue.read & (REQ_BUFFER_SIZE - 1);
+    // Decoded by the worker after releasing the lock
+    let data = queue.buffer[id];
 
     queue.read = queue.read.wrapping_add(1);
+    // Posted under the lock, so clients count permits and free slots consistently
+    is.space.post();
 
     drop(queue);
 
-    is.space.post();
-    data
+    Some(data)
 }
 
-fn os_push_item(item: ResponseData, os: &ResponseFrame) {
-    os.space.wait();
+/// Answer a request that could not be decoded with an error, if its mailbox is valid
+fn reject_malformed(malformed: MalformedRequest, mem: &HashtableMemory) {
+    let MalformedRequest {
+        client_id,
+        request_id,
+        error,
+        ..
+    } = malformed;
+    eprintln!("Malformed request {request_id} of client {client_id}: {error}");
+    os_push_item(malformed_response(malformed), malformed.mailbox, mem);
+}
 
-    let mut tail = os.tail.lock();
+fn malformed_response(malformed: MalformedRequest) -> ResponseData {
+    ResponseData {
+        client_id: malformed.client_id,
+        request_id: malformed.request_id,
+        payload: ResponsePayload::error(malformed.error.code(), &malformed.error.to_string()),
+    }
+}
 
-    if tail.rx_cnt == 0 {
-        eprintln!("All clients left the channel, dropping msg: {item:?}");
+/// Write a response to the mailbox of the client that sent the request
+fn os_push_item(item: ResponseData, mailbox: u32, mem: &HashtableMemory) {
+    let drop_values = || {
+        for blob in item.payload.blobs() {
+            let _ = mem.slab.free(blob, SERVER);
+        }
+    };
+    let Some(mailbox) = mem.mailboxes.get(mailbox as usize) else {
+        eprintln!("Invalid mailbox {mailbox}, dropping msg: {item:?}");
+        drop_values();
         return;
+    };
+
+    let mut queue = mailbox
+        .queue
+        .lock()
+        .unwrap_or_else(|died| client_died(died, mem));
+    loop {
+        if queue.owner != Some(item.client_id) {
+            eprintln!("Client left, dropping msg: {item:?}");
+            drop_values();
+            // Other workers may still wait for space
+            mailbox.space.notify_all();
+            return;
+        }
+        if !queue.is_full() {
+            // Under the lock of the mailbox, so the values are freed when the client leaves
+            for blob in item.payload.blobs() {
+                let _ = mem.slab.give(blob, SERVER, item.client_id);
+            }
+            queue.push(&item);
+            mailbox.ready.notify_one();
+            return;
+        }
+        // Clients notify once they took responses out, or left, the reaper once they died
+        queue = mailbox
+            .space
+            .wait(queue, None)
+            .unwrap_or_else(|died| client_died(died, mem));
+    }
+}
+
+/// Repair a mailbox whose previous lock owner died
+///
+/// Workers do not die on their own, so the previous owner must have been the client
+fn client_died<'a>(
+    died: OwnerDied<MutexGuard<'a, MailboxQueue>>,
+    mem: &HashtableMemory,
+) -> MutexGuard<'a, MailboxQueue> {
+    let mut queue = died.into_inner();
+    eprintln!("Client {:?} died, freeing its mailbox", queue.owner);
+    queue.unregister(&mem.shards, &mem.slab);
+    queue
+}
+
+/// Free the mailboxes of clients whose process exited, or whose lease expired
+fn reap_clients(mem: &HashtableMemory) {
+    for mailbox in &mem.mailboxes {
+        let mut died = false;
+        let mut queue = mailbox.queue.lock().unwrap_or_else(|d| {
+            died = true;
+            client_died(d, mem)
+        });
+        if let Some(client_id) = queue.owner {
+            let exited = unsafe { kill(queue.pid, 0) } != 0
+                && io::Error::last_os_error().raw_os_error() == Some(ESRCH);
+            if exited || queue.is_expired() {
+                eprintln!("Reaping client {client_id} (pid {})", queue.pid);
+                queue.unregister(&mem.shards, &mem.slab);
+                died = true;
+            }
+        }
+        drop(queue);
+        if died {
+            // Workers waiting for space drop their responses now
+            mailbox.space.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        env, fs,
+        panic::{self, AssertUnwindSafe},
+        process,
+        time::Duration,
+    };
+
+    use clap::Parser;
+    use shared::{
+        slab::Slab, BatchOp, ErrorCode, KeyType, RequestData, RequestPayload, ResponsePayload,
+        TransactionOp, BATCH_SIZE, TRANSACTION_SIZE,
+    };
+
+    use super::{
+        cli::Args, hash_table::ChainBucket, load, malformed_response, process_request, HashTable,
+        Server, Value, SERVER,
+    };
+
+    fn slab(args: &Args) -> Box<Slab> {
+        let mut slab = Box::<Slab>::new_uninit();
+        unsafe {
+            Slab::init_at(slab.as_mut_ptr(), args.max_value_size);
+            slab.assume_init()
+        }
+    }
+
+    fn server<'a>(args: &'a Args, slab: &'a Slab) -> Server<'a, ChainBucket<KeyType, Value>> {
+        Server {
+            args,
+            hm: HashTable::new(4),
+            wal: None,
+            slab,
+        }
     }
 
-    let pos = tail.pos;
-    let rem = tail.rx_cnt;
+    /// Process a request with a single response
+    /// Process a request like the workers do, after encoding and decoding it
+    fn request(
+        server: &Server<ChainBucket<KeyType, Value>>,
+        payload: RequestPayload,
+    ) -> ResponsePayload {
+        let mut responses = Vec::new();
+        let request = RequestData {
+            client_id: 1,
+            mailbox: 0,
+            request_id: 2,
+            payload,
+        };
+        match RequestData::decode(&request.encode()) {
+            Ok(request) => {
+                process_request(request, server, |response| responses.push(response.payload))
+            }
+            Err(malformed) => responses.push(malformed_response(malformed).payload),
+        }
+        assert_eq!(responses.len(), 1);
+        responses.pop().unwrap()
+    }
 
-    let id = (pos & (RES_BUFFER_SIZE - 1) as u64) as usize;
+    #[test]
+    fn get() {
+        let args = Args::parse_from(["server", "-s", "4"]);
+        let slab = slab(&args);
+        let server = server(&args, &slab);
+        let key = KeyType::try_from("key").unwrap();
+        let other = KeyType::try_from("other").unwrap();
 
-    let lock = &os.buffer[id];
-    let mut slot = lock.write();
+        assert!(matches!(
+            request(&server, RequestPayload::Get(key)),
+            ResponsePayload::NotFound
+        ));
+        request(&server, RequestPayload::Insert(key, 7));
+        assert!(matches!(
+            request(&server, RequestPayload::Get(key)),
+            ResponsePayload::Value(7)
+        ));
+        assert!(matches!(
+            request(&server, RequestPayload::Get(other)),
+            ResponsePayload::NotFound
+        ));
 
-    tail.pos = tail.pos.wrapping_add(1);
+        let blob = slab.alloc(b"bytes", 1).unwrap();
+        request(&server, RequestPayload::InsertBytes(other, blob));
+        let ResponsePayload::Bytes(blob) = request(&server, RequestPayload::Get(other)) else {
+            panic!("expected bytes");
+        };
+        // Handed over to the client once the response is in its mailbox
+        assert_eq!(slab.take(blob, SERVER).unwrap(), b"bytes");
+    }
 
-    slot.pos = pos;
-    slot.rem.store(rem, Ordering::Relaxed);
+    #[test]
+    fn ttl_overflow() {
+        let args = Args::parse_from(["server", "-s", "4"]);
+        let slab = slab(&args);
+        let server = server(&args, &slab);
+        let key = KeyType::try_from("key").unwrap();
 
-    slot.val.write(item);
+        request(&server, RequestPayload::Insert(key, 1));
+        for payload in [
+            RequestPayload::Touch(key, Some(Duration::MAX)),
+            RequestPayload::InsertWithTtl(key, 2, Duration::MAX),
+        ] {
+            let response = request(&server, payload);
+            assert!(matches!(
+                response,
+                ResponsePayload::Error {
+                    code: ErrorCode::InvalidArgument,
+                    ..
+                }
+            ));
+        }
+        assert!(matches!(
+            request(&server, RequestPayload::Get(key)),
+            ResponsePayload::Value(1)
+        ));
+    }
+
+    #[test]
+    fn restart_after_compaction() {
+        let dir = env::temp_dir().join(format!("hashtable_restart_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (wal, snapshot) = (dir.join("wal"), dir.join("snapshot"));
+        let args = Args::parse_from([
+            "server".as_ref(),
+            "-s".as_ref(),
+            "4".as_ref(),
+            "--wal".as_ref(),
+            wal.as_os_str(),
+            "--snapshot".as_ref(),
+            snapshot.as_os_str(),
+        ]);
+        let slab = slab(&args);
+        let a = KeyType::try_from("a").unwrap();
+        let b = KeyType::try_from("b").unwrap();
+
+        let (hm, wal) = load(&args).unwrap();
+        let server: Server<ChainBucket<_, _>> = Server {
+            args: &args,
+            hm,
+            wal,
+            slab: &slab,
+        };
+        request(&server, RequestPayload::Insert(a, 1));
+        // Truncates the log
+        let response = request(&server, RequestPayload::Snapshot);
+        assert!(matches!(
+            response,
+            ResponsePayload::Snapshotted { entries: 1 }
+        ));
+        request(&server, RequestPayload::Insert(b, 2));
+        drop(server);
+
+        // Restarted without `--restore`
+        let (hm, _wal) = load::<ChainBucket<_, _>>(&args).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(hm.get(a), Some(Value::Int(1))));
+        assert!(matches!(hm.get(b), Some(Value::Int(2))));
+    }
+
+    #[test]
+    fn batch_too_large() {
+        let args = Args::parse_from(["server", "-s", "4"]);
+        let slab = slab(&args);
+        let server = server(&args, &slab);
+        let key = KeyType::try_from("key").unwrap();
+
+        let ops = [BatchOp::Insert(key, 1); BATCH_SIZE];
+        let response = request(
+            &server,
+            RequestPayload::Batch {
+                len: BATCH_SIZE + 1,
+                ops,
+            },
+        );
+        assert!(matches!(
+            response,
+            ResponsePayload::Error {
+                code: ErrorCode::Malformed,
+                ..
+            }
+        ));
+        let ops = [TransactionOp::Insert(key, 1); TRANSACTION_SIZE];
+        let response = request(
+            &server,
+            RequestPayload::Transaction {
+                len: TRANSACTION_SIZE + 1,
+                ops,
+            },
+        );
+        assert!(matches!(
+            response,
+            ResponsePayload::Error {
+                code: ErrorCode::Malformed,
+                ..
+            }
+        ));
+        // None of the operations was executed
+        assert!(matches!(
+            request(&server, RequestPayload::Get(key)),
+            ResponsePayload::NotFound
+        ));
+
+        let response = request(
+            &server,
+            RequestPayload::Batch {
+                len: BATCH_SIZE,
+                ops: [BatchOp::Insert(key, 1); BATCH_SIZE],
+            },
+        );
+        assert!(matches!(
+            response,
+            ResponsePayload::Batch {
+                len: BATCH_SIZE,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn panic_while_locked() {
+        let dir = env::temp_dir().join(format!("hashtable_panic_{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let wal = dir.join("wal");
+        let args = Args::parse_from([
+            "server".as_ref(),
+            "-s".as_ref(),
+            "4".as_ref(),
+            "--wal".as_ref(),
+            wal.as_os_str(),
+        ]);
+        let slab = slab(&args);
+        let (hm, wal) = load(&args).unwrap();
+        let server: Server<ChainBucket<_, _>> = Server {
+            args: &args,
+            hm,
+            wal,
+            slab: &slab,
+        };
+        let key = KeyType::try_from("key").unwrap();
+
+        // Holds the WAL and the bucket of `key` locked, like the workers they survive the panic
+        let panicked = panic::catch_unwind(AssertUnwindSafe(|| {
+            server.mutate(
+                |hm| hm.transaction(&[key], |_| -> Result<(), ()> { panic!("bad request") }),
+                |_| Vec::new(),
+            )
+        }));
+        assert!(panicked.is_err());
+
+        assert!(matches!(
+            request(&server, RequestPayload::Insert(key, 1)),
+            ResponsePayload::Inserted
+        ));
+        assert!(matches!(
+            request(&server, RequestPayload::Get(key)),
+            ResponsePayload::Value(1)
+        ));
+        drop(server);
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,229 @@
+//! On-disk snapshots of the table contents
+//!
+//! Format, all integers are little endian:
+//! - Header: magic `b"HTSNAP"`, version (`u16`), number of entries (`u64`)
+//! - Per entry: key length (`u8`), key (bytes), value,
+//!   expiry in milliseconds since the unix epoch (`u64`, 0 if the entry does not expire)
+//! - Values: tag (`u8`), followed by a number (0, `u32`), or by length (`u32`) and bytes (1).
+//!   Version 1 only had numbers, without a tag
+
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, PoisonError},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context};
+use shared::{KeyType, ValueType, MAX_KEY_SIZE, MAX_VALUE_SIZE};
+
+use crate::value::Value;
+
+pub const SNAPSHOT_MAGIC: &[u8; 6] = b"HTSNAP";
+pub const SNAPSHOT_VERSION: u16 = 2;
+
+pub type Entry = (KeyType, Value, Option<SystemTime>);
+
+// Key lengths are stored as a single byte
+const _: () = assert!(MAX_KEY_SIZE <= u8::MAX as usize);
+
+/// Snapshots can be requested by multiple workers and the signal thread at once
+static WRITE_LOCK: Mutex<()> = Mutex::new(());
+
+/// Write a snapshot atomically, by replacing `path` with a completely written file
+pub fn write(path: &Path, entries: &[Entry]) -> anyhow::Result<()> {
+    let _guard = WRITE_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+
+    let tmp = tmp_path(path);
+    let file = File::create(&tmp).with_context(|| format!("Creating {tmp:?} failed"))?;
+    let mut writer = BufWriter::new(file);
+
+    writer.write_all(SNAPSHOT_MAGIC)?;
+    writer.write_all(&SNAPSHOT_VERSION.to_le_bytes())?;
+    writer.write_all(&(entries.len() as u64).to_le_bytes())?;
+    for entry in entries {
+        write_entry(&mut writer, entry)?;
+    }
+
+    let file = writer.into_inner()?;
+    file.sync_all()?;
+    fs::rename(&tmp, path).with_context(|| format!("Replacing {path:?} failed"))?;
+    Ok(())
+}
+
+pub fn read(path: &Path) -> anyhow::Result<Vec<Entry>> {
+    let file = File::open(path).with_context(|| format!("Opening {path:?} failed"))?;
+    let mut reader = BufReader::new(file);
+
+    let mut magic = [0; SNAPSHOT_MAGIC.len()];
+    reader.read_exact(&mut magic)?;
+    if &magic != SNAPSHOT_MAGIC {
+        bail!("{path:?} is not a snapshot");
+    }
+    let version = u16::from_le_bytes(read_array(&mut reader)?);
+    if !(1..=SNAPSHOT_VERSION).contains(&version) {
+        bail!("Unsupported snapshot version {version}");
+    }
+
+    let len = u64::from_le_bytes(read_array(&mut reader)?);
+    let mut entries = Vec::new();
+    for _ in 0..len {
+        entries.push(read_entry(&mut reader, version)?);
+    }
+    Ok(entries)
+}
+
+pub fn write_entry(writer: &mut impl Write, (key, value, expires): &Entry) -> anyhow::Result<()> {
+    write_key(writer, key)?;
+    write_value(writer, value)?;
+    write_expiry(writer, *expires)
+}
+
+/// Read an entry in the format of snapshot `version`
+pub fn read_entry(reader: &mut impl Read, version: u16) -> anyhow::Result<Entry> {
+    let key = read_key(reader)?;
+    let value = match version {
+        1 => Value::Int(ValueType::from_le_bytes(read_array(reader)?)),
+        _ => read_value(reader)?,
+    };
+    let expires = read_expiry(reader)?;
+    Ok((key, value, expires))
+}
+
+pub fn write_value(writer: &mut impl Write, value: &Value) -> anyhow::Result<()> {
+    match value {
+        Value::Int(v) => {
+            writer.write_all(&[0])?;
+            writer.write_all(&v.to_le_bytes())?;
+        }
+        Value::Bytes(bytes) => {
+            writer.write_all(&[1])?;
+            writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+            writer.write_all(bytes)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn read_value(reader: &mut impl Read) -> anyhow::Result<Value> {
+    let [tag] = read_array(reader)?;
+    Ok(match tag {
+        0 => Value::Int(ValueType::from_le_bytes(read_array(reader)?)),
+        1 => {
+            let len = u32::from_le_bytes(read_array(reader)?) as usize;
+            if len > MAX_VALUE_SIZE {
+                bail!("Value of length {len} is too long");
+            }
+            let mut bytes = vec![0; len];
+            reader.read_exact(&mut bytes)?;
+            Value::Bytes(bytes.into())
+        }
+        tag => bail!("Invalid value tag {tag}"),
+    })
+}
+
+pub fn write_key(writer: &mut impl Write, key: &KeyType) -> anyhow::Result<()> {
+    writer.write_all(&[key.len() as u8])?;
+    writer.write_all(key.as_bytes())?;
+    Ok(())
+}
+
+pub fn read_key(reader: &mut impl Read) -> anyhow::Result<KeyType> {
+    let [key_len] = read_array(reader)?;
+    let mut key_bytes = vec![0; key_len as usize];
+    reader.read_exact(&mut key_bytes)?;
+    Ok(KeyType::try_from(&key_bytes[..])?)
+}
+
+pub fn write_expiry(writer: &mut impl Write, expires: Option<SystemTime>) -> anyhow::Result<()> {
+    let expires = match expires {
+        // Already expired entries must not turn into ones without expiry
+        Some(t) => t
+            .duration_since(UNIX_EPOCH)
+            .map_or(1, |d| d.as_millis().max(1) as u64),
+        None => 0,
+    };
+    writer.write_all(&expires.to_le_bytes())?;
+    Ok(())
+}
+
+pub fn read_expiry(reader: &mut impl Read) -> anyhow::Result<Option<SystemTime>> {
+    Ok(match u64::from_le_bytes(read_array(reader)?) {
+        0 => None,
+        ms => Some(UNIX_EPOCH + Duration::from_millis(ms)),
+    })
+}
+
+pub fn read_array<const N: usize>(reader: &mut impl Read) -> anyhow::Result<[u8; N]> {
+    let mut buf = [0; N];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+pub fn tmp_path(path: &Path) -> PathBuf {
+    let mut tmp = OsString::from(path);
+    tmp.push(".tmp");
+    tmp.into()
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        env, fs,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
+
+    use shared::KeyType;
+
+    use crate::value::Value;
+
+    use super::{read, write, SNAPSHOT_MAGIC};
+
+    #[test]
+    fn roundtrip() {
+        let path = env::temp_dir().join(format!("hashtable_snapshot_{}", std::process::id()));
+        let expires = UNIX_EPOCH + Duration::from_millis(1_700_000_000_000);
+        let entries = vec![
+            (KeyType::try_from("hello").unwrap(), Value::Int(1), None),
+            (
+                KeyType::try_from("world").unwrap(),
+                Value::Int(2),
+                Some(expires),
+            ),
+            (
+                KeyType::try_from(&[0xff, 0][..]).unwrap(),
+                Value::Bytes([0, 255].into()),
+                None,
+            ),
+            (KeyType::new(), Value::Int(3), Some(UNIX_EPOCH)),
+        ];
+        write(&path, &entries).unwrap();
+        let read = read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(read[..3], entries[..3]);
+        // Expired at the epoch is stored as expired shortly after it
+        assert!(read[3].2.is_some_and(|t| t < SystemTime::now()));
+    }
+
+    #[test]
+    fn read_version_1() {
+        let path = env::temp_dir().join(format!("hashtable_snapshot_v1_{}", std::process::id()));
+        let mut file = SNAPSHOT_MAGIC.to_vec();
+        file.extend_from_slice(&1u16.to_le_bytes());
+        file.extend_from_slice(&1u64.to_le_bytes());
+        file.extend_from_slice(b"\x05hello");
+        file.extend_from_slice(&7u32.to_le_bytes());
+        file.extend_from_slice(&0u64.to_le_bytes());
+        fs::write(&path, file).unwrap();
+        let read = read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            read,
+            [(KeyType::try_from("hello").unwrap(), Value::Int(7), None)]
+        );
+    }
+}
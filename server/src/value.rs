@@ -0,0 +1,39 @@
+//! Values of the entries of the table
+
+use std::sync::Arc;
+
+use shared::ValueType;
+
+use crate::hash_table::HeapSize;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    /// Written by the numeric requests, e.g. `Insert` or `FetchAdd`
+    Int(ValueType),
+    /// Written by `InsertBytes`, shared by all readers until it is overwritten
+    Bytes(Arc<[u8]>),
+}
+
+impl Value {
+    pub fn as_int(&self) -> Option<ValueType> {
+        match self {
+            Self::Int(v) => Some(*v),
+            Self::Bytes(_) => None,
+        }
+    }
+}
+
+impl HeapSize for Value {
+    fn heap_size(&self) -> usize {
+        match self {
+            Self::Int(_) => 0,
+            Self::Bytes(bytes) => bytes.len(),
+        }
+    }
+}
+
+impl From<ValueType> for Value {
+    fn from(v: ValueType) -> Self {
+        Self::Int(v)
+    }
+}
@@ -0,0 +1,358 @@
+//! Append-only write-ahead log of all mutations
+//!
+//! Format, all integers are little endian:
+//! - Header: magic `b"HTWAL\0"`, version (`u16`)
+//! - Per record: tag (`u8`), followed by
+//!   - `Insert` (0): an entry, encoded as in snapshots
+//!   - `Delete` (1): key length (`u8`), key
+//!   - `Touch` (2): key length (`u8`), key, new expiry (encoded as in snapshots)
+//!   - `Replace` (3): key length (`u8`), key, new value (encoded as in snapshots),
+//!     the expiry is kept
+//!
+//! A record which was only partially written before a crash is discarded on startup.
+//! Logs of version 1 store values as version 1 snapshots do (a bare `u32`), they are
+//! rewritten in the current format when they are opened.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, Read, Seek, Write},
+    path::Path,
+    str::FromStr,
+    sync::{Mutex, MutexGuard, PoisonError},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{bail, Context};
+use shared::{KeyType, ValueType};
+
+use crate::{
+    snapshot::{self, Entry},
+    value::Value,
+};
+
+pub const WAL_MAGIC: &[u8; 6] = b"HTWAL\0";
+/// Version 1 stored values as in version 1 of the snapshots
+pub const WAL_VERSION: u16 = 2;
+
+const HEADER_LEN: u64 = (WAL_MAGIC.len() + size_of::<u16>()) as u64;
+
+/// When appended records are flushed to disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Before the response is sent
+    Always,
+    /// Periodically, by a background thread
+    Interval(Duration),
+    /// Whenever the OS decides to
+    Never,
+}
+
+impl FromStr for SyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            ms => ms
+                .parse()
+                .map(|ms| Self::Interval(Duration::from_millis(ms)))
+                .map_err(|_| format!("expected `always`, `never` or milliseconds, got `{ms}`")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Record {
+    Insert(Entry),
+    Delete(KeyType),
+    Touch(KeyType, Option<SystemTime>),
+    Replace(KeyType, Value),
+}
+
+#[derive(Debug)]
+pub struct Wal {
+    log: Mutex<Log>,
+    sync: SyncPolicy,
+}
+
+/// The locked log, mutations have to be applied while holding it,
+/// so they are logged in the same order as they are applied
+#[derive(Debug)]
+pub struct Log {
+    file: File,
+    len: u64,
+    sync: SyncPolicy,
+    /// Records written since the last sync
+    dirty: bool,
+    buf: Vec<u8>,
+}
+
+impl Wal {
+    /// Open (or create) the log at `path`, returns the records it already contains
+    pub fn open(path: &Path, sync: SyncPolicy) -> anyhow::Result<(Self, Vec<Record>)> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)
+            .with_context(|| format!("Opening {path:?} failed"))?;
+
+        let mut records = Vec::new();
+        let len = if file.metadata()?.len() == 0 {
+            file.write_all(WAL_MAGIC)?;
+            file.write_all(&WAL_VERSION.to_le_bytes())?;
+            file.sync_all()?;
+            HEADER_LEN
+        } else {
+            let mut reader = BufReader::new(&file);
+            let magic: [u8; WAL_MAGIC.len()] = snapshot::read_array(&mut reader)?;
+            if &magic != WAL_MAGIC {
+                bail!("{path:?} is not a write-ahead log");
+            }
+            let version = u16::from_le_bytes(snapshot::read_array(&mut reader)?);
+            if !(1..=WAL_VERSION).contains(&version) {
+                bail!("Unsupported write-ahead log version {version}");
+            }
+
+            let mut valid = HEADER_LEN;
+            loop {
+                match read_record(&mut reader, version) {
+                    Ok(record) => records.push(record),
+                    Err(e) if is_eof(&e) => break,
+                    Err(e) => return Err(e.context(format!("{path:?} is corrupted"))),
+                }
+                valid = reader.stream_position()?;
+            }
+            if version == WAL_VERSION {
+                // Drop a partially written last record
+                file.set_len(valid)?;
+                valid
+            } else {
+                // New records must not be appended in a different format
+                file = upgrade(path, &records)?;
+                file.metadata()?.len()
+            }
+        };
+
+        let log = Log {
+            file,
+            len,
+            sync,
+            dirty: false,
+            buf: Vec::new(),
+        };
+        let wal = Self {
+            log: Mutex::new(log),
+            sync,
+        };
+        Ok((wal, records))
+    }
+
+    /// Also succeeds if a mutation panicked while holding the lock, records are appended as a whole
+    pub fn lock(&self) -> MutexGuard<'_, Log> {
+        self.log.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Interval of the background syncs, if the policy requires them
+    pub fn sync_interval(&self) -> Option<Duration> {
+        match self.sync {
+            SyncPolicy::Interval(interval) => Some(interval),
+            _ => None,
+        }
+    }
+}
+
+impl Log {
+    /// Append records with a single write, they are synced before returning if the policy is `Always`
+    pub fn append(&mut self, records: &[Record]) -> anyhow::Result<()> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        self.buf.clear();
+        for record in records {
+            write_record(&mut self.buf, record)?;
+        }
+        if let Err(e) = self.file.write_all(&self.buf) {
+            // Records appended later must not follow a partially written one
+            let _ = self.file.set_len(self.len);
+            return Err(e.into());
+        }
+        self.len += self.buf.len() as u64;
+
+        if self.sync == SyncPolicy::Always {
+            self.file.sync_data()?;
+        } else {
+            self.dirty = true;
+        }
+        Ok(())
+    }
+
+    pub fn sync(&mut self) -> io::Result<()> {
+        if self.dirty {
+            self.file.sync_data()?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Remove all records, once they are contained in a snapshot
+    pub fn truncate(&mut self) -> io::Result<()> {
+        self.file.set_len(HEADER_LEN)?;
+        self.file.sync_all()?;
+        self.len = HEADER_LEN;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Size of the log in bytes
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == HEADER_LEN
+    }
+}
+
+fn write_record(writer: &mut impl Write, record: &Record) -> anyhow::Result<()> {
+    match record {
+        Record::Insert(entry) => {
+            writer.write_all(&[0])?;
+            snapshot::write_entry(writer, entry)
+        }
+        Record::Delete(key) => {
+            writer.write_all(&[1])?;
+            snapshot::write_key(writer, key)
+        }
+        Record::Touch(key, expires) => {
+            writer.write_all(&[2])?;
+            snapshot::write_key(writer, key)?;
+            snapshot::write_expiry(writer, *expires)
+        }
+        Record::Replace(key, value) => {
+            writer.write_all(&[3])?;
+            snapshot::write_key(writer, key)?;
+            snapshot::write_value(writer, value)
+        }
+    }
+}
+
+/// Read a record in the format of log `version`, which stores values as snapshots of the same version
+fn read_record(reader: &mut impl Read, version: u16) -> anyhow::Result<Record> {
+    let [tag] = snapshot::read_array(reader)?;
+    Ok(match tag {
+        0 => Record::Insert(snapshot::read_entry(reader, version)?),
+        1 => Record::Delete(snapshot::read_key(reader)?),
+        2 => Record::Touch(snapshot::read_key(reader)?, snapshot::read_expiry(reader)?),
+        3 => {
+            let key = snapshot::read_key(reader)?;
+            let value = match version {
+                1 => Value::Int(ValueType::from_le_bytes(snapshot::read_array(reader)?)),
+                _ => snapshot::read_value(reader)?,
+            };
+            Record::Replace(key, value)
+        }
+        tag => bail!("Invalid record tag {tag}"),
+    })
+}
+
+/// Replace the log at `path` with `records` in the current format, like snapshots are replaced
+fn upgrade(path: &Path, records: &[Record]) -> anyhow::Result<File> {
+    let mut buf = WAL_MAGIC.to_vec();
+    buf.extend_from_slice(&WAL_VERSION.to_le_bytes());
+    for record in records {
+        write_record(&mut buf, record)?;
+    }
+    let tmp = snapshot::tmp_path(path);
+    let mut file = File::create(&tmp).with_context(|| format!("Creating {tmp:?} failed"))?;
+    file.write_all(&buf)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path).with_context(|| format!("Replacing {path:?} failed"))?;
+    Ok(OpenOptions::new().read(true).append(true).open(path)?)
+}
+
+fn is_eof(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<io::Error>()
+        .is_some_and(|e| e.kind() == io::ErrorKind::UnexpectedEof)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        env,
+        fs::{self, OpenOptions},
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use shared::KeyType;
+
+    use crate::value::Value;
+
+    use super::{Record, SyncPolicy, Wal, WAL_MAGIC, WAL_VERSION};
+
+    #[test]
+    fn append_replay() {
+        let path = env::temp_dir().join(format!("hashtable_wal_{}", std::process::id()));
+        let key = KeyType::try_from("hello").unwrap();
+        let expires = UNIX_EPOCH + Duration::from_millis(1_700_000_000_000);
+        let records = vec![
+            Record::Insert((key, Value::Int(1), None)),
+            Record::Touch(key, Some(expires)),
+            Record::Replace(key, Value::Int(2)),
+            Record::Insert((key, Value::Bytes(b"world".as_slice().into()), None)),
+            Record::Delete(key),
+        ];
+
+        let (wal, replayed) = Wal::open(&path, SyncPolicy::Always).unwrap();
+        assert!(replayed.is_empty());
+        wal.lock().append(&records).unwrap();
+        let len = wal.lock().len();
+        drop(wal);
+
+        // A torn write of the next record
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(len + 3).unwrap();
+
+        let (wal, replayed) = Wal::open(&path, SyncPolicy::Never).unwrap();
+        assert_eq!(replayed, records);
+        assert_eq!(wal.lock().len(), len);
+
+        wal.lock().truncate().unwrap();
+        drop(wal);
+        let (_wal, replayed) = Wal::open(&path, SyncPolicy::Never).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(replayed.is_empty());
+    }
+
+    #[test]
+    fn read_version_1() {
+        let path = env::temp_dir().join(format!("hashtable_wal_v1_{}", std::process::id()));
+        let key = KeyType::try_from("hello").unwrap();
+        let mut file = WAL_MAGIC.to_vec();
+        file.extend_from_slice(&1u16.to_le_bytes());
+        file.extend_from_slice(b"\x00\x05hello");
+        file.extend_from_slice(&7u32.to_le_bytes());
+        file.extend_from_slice(&0u64.to_le_bytes());
+        file.extend_from_slice(b"\x03\x05hello");
+        file.extend_from_slice(&8u32.to_le_bytes());
+        fs::write(&path, file).unwrap();
+        let records = vec![
+            Record::Insert((key, Value::Int(7), None)),
+            Record::Replace(key, Value::Int(8)),
+            Record::Replace(key, Value::Bytes(b"world".as_slice().into())),
+        ];
+
+        let (wal, replayed) = Wal::open(&path, SyncPolicy::Never).unwrap();
+        assert_eq!(replayed, records[..2]);
+        // Appended to the upgraded log
+        wal.lock().append(&records[2..]).unwrap();
+        drop(wal);
+
+        let (_wal, replayed) = Wal::open(&path, SyncPolicy::Never).unwrap();
+        let version = fs::read(&path).unwrap()[WAL_MAGIC.len()..][..2].to_vec();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(version, WAL_VERSION.to_le_bytes());
+        assert_eq!(replayed, records);
+    }
+}
@@ -0,0 +1,182 @@
+//! Keys of the table, arbitrary bytes of a bounded length
+//!
+//! Keys are stored inline (in messages and in the table), so they have a fixed capacity
+//! of [`MAX_KEY_SIZE`] bytes. The server may accept only shorter keys, see `--max-key-size`.
+
+use std::{
+    cmp::Ordering,
+    error::Error,
+    fmt,
+    hash::{Hash, Hasher},
+};
+
+use crate::{ErrorCode, MAX_KEY_SIZE};
+
+const _: () = assert!(MAX_KEY_SIZE <= u16::MAX as usize);
+
+/// Byte string of up to [`MAX_KEY_SIZE`] bytes, which need not be UTF-8
+///
+/// Compared and hashed by its bytes only, the unused capacity is always zeroed
+#[derive(Clone, Copy)]
+pub struct Key {
+    len: u16,
+    bytes: [u8; MAX_KEY_SIZE],
+}
+
+/// A key exceeds the maximum length
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyTooLong {
+    pub len: usize,
+    pub max: usize,
+}
+
+impl KeyTooLong {
+    pub fn code(&self) -> ErrorCode {
+        ErrorCode::InvalidKey
+    }
+}
+
+impl fmt::Display for KeyTooLong {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "key of {} bytes exceeds the maximum of {} bytes",
+            self.len, self.max
+        )
+    }
+}
+
+impl Error for KeyTooLong {}
+
+impl Key {
+    /// The empty key
+    pub const fn new() -> Self {
+        Self {
+            len: 0,
+            bytes: [0; MAX_KEY_SIZE],
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Default for Key {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TryFrom<&[u8]> for Key {
+    type Error = KeyTooLong;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, KeyTooLong> {
+        if bytes.len() > MAX_KEY_SIZE {
+            return Err(KeyTooLong {
+                len: bytes.len(),
+                max: MAX_KEY_SIZE,
+            });
+        }
+        let mut key = Self::new();
+        key.bytes[..bytes.len()].copy_from_slice(bytes);
+        key.len = bytes.len() as u16;
+        Ok(key)
+    }
+}
+
+impl TryFrom<&str> for Key {
+    type Error = KeyTooLong;
+
+    fn try_from(s: &str) -> Result<Self, KeyTooLong> {
+        Self::try_from(s.as_bytes())
+    }
+}
+
+impl AsRef<[u8]> for Key {
+    fn as_ref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_bytes() == other.as_bytes()
+    }
+}
+
+impl Eq for Key {}
+
+impl PartialOrd for Key {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Key {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.as_bytes().cmp(other.as_bytes())
+    }
+}
+
+impl Hash for Key {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_bytes().hash(state);
+    }
+}
+
+/// UTF-8 keys are shown as strings, others with their bytes escaped
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match std::str::from_utf8(self.as_bytes()) {
+            Ok(s) => f.write_str(s),
+            Err(_) => write!(f, "{}", self.as_bytes().escape_ascii()),
+        }
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match std::str::from_utf8(self.as_bytes()) {
+            Ok(s) => write!(f, "{s:?}"),
+            Err(_) => write!(f, "b\"{}\"", self.as_bytes().escape_ascii()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::MAX_KEY_SIZE;
+
+    use super::{Key, KeyTooLong};
+
+    #[test]
+    fn binary_keys() {
+        let binary = Key::try_from(&[0xff, 0, b'a'][..]).unwrap();
+        assert_eq!(binary.as_bytes(), [0xff, 0, b'a']);
+        assert_eq!(format!("{binary:?}"), r#"b"\xff\x00a""#);
+        assert_eq!(format!("{:?}", Key::try_from("é").unwrap()), r#""é""#);
+
+        // Only the used bytes count, a trailing zero makes a different key
+        assert_ne!(Key::try_from(&[0][..]).unwrap(), Key::new());
+        assert!(Key::new() < Key::try_from(&[0][..]).unwrap());
+
+        let longest = [1; MAX_KEY_SIZE];
+        assert_eq!(Key::try_from(&longest[..]).unwrap().len(), MAX_KEY_SIZE);
+        assert_eq!(
+            Key::try_from(&[1; MAX_KEY_SIZE + 1][..]),
+            Err(KeyTooLong {
+                len: MAX_KEY_SIZE + 1,
+                max: MAX_KEY_SIZE
+            })
+        );
+    }
+}
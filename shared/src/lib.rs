@@ -1,91 +1,354 @@
-use std::{mem::MaybeUninit, ptr, sync::atomic::AtomicUsize};
+use std::{
+    fmt, ptr,
+    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::bail;
 use arrayvec::ArrayString;
-use libc::c_int;
-use sync::{Mutex, RwLock, Semaphore};
+use key::Key;
+use libc::{c_int, getpid, pid_t};
+use ring::Ring;
+use slab::{Blob, Slab, FREE};
+use sync::{Event, Mutex, MutexGuard, Semaphore};
+use wire::{EncodedRequest, EncodedResponse};
 
-use shm::{HeapArrayInit, ShmSafe};
+use shm::{layout_hash, Protocol, ShmSafe};
 
+pub mod key;
+pub mod ring;
 pub mod shm;
+pub mod slab;
 pub mod sync;
+pub mod wire;
 
 pub const MAGIC_VALUE: u32 = 0x77256810;
 pub const DESCRIPTOR: &str = "/hashtable";
 
+/// Version of the protocol between server and clients, see [`Protocol`]
+pub const PROTOCOL_VERSION: u32 = 4;
+/// Oldest protocol version this build can talk to. Only exact matches are supported so far, since every
+/// earlier version used another layout or encoding: version 1 placed Rust values in the queues,
+/// version 2 had no [`Slab`], version 3 only had UTF-8 keys of at most 64 bytes.
+/// Lower this once a version keeps the layout and only adds features
+pub const MIN_PROTOCOL_VERSION: u32 = PROTOCOL_VERSION;
+
 pub const REQ_BUFFER_SIZE: usize = 2048;
-pub const RES_BUFFER_SIZE: usize = 2048;
 
-pub type KeyType = ArrayString<64>;
+/// Maximum number of request queues, see [`ShardTable`]
+pub const MAX_SHARDS: usize = 16;
+
+/// Maximum number of clients connected at once, every client has its own mailbox
+pub const MAX_CLIENTS: usize = 64;
+/// Number of responses a mailbox can hold
+pub const MAILBOX_SIZE: usize = 64;
+
+/// How often clients renew their registration, see [`MailboxQueue::heartbeat`]
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+/// Clients that did not renew their registration for this long are reaped by the server
+pub const CLIENT_LEASE: Duration = Duration::from_secs(10);
+/// Clients consider the server gone once it did not signal for this long, it signals
+/// every [`HEARTBEAT_INTERVAL`]
+pub const SERVER_LEASE: Duration = Duration::from_secs(5);
+
+/// Maximum number of entries per bucket content response
+pub const BUCKET_CHUNK_SIZE: usize = 32;
+
+/// Maximum number of operations per batch
+pub const BATCH_SIZE: usize = 8;
+
+/// Maximum number of operations per transaction
+pub const TRANSACTION_SIZE: usize = 8;
+
+/// Capacity of a key in bytes, the server may configure a lower limit
+pub const MAX_KEY_SIZE: usize = 128;
+/// Maximum length of the detail of an error response in bytes
+pub const ERROR_DETAIL_SIZE: usize = 128;
+
+/// Size of the blocks values are stored in, see [`Slab`]
+pub const SLAB_BLOCK_SIZE: usize = 256;
+/// Number of blocks in the slab
+pub const SLAB_BLOCKS: usize = 16384;
+/// Maximum length of a value stored as bytes, the server may configure a lower limit
+pub const MAX_VALUE_SIZE: usize = 64 * 1024;
+
+pub type KeyType = Key;
 pub type ValueType = u32;
+/// Human readable part of an error response
+pub type ErrorDetail = ArrayString<ERROR_DETAIL_SIZE>;
 
 #[repr(C)]
 #[derive(Debug)]
 pub struct HashtableMemory {
-    pub request_frame: RequestFrame,
-    pub response_frame: ResponseFrame,
+    /// Implementation of the request queues, chosen by the server
+    pub queue_kind: QueueKind,
+    pub shards: ShardTable,
+    /// Only the first [`ShardTable::num_shards`] queues are served
+    pub request_frames: [RequestFrame; MAX_SHARDS],
+    pub mailboxes: [Mailbox; MAX_CLIENTS],
+    /// Values of `InsertBytes` requests and `Bytes` responses
+    pub slab: Slab,
+    /// Id of the next client to join, see [`HashtableMemory::allocate_client_id`]
+    next_client_id: AtomicU32,
 }
 
 unsafe impl ShmSafe for HashtableMemory {}
 
+impl Protocol for HashtableMemory {
+    const VERSION: u32 = PROTOCOL_VERSION;
+    const MIN_VERSION: u32 = MIN_PROTOCOL_VERSION;
+    // Covers the constants making up requests and responses, such as the capacity of keys,
+    // the encoding itself is covered by the version
+    const LAYOUT_HASH: u64 = layout_hash(&[
+        (size_of::<HashtableMemory>(), align_of::<HashtableMemory>()),
+        (size_of::<EncodedRequest>(), align_of::<EncodedRequest>()),
+        (size_of::<EncodedResponse>(), align_of::<EncodedResponse>()),
+        (MAX_KEY_SIZE, 1),
+        (ERROR_DETAIL_SIZE, 1),
+        (BATCH_SIZE, 1),
+        (TRANSACTION_SIZE, 1),
+        (BUCKET_CHUNK_SIZE, 1),
+        (SLAB_BLOCK_SIZE, 1),
+        (SLAB_BLOCKS, 1),
+        (MAX_VALUE_SIZE, 1),
+    ]);
+}
+
+/// Optional parts of the protocol, as a bitmap
+///
+/// The server advertises the features it supports in the header of the region, clients only send
+/// requests both sides support. Bits unknown to a build are never set in its intersections
+#[repr(transparent)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Features(u64);
+
+impl Features {
+    pub const NONE: Self = Self(0);
+    /// Entries with a time-to-live, `InsertWithTtl` and `Touch`
+    pub const TTL: Self = Self(1 << 0);
+    /// `InsertIfAbsent`, `ReplaceIfPresent` and `CompareAndSwap`
+    pub const CONDITIONAL: Self = Self(1 << 1);
+    /// `FetchAdd`, `FetchSub`, `FetchMin` and `FetchMax`
+    pub const FETCH: Self = Self(1 << 2);
+    pub const BATCH: Self = Self(1 << 3);
+    pub const TRANSACTION: Self = Self(1 << 4);
+    /// The server has a snapshot path configured
+    pub const SNAPSHOT: Self = Self(1 << 5);
+    /// Values of variable length, `InsertBytes`
+    pub const BYTES: Self = Self(1 << 6);
+    /// All features known to this build
+    pub const ALL: Self = Self((1 << 7) - 1);
+
+    pub const fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub const fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    pub const fn difference(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+}
+
+impl fmt::Display for Features {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = [
+            (Self::TTL, "ttl"),
+            (Self::CONDITIONAL, "conditional"),
+            (Self::FETCH, "fetch"),
+            (Self::BATCH, "batch"),
+            (Self::TRANSACTION, "transaction"),
+            (Self::SNAPSHOT, "snapshot"),
+            (Self::BYTES, "bytes"),
+        ];
+        let mut names = names.iter().filter(|(feature, _)| self.contains(*feature));
+        match names.next() {
+            Some((_, name)) => write!(f, "{name}")?,
+            None => write!(f, "none")?,
+        }
+        for (_, name) in names {
+            write!(f, ", {name}")?;
+        }
+        Ok(())
+    }
+}
+
 impl HashtableMemory {
     /// Use a custom, unsafe initializer. This is required because
     /// the ring buffers (arrays) can overflow the stack on construction
     /// (before being able to move them to shared memory)
-    pub unsafe fn init_in_shm(shm: *mut HashtableMemory, num_writers: usize) {
-        // Initialize Request Frame
+    ///
+    /// # Safety
+    /// `shm` must point to writable, uninitialized shared memory
+    pub unsafe fn init_in_shm(
+        shm: *mut HashtableMemory,
+        num_shards: usize,
+        queue_kind: QueueKind,
+        max_value_size: usize,
+    ) {
+        assert!((1..=MAX_SHARDS).contains(&num_shards));
+
+        ptr::write(&raw mut (*shm).queue_kind, queue_kind);
+
+        // Initialize Shard Table
         {
-            let count = &raw mut (*shm).request_frame.count;
-            let space = &raw mut (*shm).request_frame.space;
-            let queue = &raw mut (*shm).request_frame.queue;
+            let shards = &raw mut (*shm).shards;
+            ptr::write(shards, ShardTable::new(num_shards));
+        }
+
+        // Initialize Request Frames, one at a time to keep them off the stack
+        {
+            let frames = (&raw mut (*shm).request_frames).cast::<RequestFrame>();
+            for index in 0..MAX_SHARDS {
+                let count = &raw mut (*frames.add(index)).count;
+                let space = &raw mut (*frames.add(index)).space;
+                let queue = &raw mut (*frames.add(index)).queue;
 
-            ptr::write(count, Semaphore::new(0));
-            ptr::write(space, Semaphore::new(REQ_BUFFER_SIZE as u32));
-            Mutex::init_at(queue, |queue_inner| {
-                let write = &raw mut (*queue_inner).write;
-                let read = &raw mut (*queue_inner).read;
-                let buffer = &raw mut (*queue_inner).buffer;
+                ptr::write(count, Semaphore::new(0));
+                ptr::write(space, Semaphore::new(REQ_BUFFER_SIZE as u32));
+                Mutex::init_at(queue, |queue_inner| {
+                    let write = &raw mut (*queue_inner).write;
+                    let read = &raw mut (*queue_inner).read;
+                    let posted = &raw mut (*queue_inner).posted;
 
-                ptr::write(write, 0);
-                ptr::write(read, 0);
+                    ptr::write(write, 0);
+                    ptr::write(read, 0);
+                    ptr::write(posted, 0);
+                    // The buffer consists of bytes, which are valid in any state
+                });
 
-                // The relevant part: initialize the array on the heap
-                // and move it to shared memory
-                let init_buffer = HeapArrayInit::from_fn(|_| MaybeUninit::uninit());
-                init_buffer.move_to(buffer);
-            });
+                // Initializing a ring touches all of its memory, so only do so if it is used
+                if queue_kind == QueueKind::LockFree && index < num_shards {
+                    Ring::init_at(&raw mut (*frames.add(index)).ring);
+                }
+            }
         }
 
-        // Initialize Response Frame
+        // Initialize Mailboxes, one at a time to keep them off the stack
         {
-            let buffer = &raw mut (*shm).response_frame.buffer;
-            let space = &raw mut (*shm).response_frame.space;
-            let num_tx = &raw mut (*shm).response_frame.num_tx;
-            let tail = &raw mut (*shm).response_frame.tail;
+            let mailboxes = (&raw mut (*shm).mailboxes).cast::<Mailbox>();
+            for index in 0..MAX_CLIENTS {
+                let queue = &raw mut (*mailboxes.add(index)).queue;
+                ptr::write(&raw mut (*mailboxes.add(index)).ready, Event::new());
+                ptr::write(&raw mut (*mailboxes.add(index)).space, Event::new());
+                Mutex::init_at(queue, |queue_inner| {
+                    let owner = &raw mut (*queue_inner).owner;
+                    let write = &raw mut (*queue_inner).write;
+                    let read = &raw mut (*queue_inner).read;
+
+                    ptr::write(owner, None);
+                    ptr::write(&raw mut (*queue_inner).pid, 0);
+                    ptr::write(&raw mut (*queue_inner).heartbeat, 0);
+                    ptr::write(&raw mut (*queue_inner).shard, 0);
+                    ptr::write(write, 0);
+                    ptr::write(read, 0);
+                    // The buffer consists of bytes, which are valid in any state
+                });
+            }
+        }
+
+        Slab::init_at(&raw mut (*shm).slab, max_value_size);
+        ptr::write(&raw mut (*shm).next_client_id, AtomicU32::new(0));
+    }
 
-            let init_buffer = HeapArrayInit::from_fn(|index| {
-                RwLock::new(ResponseSlot {
-                    rem: AtomicUsize::new(0),
-                    pos: (index as u64).wrapping_sub(RES_BUFFER_SIZE as u64),
-                    val: MaybeUninit::uninit(),
-                })
-            });
+    /// Id for a client joining this region, distinct from the ids of all other clients
+    /// as long as fewer than [`FREE`] clients joined
+    ///
+    /// The id owns the values of the client in the [`Slab`], so it must not be shared
+    pub fn allocate_client_id(&self) -> u32 {
+        // The largest ids denote the server and free blocks in the slab
+        self.next_client_id.fetch_add(1, Ordering::Relaxed) % FREE
+    }
+}
 
-            init_buffer.move_to(buffer);
+/// Assignment of clients to request queues
+///
+/// Every client sends its requests to one shard, which is picked and rebalanced by
+/// the number of clients per shard. Every worker of the server has a home shard,
+/// idle workers also serve the other shards.
+#[repr(C)]
+#[derive(Debug)]
+pub struct ShardTable {
+    /// Number of request queues served, fixed by the server on startup
+    pub num_shards: usize,
+    /// Number of clients sending to each shard
+    pub clients: [AtomicUsize; MAX_SHARDS],
+}
 
-            ptr::write(space, Semaphore::new(RES_BUFFER_SIZE as u32));
-            ptr::write(num_tx, num_writers);
-            ptr::write(tail, Mutex::new(ResponseTail { pos: 0, rx_cnt: 0 }));
+impl ShardTable {
+    pub fn new(num_shards: usize) -> Self {
+        Self {
+            num_shards,
+            clients: [const { AtomicUsize::new(0) }; MAX_SHARDS],
         }
     }
+
+    /// The shard with the fewest clients
+    pub fn least_contended(&self) -> usize {
+        (0..self.num_shards)
+            .min_by_key(|&shard| self.clients[shard].load(Ordering::Relaxed))
+            .unwrap_or(0)
+    }
+
+    /// Register a client with the least contended shard, returns the shard
+    pub fn join(&self) -> usize {
+        let shard = self.least_contended();
+        self.clients[shard].fetch_add(1, Ordering::Relaxed);
+        shard
+    }
+
+    pub fn leave(&self, shard: usize) {
+        self.clients[shard].fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Move a client of `shard` to the least contended shard, if that has
+    /// at least two clients less, returns the new shard of the client
+    pub fn rebalance(&self, shard: usize) -> usize {
+        let target = self.least_contended();
+        let load = |shard: usize| self.clients[shard].load(Ordering::Relaxed);
+        if load(target) + 1 < load(shard) {
+            self.clients[target].fetch_add(1, Ordering::Relaxed);
+            self.leave(shard);
+            target
+        } else {
+            shard
+        }
+    }
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueKind {
+    /// [`RequestFrame::queue`], guarded by a mutex and counted by semaphores
+    Mutex,
+    /// [`RequestFrame::ring`], semaphores are only used to wait while it is empty or full
+    LockFree,
 }
 
 #[repr(C)]
 #[derive(Debug)]
 pub struct RequestFrame {
     pub count: Semaphore,
+    /// Wakes clients waiting for a full queue, taken and posted while holding the lock of `queue`.
+    /// Clients check for room in the queue itself, so a leaked permit only delays a wake-up
     pub space: Semaphore,
     pub queue: Mutex<RequestQueue>,
+    /// Only initialized with [`QueueKind::LockFree`]
+    pub ring: Ring<EncodedRequest, REQ_BUFFER_SIZE>,
 }
 
 #[repr(C)]
@@ -93,51 +356,390 @@ pub struct RequestFrame {
 pub struct RequestQueue {
     pub write: usize,
     pub read: usize,
-    pub buffer: [MaybeUninit<RequestData>; REQ_BUFFER_SIZE],
+    /// Value of `write` up to which `count` was posted
+    pub posted: usize,
+    pub buffer: [EncodedRequest; REQ_BUFFER_SIZE],
 }
 
-#[repr(C)]
+impl RequestFrame {
+    /// Lock the queue, and repair it if a client died while holding the lock
+    ///
+    /// Items are only published by advancing `write` once they are fully written,
+    /// so a half-written slot is simply overwritten by the next client.
+    /// Items whose client died before posting `count` are posted here.
+    /// `space` needs no repair, clients take its permits together with the slot, see [`RequestFrame::space`]
+    pub fn lock_queue(&self) -> MutexGuard<'_, RequestQueue> {
+        self.queue.lock().unwrap_or_else(|died| {
+            let mut queue = died.into_inner();
+            while queue.posted != queue.write {
+                self.count.post();
+                queue.posted = queue.posted.wrapping_add(1);
+            }
+            queue
+        })
+    }
+}
+
+/// Request as seen by the client and the server, it is encoded in shared memory, see [`wire`]
 #[derive(Debug, Copy, Clone)]
 pub struct RequestData {
     pub client_id: u32,
+    /// Mailbox the client registered, see [`Mailbox`]
+    pub mailbox: u32,
     pub request_id: u32,
     pub payload: RequestPayload,
 }
 
-#[repr(C, u8)]
+// Requests are copied in and out of shared memory, and are never allocated
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Copy, Clone)]
 pub enum RequestPayload {
     Insert(KeyType, ValueType),
+    /// Insert an entry that expires after the given time
+    InsertWithTtl(KeyType, ValueType, Duration),
+    /// Set (or clear, with `None`) the time-to-live of an existing entry
+    Touch(KeyType, Option<Duration>),
+    /// Insert an entry only if the key is not present
+    InsertIfAbsent(KeyType, ValueType),
+    /// Overwrite the value of an entry only if the key is present
+    ReplaceIfPresent(KeyType, ValueType),
+    /// Overwrite the value of an entry only if it currently is `expected`
+    CompareAndSwap {
+        key: KeyType,
+        expected: ValueType,
+        new: ValueType,
+    },
+    /// Add to the value, missing entries start at 0. Responds with the previous value
+    FetchAdd(KeyType, ValueType, Overflow),
+    /// Subtract from the value, missing entries start at 0. Responds with the previous value
+    FetchSub(KeyType, ValueType, Overflow),
+    /// Store the minimum of the value and the given one, missing entries start at `ValueType::MAX`.
+    /// Responds with the previous value
+    FetchMin(KeyType, ValueType),
+    /// Store the maximum of the value and the given one, missing entries start at 0.
+    /// Responds with the previous value
+    FetchMax(KeyType, ValueType),
+    /// Insert (or overwrite) an entry holding the bytes of a value in the [`Slab`],
+    /// which the server takes out of it
+    InsertBytes(KeyType, Blob),
+    /// Execute the first `len` operations one after another, with a single response
+    Batch {
+        len: usize,
+        ops: [BatchOp; BATCH_SIZE],
+    },
+    /// Execute the first `len` operations atomically, or none of them
+    Transaction {
+        len: usize,
+        ops: [TransactionOp; TRANSACTION_SIZE],
+    },
+    Get(KeyType),
     ReadBucket(KeyType),
     PrintHashmap,
+    /// Write a snapshot of the table to the snapshot path of the server
+    Snapshot,
     Delete(KeyType),
 }
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct ResponseFrame {
-    pub buffer: [RwLock<ResponseSlot>; RES_BUFFER_SIZE],
-    pub space: Semaphore,
-    pub num_tx: usize,
-    pub tail: Mutex<ResponseTail>,
+/// Operation of a batch, executed like the request of the same name
+#[derive(Debug, Copy, Clone)]
+pub enum BatchOp {
+    Insert(KeyType, ValueType),
+    InsertWithTtl(KeyType, ValueType, Duration),
+    Get(KeyType),
+    Delete(KeyType),
+    FetchAdd(KeyType, ValueType, Overflow),
+    InsertBytes(KeyType, Blob),
+}
+
+impl RequestPayload {
+    /// Features the server has to support to process the request
+    pub fn features(&self) -> Features {
+        match self {
+            Self::InsertWithTtl(..) | Self::Touch(..) => Features::TTL,
+            Self::InsertIfAbsent(..) | Self::ReplaceIfPresent(..) | Self::CompareAndSwap { .. } => {
+                Features::CONDITIONAL
+            }
+            Self::FetchAdd(..) | Self::FetchSub(..) | Self::FetchMin(..) | Self::FetchMax(..) => {
+                Features::FETCH
+            }
+            Self::Batch { len, ops } => ops[..(*len).min(BATCH_SIZE)]
+                .iter()
+                .map(|&op| Self::from(op).features())
+                .fold(Features::BATCH, Features::union),
+            Self::Transaction { .. } => Features::TRANSACTION,
+            Self::Snapshot => Features::SNAPSHOT,
+            Self::InsertBytes(..) => Features::BYTES,
+            Self::Insert(..)
+            | Self::Get(_)
+            | Self::ReadBucket(_)
+            | Self::PrintHashmap
+            | Self::Delete(_) => Features::NONE,
+        }
+    }
+
+    /// Keys the request operates on, the operations of a batch are requests of their own
+    pub fn keys(&self) -> impl Iterator<Item = KeyType> + '_ {
+        let single = match *self {
+            Self::Insert(k, _)
+            | Self::InsertWithTtl(k, ..)
+            | Self::Touch(k, _)
+            | Self::InsertIfAbsent(k, _)
+            | Self::ReplaceIfPresent(k, _)
+            | Self::CompareAndSwap { key: k, .. }
+            | Self::FetchAdd(k, ..)
+            | Self::FetchSub(k, ..)
+            | Self::FetchMin(k, _)
+            | Self::FetchMax(k, _)
+            | Self::InsertBytes(k, _)
+            | Self::Get(k)
+            | Self::ReadBucket(k)
+            | Self::Delete(k) => Some(k),
+            Self::Batch { .. } | Self::Transaction { .. } | Self::PrintHashmap | Self::Snapshot => {
+                None
+            }
+        };
+        let ops = match self {
+            Self::Transaction { len, ops } => &ops[..(*len).min(TRANSACTION_SIZE)],
+            _ => &[],
+        };
+        single.into_iter().chain(ops.iter().map(TransactionOp::key))
+    }
+
+    /// Number of operations of a batch or transaction, and how many it can hold at most
+    pub fn ops_len(&self) -> Option<(usize, usize)> {
+        match self {
+            Self::Batch { len, .. } => Some((*len, BATCH_SIZE)),
+            Self::Transaction { len, .. } => Some((*len, TRANSACTION_SIZE)),
+            _ => None,
+        }
+    }
+
+    /// Values in the [`Slab`] the request refers to
+    pub fn blobs(&self) -> impl Iterator<Item = Blob> + '_ {
+        let ops = match self {
+            Self::Batch { len, ops } => &ops[..(*len).min(BATCH_SIZE)],
+            _ => &[],
+        };
+        let single = match self {
+            Self::InsertBytes(_, blob) => Some(*blob),
+            _ => None,
+        };
+        single
+            .into_iter()
+            .chain(ops.iter().filter_map(|op| match op {
+                BatchOp::InsertBytes(_, blob) => Some(*blob),
+                _ => None,
+            }))
+    }
+}
+
+impl From<BatchOp> for RequestPayload {
+    fn from(op: BatchOp) -> Self {
+        match op {
+            BatchOp::Insert(k, v) => Self::Insert(k, v),
+            BatchOp::InsertWithTtl(k, v, ttl) => Self::InsertWithTtl(k, v, ttl),
+            BatchOp::Get(k) => Self::Get(k),
+            BatchOp::Delete(k) => Self::Delete(k),
+            BatchOp::FetchAdd(k, v, overflow) => Self::FetchAdd(k, v, overflow),
+            BatchOp::InsertBytes(k, blob) => Self::InsertBytes(k, blob),
+        }
+    }
+}
+
+/// Result of a batch operation, same as the response of the same name
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BatchResult {
+    Inserted,
+    InsertedEvicted(KeyType),
+    Value(ValueType),
+    Deleted,
+    Fetched(ValueType),
+    NotFound,
+    Bytes(Blob),
+    /// The operation failed, the detail is only part of single responses
+    Error(ErrorCode),
+}
+
+impl TryFrom<ResponsePayload> for BatchResult {
+    type Error = ResponsePayload;
+
+    fn try_from(payload: ResponsePayload) -> Result<Self, ResponsePayload> {
+        Ok(match payload {
+            ResponsePayload::Inserted => Self::Inserted,
+            ResponsePayload::InsertedEvicted(k) => Self::InsertedEvicted(k),
+            ResponsePayload::Value(v) => Self::Value(v),
+            ResponsePayload::Deleted => Self::Deleted,
+            ResponsePayload::Fetched(v) => Self::Fetched(v),
+            ResponsePayload::NotFound => Self::NotFound,
+            ResponsePayload::Bytes(blob) => Self::Bytes(blob),
+            ResponsePayload::Error { code, .. } => Self::Error(code),
+            other => return Err(other),
+        })
+    }
+}
+
+/// Operation of a transaction, all of them result in the value of their key before the operation
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TransactionOp {
+    Get(KeyType),
+    /// Insert (or overwrite) an entry, without expiry
+    Insert(KeyType, ValueType),
+    Delete(KeyType),
+    /// Abort unless the entry has the given value (or does not exist, for `None`)
+    Check(KeyType, Option<ValueType>),
+    /// Add to the value (missing entries start at 0), abort if the result does not fit.
+    /// Existing entries keep their expiry
+    Add(KeyType, i64),
+}
+
+impl TransactionOp {
+    pub fn key(&self) -> KeyType {
+        match *self {
+            Self::Get(k) | Self::Insert(k, _) | Self::Delete(k) | Self::Check(k, _) => k,
+            Self::Add(k, _) => k,
+        }
+    }
+}
+
+/// Why a transaction did not apply any of its operations
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AbortReason {
+    /// A `Check` failed, with the actual value
+    CheckFailed(Option<ValueType>),
+    /// The result of an `Add` would not fit into the value
+    Overflow,
+    /// More than `TRANSACTION_SIZE` operations
+    TooLarge,
+    /// An operation hit an entry holding bytes, transactions only operate on numbers
+    WrongType,
+}
+
+/// Kind of failure of a request, see [`ResponsePayload::Error`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The key of the request exceeds the maximum key size of the server
+    InvalidKey,
+    /// Another parameter of the request is out of range
+    InvalidArgument,
+    /// The table has no room for the entry
+    TableFull,
+    /// The server does not support the request, or is not configured for it
+    Unsupported,
+    /// The server failed while processing the request, see the detail
+    Internal,
+    /// The request could not be completed in time
+    Timeout,
+    /// The request, or its response, could not be decoded, see [`wire::DecodeError`]
+    Malformed,
+    /// A numeric operation hit an entry holding bytes, or the other way round
+    WrongType,
+    /// There is no space left for a value in the [`Slab`]
+    SlabFull,
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::InvalidKey => "invalid key",
+            Self::InvalidArgument => "invalid argument",
+            Self::TableFull => "table full",
+            Self::Unsupported => "unsupported request",
+            Self::Internal => "internal error",
+            Self::Timeout => "timed out",
+            Self::Malformed => "malformed message",
+            Self::WrongType => "wrong value type",
+            Self::SlabFull => "value slab full",
+        })
+    }
 }
 
+/// Behavior of `FetchAdd` and `FetchSub` if the result does not fit into the value
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Overflow {
+    Saturating,
+    Wrapping,
+}
+
+/// Responses for a single client, written by the workers and read by the client that registered it
+///
+/// The mailboxes are also the registry of connected clients, which the server uses
+/// to reap clients that died without unregistering
 #[repr(C)]
 #[derive(Debug)]
-pub struct ResponseTail {
-    pub pos: u64,
-    pub rx_cnt: usize,
+pub struct Mailbox {
+    pub queue: Mutex<MailboxQueue>,
+    /// Notified by workers after writing a response
+    pub ready: Event,
+    /// Notified by the client after taking responses out, and when it leaves
+    pub space: Event,
 }
 
 #[repr(C)]
 #[derive(Debug)]
-pub struct ResponseSlot {
-    pub rem: AtomicUsize,
-    pub pos: u64,
-    pub val: MaybeUninit<ResponseData>,
+pub struct MailboxQueue {
+    /// Id of the client the mailbox is registered to, `None` if it is free
+    pub owner: Option<u32>,
+    /// Process of the client
+    pub pid: pid_t,
+    /// Last time the client renewed its registration, in milliseconds since the Unix epoch
+    pub heartbeat: u64,
+    /// Shard the client sends its requests to, see [`ShardTable`]
+    pub shard: usize,
+    pub write: usize,
+    pub read: usize,
+    pub buffer: [EncodedResponse; MAILBOX_SIZE],
 }
 
-#[repr(C)]
+impl MailboxQueue {
+    pub fn is_full(&self) -> bool {
+        self.write.wrapping_sub(self.read) == MAILBOX_SIZE
+    }
+
+    /// The mailbox must not be full
+    pub fn push(&mut self, item: &ResponseData) {
+        debug_assert!(!self.is_full());
+        item.encode_into(&mut self.buffer[self.write & (MAILBOX_SIZE - 1)]);
+        self.write = self.write.wrapping_add(1);
+    }
+
+    /// Register the mailbox for a client of this process, and assign it a shard
+    pub fn register(&mut self, client_id: u32, shards: &ShardTable) {
+        self.owner = Some(client_id);
+        self.pid = unsafe { getpid() };
+        self.heartbeat = unix_millis();
+        self.shard = shards.join();
+        self.read = self.write;
+    }
+
+    /// Free the mailbox, dropping all unread responses
+    ///
+    /// Also frees the values of the client in the slab, including those of unread
+    /// responses and of requests the server did not take yet
+    pub fn unregister(&mut self, shards: &ShardTable, slab: &Slab) {
+        if let Some(client_id) = self.owner.take() {
+            shards.leave(self.shard);
+            slab.release(client_id);
+        }
+        self.read = self.write;
+    }
+
+    /// Whether the lease of the client expired
+    pub fn is_expired(&self) -> bool {
+        unix_millis().saturating_sub(self.heartbeat) > CLIENT_LEASE.as_millis() as u64
+    }
+
+    /// Take the oldest response, it is decoded by the client after releasing the lock
+    pub fn pop(&mut self) -> Option<EncodedResponse> {
+        if self.read == self.write {
+            return None;
+        }
+        let item = self.buffer[self.read & (MAILBOX_SIZE - 1)];
+        self.read = self.read.wrapping_add(1);
+        Some(item)
+    }
+}
+
+/// Response as seen by the client and the server, it is encoded in shared memory, see [`wire`]
 #[derive(Debug, Copy, Clone)]
 pub struct ResponseData {
     pub client_id: u32,
@@ -145,18 +747,102 @@ pub struct ResponseData {
     pub payload: ResponsePayload,
 }
 
-#[repr(C, u8)]
+// Responses are copied in and out of shared memory, and are never allocated
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Copy, Clone)]
 pub enum ResponsePayload {
     Inserted,
+    /// Inserted, but the table was full and the entry with the given key was evicted
+    /// (the first one, if a large value required evicting several)
+    InsertedEvicted(KeyType),
+    Value(ValueType),
+    /// Value of an entry holding bytes, in the [`Slab`]. It belongs to the client,
+    /// which has to take it out of the slab
+    Bytes(Blob),
+    /// Part of a bucket, further responses with the same `request_id` follow if `more` is set
+    ///
+    /// Only lists entries holding numbers, entries holding bytes are read with `Get`
     BucketContent {
         len: usize,
-        data: [(KeyType, ValueType); 32],
+        more: bool,
+        data: [(KeyType, ValueType); BUCKET_CHUNK_SIZE],
     },
     Deleted,
+    Touched,
+    /// Outcome of a conditional operation, with the value of the entry after it
+    Conditional {
+        success: bool,
+        current: Option<ValueType>,
+    },
+    /// Value before a `Fetch*` operation
+    Fetched(ValueType),
+    /// Results of the first `len` operations of a batch
+    Batch {
+        len: usize,
+        results: [BatchResult; BATCH_SIZE],
+    },
+    /// Results of the first `len` operations of a transaction, which was applied
+    Committed {
+        len: usize,
+        results: [Option<ValueType>; TRANSACTION_SIZE],
+    },
+    /// The operation at `index` aborted the transaction, which was not applied
+    Aborted {
+        index: usize,
+        reason: AbortReason,
+    },
     NotFound,
     Printed,
-    Overflow,
+    Snapshotted {
+        entries: u64,
+    },
+    /// The request failed, and had no effect unless the detail says otherwise
+    Error {
+        code: ErrorCode,
+        detail: ErrorDetail,
+    },
+}
+
+impl ResponsePayload {
+    /// Values in the [`Slab`] the response refers to
+    pub fn blobs(&self) -> impl Iterator<Item = Blob> + '_ {
+        let results = match self {
+            Self::Batch { len, results } => &results[..(*len).min(BATCH_SIZE)],
+            _ => &[],
+        };
+        let single = match self {
+            Self::Bytes(blob) => Some(*blob),
+            _ => None,
+        };
+        single
+            .into_iter()
+            .chain(results.iter().filter_map(|result| match result {
+                BatchResult::Bytes(blob) => Some(*blob),
+                _ => None,
+            }))
+    }
+
+    /// Error response, `detail` is truncated to the capacity of [`ErrorDetail`]
+    pub fn error(code: ErrorCode, detail: &str) -> Self {
+        let mut truncated = ErrorDetail::new();
+        let mut end = detail.len().min(truncated.capacity());
+        while !detail.is_char_boundary(end) {
+            end -= 1;
+        }
+        truncated.push_str(&detail[..end]);
+        Self::Error {
+            code,
+            detail: truncated,
+        }
+    }
+}
+
+/// Current time in milliseconds since the Unix epoch, comparable across processes
+pub fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
 }
 
 pub trait CheckOk<R> {
@@ -0,0 +1,297 @@
+//! Bounded lock-free MPMC ring buffer (after Dmitry Vyukov), placed in shared memory
+//!
+//! Every slot carries a sequence number, which tells producers and consumers
+//! whether it is free for the lap they are in. Producers claim slots by a CAS on `tail`,
+//! consumers by a CAS on `head`, so neither side takes a lock.
+//! Only when the ring is empty (or full), consumers (or producers) block on a semaphore.
+//!
+//! Unlike the mutex-based queue, the ring cannot be repaired if a producer dies between
+//! claiming a slot and publishing it, consumers stop at that slot.
+
+use std::{
+    cell::UnsafeCell,
+    mem::MaybeUninit,
+    ptr,
+    sync::atomic::{fence, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use crate::{shm::ShmSafe, sync::Semaphore};
+
+/// Keeps the indices on separate cache lines, producers and consumers do not share them
+#[repr(C, align(64))]
+#[derive(Debug)]
+struct CachePadded<T>(T);
+
+#[repr(C)]
+#[derive(Debug)]
+struct Slot<T> {
+    /// `index` if the slot is free for the producer of `index`,
+    /// `index + 1` if it holds the item for the consumer of `index`
+    seq: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+/// Parks threads while the ring is empty or full
+///
+/// A waker claims one waiter by decrementing `waiting`, then posts the semaphore.
+/// Waiters retry after registering, so wakeups are never lost,
+/// at worst they wake up once too often.
+#[repr(C)]
+#[derive(Debug)]
+struct Parking {
+    waiting: AtomicUsize,
+    semaphore: Semaphore,
+}
+
+impl Parking {
+    /// Park until woken up, unless `ready` succeeds after registering
+    fn park<R>(&self, ready: impl FnOnce() -> Option<R>) -> Option<R> {
+        self.waiting.fetch_add(1, Ordering::SeqCst);
+        fence(Ordering::SeqCst);
+        if let Some(r) = ready() {
+            self.unregister();
+            return Some(r);
+        }
+        self.semaphore.wait();
+        None
+    }
+
+    /// Same as [`Self::park`], but wait for at most `timeout`
+    fn park_timeout<R>(&self, timeout: Duration, ready: impl FnOnce() -> Option<R>) -> Option<R> {
+        self.waiting.fetch_add(1, Ordering::SeqCst);
+        fence(Ordering::SeqCst);
+        if let Some(r) = ready() {
+            self.unregister();
+            return Some(r);
+        }
+        if self.semaphore.wait_timeout(timeout).is_err() {
+            self.unregister();
+        }
+        None
+    }
+
+    /// Withdraw a registration, if a waker claimed it already, its post
+    /// causes a spurious wakeup later
+    fn unregister(&self) {
+        let _ = self
+            .waiting
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1));
+    }
+
+    fn wake_one(&self) {
+        fence(Ordering::SeqCst);
+        if self
+            .waiting
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok()
+        {
+            self.semaphore.post();
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct Ring<T, const N: usize> {
+    /// Next index to consume
+    head: CachePadded<AtomicUsize>,
+    /// Next index to produce
+    tail: CachePadded<AtomicUsize>,
+    consumers: Parking,
+    producers: Parking,
+    slots: [Slot<T>; N],
+}
+
+impl<T: Copy, const N: usize> Ring<T, N> {
+    /// # Safety
+    /// `ring` must point to writable, uninitialized memory
+    pub unsafe fn init_at(ring: *mut Self) {
+        const { assert!(N.is_power_of_two()) };
+
+        ptr::write(&raw mut (*ring).head, CachePadded(AtomicUsize::new(0)));
+        ptr::write(&raw mut (*ring).tail, CachePadded(AtomicUsize::new(0)));
+        for parking in [&raw mut (*ring).consumers, &raw mut (*ring).producers] {
+            ptr::write(
+                parking,
+                Parking {
+                    waiting: AtomicUsize::new(0),
+                    semaphore: Semaphore::new(0),
+                },
+            );
+        }
+
+        // Initialize the slots one at a time to keep them off the stack
+        let slots = (&raw mut (*ring).slots).cast::<Slot<T>>();
+        for index in 0..N {
+            // The values consist of `MaybeUninit`s, which need no initialization
+            ptr::write(&raw mut (*slots.add(index)).seq, AtomicUsize::new(index));
+        }
+    }
+
+    /// Append an item, returns it if the ring is full
+    pub fn try_push(&self, item: T) -> Result<(), T> {
+        let mut tail = self.tail.0.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[tail & (N - 1)];
+            let seq = slot.seq.load(Ordering::Acquire);
+            match seq.wrapping_sub(tail) as isize {
+                0 => match self.tail.0.compare_exchange_weak(
+                    tail,
+                    tail.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        unsafe { (*slot.value.get()).write(item) };
+                        slot.seq.store(tail.wrapping_add(1), Ordering::Release);
+                        self.consumers.wake_one();
+                        return Ok(());
+                    }
+                    Err(current) => tail = current,
+                },
+                // The consumer of the previous lap has not taken the item yet
+                d if d < 0 => return Err(item),
+                _ => tail = self.tail.0.load(Ordering::Relaxed),
+            }
+        }
+    }
+
+    /// Take the oldest item, `None` if the ring is empty
+    pub fn try_pop(&self) -> Option<T> {
+        let mut head = self.head.0.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[head & (N - 1)];
+            let seq = slot.seq.load(Ordering::Acquire);
+            match seq.wrapping_sub(head.wrapping_add(1)) as isize {
+                0 => match self.head.0.compare_exchange_weak(
+                    head,
+                    head.wrapping_add(1),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        let item = unsafe { (*slot.value.get()).assume_init() };
+                        slot.seq.store(head.wrapping_add(N), Ordering::Release);
+                        self.producers.wake_one();
+                        return Some(item);
+                    }
+                    Err(current) => head = current,
+                },
+                // The producer of this index has not written the item yet
+                d if d < 0 => return None,
+                _ => head = self.head.0.load(Ordering::Relaxed),
+            }
+        }
+    }
+
+    /// Append an item, blocks while the ring is full
+    pub fn push(&self, mut item: T) {
+        loop {
+            match self.try_push(item) {
+                Ok(()) => return,
+                Err(i) => item = i,
+            }
+            if self.producers.park(|| self.try_push(item).ok()).is_some() {
+                return;
+            }
+        }
+    }
+
+    /// Append an item, waits for at most `timeout` while the ring is full, returns it on timeout
+    pub fn push_timeout(&self, item: T, timeout: Duration) -> Result<(), T> {
+        self.try_push(item)
+            .or_else(|item| {
+                self.producers
+                    .park_timeout(timeout, || self.try_push(item).ok())
+                    .ok_or(item)
+            })
+            .or_else(|item| self.try_push(item))
+    }
+
+    /// Take the oldest item, blocks while the ring is empty
+    pub fn pop(&self) -> T {
+        loop {
+            if let Some(item) = self.try_pop() {
+                return item;
+            }
+            if let Some(item) = self.consumers.park(|| self.try_pop()) {
+                return item;
+            }
+        }
+    }
+
+    /// Take the oldest item, waits for at most `timeout` while the ring is empty
+    pub fn pop_timeout(&self, timeout: Duration) -> Option<T> {
+        self.try_pop()
+            .or_else(|| self.consumers.park_timeout(timeout, || self.try_pop()))
+            .or_else(|| self.try_pop())
+    }
+}
+
+unsafe impl<T: Send, const N: usize> Send for Ring<T, N> {}
+unsafe impl<T: Send, const N: usize> Sync for Ring<T, N> {}
+
+unsafe impl<T: ShmSafe, const N: usize> ShmSafe for Ring<T, N> {}
+
+#[cfg(test)]
+mod test {
+    use std::{thread, time::Duration};
+
+    use super::Ring;
+
+    fn ring<const N: usize>() -> Box<Ring<u64, N>> {
+        let mut ring = Box::<Ring<u64, N>>::new_uninit();
+        unsafe {
+            Ring::init_at(ring.as_mut_ptr());
+            ring.assume_init()
+        }
+    }
+
+    #[test]
+    fn push_pop() {
+        let ring = ring::<4>();
+        assert_eq!(ring.try_pop(), None);
+        for i in 0..4 {
+            ring.try_push(i).unwrap();
+        }
+        assert_eq!(ring.try_push(4), Err(4));
+        assert_eq!(ring.push_timeout(4, Duration::from_millis(1)), Err(4));
+        assert_eq!(ring.try_pop(), Some(0));
+        ring.try_push(4).unwrap();
+        for i in 1..5 {
+            assert_eq!(ring.try_pop(), Some(i));
+        }
+        assert_eq!(ring.pop_timeout(Duration::from_millis(1)), None);
+    }
+
+    #[test]
+    fn concurrent() {
+        const PRODUCERS: u64 = 4;
+        const ITEMS: u64 = 10_000;
+
+        // Small enough that producers and consumers both have to park
+        let ring = ring::<8>();
+        let sums = thread::scope(|s| {
+            for p in 0..PRODUCERS {
+                let ring = &ring;
+                s.spawn(move || {
+                    for i in 0..ITEMS {
+                        ring.push(p * ITEMS + i);
+                    }
+                });
+            }
+            let consumers: Vec<_> = (0..PRODUCERS)
+                .map(|_| s.spawn(|| (0..ITEMS).map(|_| ring.pop()).sum::<u64>()))
+                .collect();
+            consumers
+                .into_iter()
+                .map(|c| c.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+
+        let n = PRODUCERS * ITEMS;
+        assert_eq!(sums.iter().sum::<u64>(), n * (n - 1) / 2);
+        assert_eq!(ring.try_pop(), None);
+    }
+}
@@ -1,30 +1,68 @@
 use std::{
     fmt::Debug,
-    mem::MaybeUninit,
+    mem::{offset_of, MaybeUninit},
     os::fd::OwnedFd,
     ptr::{copy_nonoverlapping, null_mut},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
 };
 
 use anyhow::{bail, Context};
 use rustix::{
-    fs::{ftruncate, Mode},
+    fs::{fstat, ftruncate, Mode},
     mm::{mmap, munmap, MapFlags, ProtFlags},
     shm::{self, OFlags},
 };
 
-use crate::MAGIC_VALUE;
+use crate::{unix_millis, MAGIC_VALUE};
 
+/// Marker for types that can be placed in a shared memory region
+///
+/// # Safety
+/// Implementors must not contain pointers into process-local memory,
+/// and must be usable from multiple processes at once
 pub unsafe trait ShmSafe {}
 
+/// Identifies what is spoken over a region, processes reject regions of an incompatible protocol
+pub trait Protocol: ShmSafe {
+    /// Incremented when the meaning of the contents changes, but not their layout
+    const VERSION: u32;
+    /// Oldest version this build can still talk to
+    const MIN_VERSION: u32;
+    /// Fingerprint of the memory layout of the contents, see [`layout_hash`]
+    const LAYOUT_HASH: u64;
+}
+
+/// FNV-1a hash over the `(size, alignment)` pairs of the types exchanged through a region
+pub const fn layout_hash(layouts: &[(usize, usize)]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let mut i = 0;
+    while i < layouts.len() {
+        let (size, align) = layouts[i];
+        let mut bytes = (size as u64) << 8 | align.trailing_zeros() as u64;
+        let mut j = 0;
+        while j < 8 {
+            hash ^= bytes & 0xff;
+            hash = hash.wrapping_mul(0x100000001b3);
+            bytes >>= 8;
+            j += 1;
+        }
+        i += 1;
+    }
+    hash
+}
+
 pub struct SharedMemory<T> {
     is_initiator: bool,
     descriptor: String,
     memory: *mut SharedMemoryContents<T>,
 }
 
-impl<T: ShmSafe> SharedMemory<T> {
+impl<T: Protocol> SharedMemory<T> {
+    /// Create the region, advertising the optional `features` of the creator as a bitmap
     pub fn create(
         descriptor: impl Into<String>,
+        features: u64,
         init: impl FnOnce(&mut MaybeUninit<T>),
     ) -> anyhow::Result<Self> {
         let descriptor = descriptor.into();
@@ -44,6 +82,12 @@ impl<T: ShmSafe> SharedMemory<T> {
             let magic = &raw mut (*ptr).magic;
             let contents = &raw mut (*ptr).contents;
 
+            (&raw mut (*ptr).version).write(T::VERSION);
+            (&raw mut (*ptr).min_version).write(T::MIN_VERSION);
+            (&raw mut (*ptr).layout_hash).write(T::LAYOUT_HASH);
+            (&raw mut (*ptr).features).write(features);
+            (&raw mut (*ptr).generation).write(unix_millis());
+            (&raw mut (*ptr).heartbeat).write(AtomicU64::new(unix_millis()));
             init(&mut *contents);
 
             *magic = MAGIC_VALUE;
@@ -56,32 +100,88 @@ impl<T: ShmSafe> SharedMemory<T> {
         })
     }
 
+    /// Map the region behind `descriptor`, if its creator speaks a compatible protocol
+    ///
+    /// # Safety
+    /// The region behind `descriptor` must have been created with
+    /// [`SharedMemory::create`] for a `T` with the same [`Protocol::LAYOUT_HASH`]
     pub unsafe fn join(descriptor: impl Into<String>) -> anyhow::Result<Self> {
         let descriptor = descriptor.into();
         let fd = shm::open(&descriptor, OFlags::RDWR, Mode::RUSR | Mode::WUSR)
             .context("Opening shared memory failed")?;
 
-        let ptr = unsafe { Self::mmap(fd)? };
-
-        unsafe {
-            let magic = &raw mut (*ptr).magic;
-
-            if *magic != MAGIC_VALUE {
-                bail!("Memory not ready yet");
-            }
+        // The creator truncates the region before initializing it
+        let size = fstat(&fd)?.st_size as usize;
+        if size < offset_of!(SharedMemoryContents<T>, contents) {
+            bail!("Memory not ready yet");
         }
-
-        Ok(Self {
+        let ptr = unsafe { Self::mmap(fd)? };
+        // Unmaps the region on errors
+        let joined = Self {
             descriptor,
             memory: ptr,
             is_initiator: false,
-        })
+        };
+
+        let header = unsafe { &*ptr };
+        if header.magic != MAGIC_VALUE {
+            bail!("Memory not ready yet");
+        }
+        if header.version.min(T::VERSION) < header.min_version.max(T::MIN_VERSION) {
+            bail!(
+                "Incompatible protocol: the server speaks versions {} to {}, this build {} to {}",
+                header.min_version,
+                header.version,
+                T::MIN_VERSION,
+                T::VERSION
+            );
+        }
+        if header.layout_hash != T::LAYOUT_HASH || size != size_of::<SharedMemoryContents<T>>() {
+            bail!(
+                "Incompatible memory layout: the server has layout {:#018x}, this build {:#018x}. \
+                 Both have to be built with the same constants and types",
+                header.layout_hash,
+                T::LAYOUT_HASH
+            );
+        }
+
+        Ok(joined)
     }
 
     pub fn get(&self) -> &T {
         unsafe { (*self.memory).contents.assume_init_ref() }
     }
 
+    /// Protocol version both sides speak, the older one of the creator and this build
+    pub fn version(&self) -> u32 {
+        unsafe { (*self.memory).version.min(T::VERSION) }
+    }
+
+    /// Bitmap of the optional features the creator of the region supports
+    pub fn features(&self) -> u64 {
+        unsafe { (*self.memory).features }
+    }
+
+    /// Identifies the process that created the region, later processes have a higher generation
+    pub fn generation(&self) -> u64 {
+        unsafe { (*self.memory).generation }
+    }
+
+    /// Signal that the creator of the region is still alive
+    pub fn beat(&self) {
+        unsafe {
+            (*self.memory)
+                .heartbeat
+                .store(unix_millis(), Ordering::Relaxed)
+        };
+    }
+
+    /// Whether the creator of the region signalled within `lease`
+    pub fn is_alive(&self, lease: Duration) -> bool {
+        let heartbeat = unsafe { (*self.memory).heartbeat.load(Ordering::Relaxed) };
+        unix_millis().saturating_sub(heartbeat) <= lease.as_millis() as u64
+    }
+
     unsafe fn mmap(fd: OwnedFd) -> anyhow::Result<*mut SharedMemoryContents<T>> {
         // Safety: Ptr is null
         Ok(mmap(
@@ -113,6 +213,19 @@ unsafe impl<T: Sync> Sync for SharedMemory<T> {}
 #[repr(C)]
 pub struct SharedMemoryContents<T> {
     magic: u32,
+    // The header has the same layout for all `T`, so it can be checked before the contents are used
+    /// [`Protocol::VERSION`] of the creator
+    version: u32,
+    /// [`Protocol::MIN_VERSION`] of the creator
+    min_version: u32,
+    /// [`Protocol::LAYOUT_HASH`] of the creator
+    layout_hash: u64,
+    /// Optional features of the creator, see [`SharedMemory::create`]
+    features: u64,
+    /// Creation time of the region in milliseconds since the Unix epoch
+    generation: u64,
+    /// Last [`SharedMemory::beat`], in milliseconds since the Unix epoch
+    heartbeat: AtomicU64,
     contents: MaybeUninit<T>,
 }
 
@@ -130,6 +243,8 @@ impl<T: Debug, const N: usize> HeapArrayInit<T, N> {
         Self { inner: vec }
     }
 
+    /// # Safety
+    /// `target` must be valid for writes of `[T; N]`
     pub unsafe fn move_to(self, target: *mut [T; N]) {
         let slice = self.inner.into_boxed_slice();
         let array: Box<[T; N]> = slice.try_into().unwrap();
@@ -137,3 +252,117 @@ impl<T: Debug, const N: usize> HeapArrayInit<T, N> {
         copy_nonoverlapping(array, target, 1);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::process;
+
+    use super::{layout_hash, Protocol, SharedMemory, ShmSafe};
+    use crate::{HashtableMemory, PROTOCOL_VERSION};
+
+    /// Speaks versions 1 and 2
+    struct Current(u64);
+    /// Speaks version 1 only
+    struct Older(u64);
+    // Never read, joining them fails
+    /// Speaks version 3 only
+    #[allow(dead_code)]
+    struct Newer(u64);
+    /// Different layout
+    #[allow(dead_code)]
+    struct Other([u64; 2]);
+
+    unsafe impl ShmSafe for Current {}
+    unsafe impl ShmSafe for Older {}
+    unsafe impl ShmSafe for Newer {}
+    unsafe impl ShmSafe for Other {}
+
+    const HASH: u64 = layout_hash(&[(size_of::<u64>(), align_of::<u64>())]);
+
+    impl Protocol for Current {
+        const VERSION: u32 = 2;
+        const MIN_VERSION: u32 = 1;
+        const LAYOUT_HASH: u64 = HASH;
+    }
+
+    impl Protocol for Older {
+        const VERSION: u32 = 1;
+        const MIN_VERSION: u32 = 1;
+        const LAYOUT_HASH: u64 = HASH;
+    }
+
+    impl Protocol for Newer {
+        const VERSION: u32 = 3;
+        const MIN_VERSION: u32 = 3;
+        const LAYOUT_HASH: u64 = HASH;
+    }
+
+    impl Protocol for Other {
+        const VERSION: u32 = 2;
+        const MIN_VERSION: u32 = 1;
+        const LAYOUT_HASH: u64 = layout_hash(&[(size_of::<[u64; 2]>(), align_of::<u64>())]);
+    }
+
+    #[test]
+    fn join_checks_protocol() {
+        let descriptor = format!("/hashtable-test-{}", process::id());
+        let created = SharedMemory::<Older>::create(&descriptor, 0b101, |c| {
+            c.write(Older(7));
+        })
+        .unwrap();
+
+        // Newer builds talk to older creators, with the older version
+        let joined = unsafe { SharedMemory::<Current>::join(&descriptor) }.unwrap();
+        assert_eq!(joined.version(), 1);
+        assert_eq!(joined.features(), 0b101);
+        assert_eq!(joined.get().0, 7);
+        assert_eq!(created.get().0, 7);
+
+        let Err(e) = (unsafe { SharedMemory::<Newer>::join(&descriptor) }) else {
+            panic!("joined with disjoint protocol versions");
+        };
+        assert!(e.to_string().contains("Incompatible protocol"));
+
+        let Err(e) = (unsafe { SharedMemory::<Other>::join(&descriptor) }) else {
+            panic!("joined with another layout");
+        };
+        assert!(e.to_string().contains("Incompatible memory layout"));
+    }
+
+    /// Stand-in for a server of this build, with the protocol of [`HashtableMemory`]
+    struct Server(u64);
+    /// Client built for the previous protocol version
+    #[allow(dead_code)]
+    struct PreviousClient(u64);
+
+    unsafe impl ShmSafe for Server {}
+    unsafe impl ShmSafe for PreviousClient {}
+
+    impl Protocol for Server {
+        const VERSION: u32 = <HashtableMemory as Protocol>::VERSION;
+        const MIN_VERSION: u32 = <HashtableMemory as Protocol>::MIN_VERSION;
+        const LAYOUT_HASH: u64 = <HashtableMemory as Protocol>::LAYOUT_HASH;
+    }
+
+    impl Protocol for PreviousClient {
+        const VERSION: u32 = PROTOCOL_VERSION - 1;
+        const MIN_VERSION: u32 = PROTOCOL_VERSION - 1;
+        const LAYOUT_HASH: u64 = <HashtableMemory as Protocol>::LAYOUT_HASH;
+    }
+
+    #[test]
+    fn previous_version_is_rejected() {
+        // Only exact matches are supported, see `MIN_PROTOCOL_VERSION`
+        let descriptor = format!("/hashtable-test-previous-{}", process::id());
+        let created = SharedMemory::<Server>::create(&descriptor, 0, |c| {
+            c.write(Server(7));
+        })
+        .unwrap();
+
+        let Err(e) = (unsafe { SharedMemory::<PreviousClient>::join(&descriptor) }) else {
+            panic!("joined with protocol version {}", PROTOCOL_VERSION - 1);
+        };
+        assert!(e.to_string().contains("Incompatible protocol"));
+        assert_eq!(created.get().0, 7);
+    }
+}
@@ -0,0 +1,422 @@
+//! Allocator for values of variable length, placed in shared memory
+//!
+//! Values do not fit into the fixed-size request and response frames, so they are copied
+//! into blocks of the slab, and only a [`Blob`] referring to them is sent.
+//! A value occupies a chain of blocks, which are linked in the block table.
+//!
+//! Every value has an owner, the client that wrote it or that a response carrying it was
+//! written to, or the server. Owners are checked when values are taken out of the slab,
+//! and the values of a client are freed when it leaves or is reaped, so dead clients do
+//! not leak blocks. Stale handles are detected by the generation of the first block.
+
+use std::{cell::UnsafeCell, error::Error, fmt, ptr};
+
+use crate::{
+    shm::ShmSafe,
+    sync::{Mutex, MutexGuard},
+    ErrorCode, MAX_VALUE_SIZE, SLAB_BLOCKS, SLAB_BLOCK_SIZE,
+};
+
+/// Owner of the values the server wrote, until they are handed to a client
+pub const SERVER: u32 = u32::MAX;
+/// Owner of free blocks, clients use ids below it
+pub const FREE: u32 = u32::MAX - 1;
+
+/// Marks all but the first block of a value
+const CONTINUATION: u32 = u32::MAX;
+/// Marks the last block of a value
+const END: u32 = u32::MAX;
+
+/// Handle of a value in the [`Slab`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Blob {
+    /// First block of the value
+    pub index: u32,
+    /// Generation of the first block when the value was allocated
+    pub generation: u32,
+    /// Length of the value in bytes
+    pub len: u32,
+}
+
+/// Why a value could not be stored in or taken out of the slab
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlabError {
+    /// The value exceeds the maximum size configured by the server
+    TooLarge { len: usize, max: usize },
+    /// There are not enough free blocks for the value
+    Full,
+    /// The handle does not refer to a value of the expected owner
+    InvalidHandle,
+}
+
+impl SlabError {
+    /// Code of the error response to a request that failed with this error
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::TooLarge { .. } | Self::InvalidHandle => ErrorCode::InvalidArgument,
+            Self::Full => ErrorCode::SlabFull,
+        }
+    }
+}
+
+impl fmt::Display for SlabError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooLarge { len, max } => {
+                write!(f, "value of {len} bytes exceeds the maximum of {max} bytes")
+            }
+            Self::Full => write!(f, "no space left for the value"),
+            Self::InvalidHandle => write!(f, "invalid value handle"),
+        }
+    }
+}
+
+impl Error for SlabError {}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Block {
+    /// Client id of the owner, [`SERVER`] or [`FREE`]
+    owner: u32,
+    /// Incremented whenever the block is allocated
+    generation: u32,
+    /// Length of the value in the first block, [`CONTINUATION`] in the others
+    len: u32,
+    /// Next block of the value, or [`END`]
+    next: u32,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct SlabState {
+    blocks: [Block; SLAB_BLOCKS],
+    /// Stack of the free blocks, the first `free_len` entries are used
+    free: [u32; SLAB_BLOCKS],
+    free_len: usize,
+}
+
+#[repr(C)]
+#[derive(Debug)]
+pub struct Slab {
+    /// Set by the server on startup, at most [`MAX_VALUE_SIZE`]
+    max_value_size: usize,
+    state: Mutex<SlabState>,
+    /// Accessed by the owner of the block, written by [`Slab::alloc`] after reserving the block
+    /// and before handing out a [`Blob`] for it, read while holding the lock of `state`
+    data: [UnsafeCell<[u8; SLAB_BLOCK_SIZE]>; SLAB_BLOCKS],
+}
+
+impl Slab {
+    /// # Safety
+    /// `slab` must point to writable, uninitialized memory
+    pub unsafe fn init_at(slab: *mut Self, max_value_size: usize) {
+        assert!(max_value_size <= MAX_VALUE_SIZE);
+
+        ptr::write(&raw mut (*slab).max_value_size, max_value_size);
+        // Initialize the tables one entry at a time to keep them off the stack
+        Mutex::init_at(&raw mut (*slab).state, |state| {
+            let blocks = (&raw mut (*state).blocks).cast::<Block>();
+            let free = (&raw mut (*state).free).cast::<u32>();
+            for index in 0..SLAB_BLOCKS {
+                ptr::write(
+                    blocks.add(index),
+                    Block {
+                        owner: FREE,
+                        generation: 0,
+                        len: CONTINUATION,
+                        next: END,
+                    },
+                );
+                // Allocate from the start of the slab first
+                ptr::write(free.add(index), (SLAB_BLOCKS - 1 - index) as u32);
+            }
+            ptr::write(&raw mut (*state).free_len, SLAB_BLOCKS);
+        });
+        // The data consists of bytes, which are valid in any state
+    }
+
+    /// Largest value the slab accepts, in bytes
+    pub fn max_value_size(&self) -> usize {
+        self.max_value_size
+    }
+
+    /// Number of blocks not used by any value
+    pub fn free_blocks(&self) -> usize {
+        self.lock().free_len
+    }
+
+    /// Copy `value` into the slab, owned by `owner` until it is taken or handed over
+    pub fn alloc(&self, value: &[u8], owner: u32) -> Result<Blob, SlabError> {
+        if value.len() > self.max_value_size {
+            return Err(SlabError::TooLarge {
+                len: value.len(),
+                max: self.max_value_size,
+            });
+        }
+        let mut chunks = value.chunks(SLAB_BLOCK_SIZE);
+        let needed = chunks.len().max(1);
+
+        let mut state = self.lock();
+        if state.free_len < needed {
+            return Err(SlabError::Full);
+        }
+        let mut blob = None;
+        let mut previous: Option<usize> = None;
+        let mut reserved = Vec::with_capacity(needed);
+        for _ in 0..needed {
+            let index = state.free[state.free_len - 1] as usize;
+            let block = &mut state.blocks[index];
+            block.owner = owner;
+            block.generation = block.generation.wrapping_add(1);
+            block.next = END;
+            block.len = match previous {
+                Some(_) => CONTINUATION,
+                None => value.len() as u32,
+            };
+            let generation = block.generation;
+            // Dropped from the stack only once it is owned, see `lock`
+            state.free_len -= 1;
+
+            match previous {
+                Some(previous) => state.blocks[previous].next = index as u32,
+                None => {
+                    blob = Some(Blob {
+                        index: index as u32,
+                        generation,
+                        len: value.len() as u32,
+                    })
+                }
+            }
+            reserved.push(index);
+            previous = Some(index);
+        }
+        drop(state);
+
+        // Copied without holding the lock, no handle to the reserved blocks exists yet
+        for index in reserved {
+            let chunk = chunks.next().unwrap_or_default();
+            let data = unsafe { &mut *self.data[index].get() };
+            data[..chunk.len()].copy_from_slice(chunk);
+        }
+        Ok(blob.unwrap())
+    }
+
+    /// Copy a value of `owner` out of the slab, and free it
+    pub fn take(&self, blob: Blob, owner: u32) -> Result<Vec<u8>, SlabError> {
+        let mut state = self.lock();
+        state.check(blob, owner, self.max_value_size)?;
+        let mut value = Vec::with_capacity(blob.len as usize);
+        for index in state.chain(blob.index) {
+            let remaining = blob.len as usize - value.len();
+            let data = unsafe { &*self.data[index].get() };
+            value.extend_from_slice(&data[..remaining.min(SLAB_BLOCK_SIZE)]);
+        }
+        state.free_chain(blob.index);
+        Ok(value)
+    }
+
+    /// Free a value of `owner` without reading it
+    pub fn free(&self, blob: Blob, owner: u32) -> Result<(), SlabError> {
+        let mut state = self.lock();
+        state.check(blob, owner, self.max_value_size)?;
+        state.free_chain(blob.index);
+        Ok(())
+    }
+
+    /// Hand a value of `from` over to `to`
+    pub fn give(&self, blob: Blob, from: u32, to: u32) -> Result<(), SlabError> {
+        let mut state = self.lock();
+        state.check(blob, from, self.max_value_size)?;
+        let chain: Vec<_> = state.chain(blob.index).collect();
+        for index in chain {
+            state.blocks[index].owner = to;
+        }
+        Ok(())
+    }
+
+    /// Free all values of `owner`, returns the number of freed blocks
+    pub fn release(&self, owner: u32) -> usize {
+        let mut state = self.lock();
+        let mut released = 0;
+        for index in 0..SLAB_BLOCKS {
+            if state.blocks[index].owner == owner {
+                state.free_block(index);
+                released += 1;
+            }
+        }
+        released
+    }
+
+    /// Lock the block table, and repair it if a process died while holding the lock
+    ///
+    /// Blocks are owned before they are dropped from the free stack, and pushed onto it
+    /// after they were freed, so the stack can be rebuilt from the owners.
+    /// Blocks of half-written values are owned by the dead client, and freed once it is reaped
+    fn lock(&self) -> MutexGuard<'_, SlabState> {
+        self.state.lock().unwrap_or_else(|died| {
+            let mut state = died.into_inner();
+            state.free_len = 0;
+            for index in 0..SLAB_BLOCKS {
+                if state.blocks[index].owner == FREE {
+                    let free_len = state.free_len;
+                    state.free[free_len] = index as u32;
+                    state.free_len += 1;
+                }
+            }
+            state
+        })
+    }
+}
+
+impl SlabState {
+    /// Check that `blob` refers to a complete value of `owner`
+    ///
+    /// Handles are written by clients, so they might point into the middle of a value
+    fn check(&self, blob: Blob, owner: u32, max_value_size: usize) -> Result<(), SlabError> {
+        let first = self
+            .blocks
+            .get(blob.index as usize)
+            .ok_or(SlabError::InvalidHandle)?;
+        if first.generation != blob.generation
+            || first.len != blob.len
+            || first.len == CONTINUATION
+            || blob.len as usize > max_value_size
+            || owner == FREE
+        {
+            return Err(SlabError::InvalidHandle);
+        }
+        let expected = (blob.len as usize).div_ceil(SLAB_BLOCK_SIZE).max(1);
+        let mut blocks = 0;
+        for index in self.chain(blob.index) {
+            let block = &self.blocks[index];
+            if blocks == expected
+                || block.owner != owner
+                || (blocks > 0 && block.len != CONTINUATION)
+            {
+                return Err(SlabError::InvalidHandle);
+            }
+            blocks += 1;
+        }
+        if blocks != expected {
+            return Err(SlabError::InvalidHandle);
+        }
+        Ok(())
+    }
+
+    /// Blocks of the value starting at `first`, at most all blocks
+    fn chain(&self, first: u32) -> impl Iterator<Item = usize> + '_ {
+        let mut next = first;
+        std::iter::from_fn(move || {
+            let index = next as usize;
+            next = self.blocks.get(index)?.next;
+            Some(index)
+        })
+        .take(SLAB_BLOCKS)
+    }
+
+    fn free_chain(&mut self, first: u32) {
+        let chain: Vec<_> = self.chain(first).collect();
+        for index in chain {
+            self.free_block(index);
+        }
+    }
+
+    fn free_block(&mut self, index: usize) {
+        self.blocks[index].owner = FREE;
+        let free_len = self.free_len;
+        self.free[free_len] = index as u32;
+        self.free_len += 1;
+    }
+}
+
+unsafe impl Send for Slab {}
+unsafe impl Sync for Slab {}
+
+unsafe impl ShmSafe for Slab {}
+
+#[cfg(test)]
+mod test {
+    use crate::{MAX_VALUE_SIZE, SLAB_BLOCKS, SLAB_BLOCK_SIZE};
+
+    use super::{Blob, Slab, SlabError, SERVER};
+
+    fn slab() -> Box<Slab> {
+        let mut slab = Box::<Slab>::new_uninit();
+        unsafe {
+            Slab::init_at(slab.as_mut_ptr(), MAX_VALUE_SIZE);
+            slab.assume_init()
+        }
+    }
+
+    #[test]
+    fn alloc_take() {
+        let slab = slab();
+        let value: Vec<u8> = (0..3 * SLAB_BLOCK_SIZE + 1).map(|i| i as u8).collect();
+        let blob = slab.alloc(&value, 1).unwrap();
+        let empty = slab.alloc(&[], 1).unwrap();
+        assert_eq!(slab.free_blocks(), SLAB_BLOCKS - 5);
+
+        assert_eq!(slab.take(blob, 2), Err(SlabError::InvalidHandle));
+        slab.give(blob, 1, SERVER).unwrap();
+        assert_eq!(slab.take(blob, 1), Err(SlabError::InvalidHandle));
+        assert_eq!(slab.take(blob, SERVER).unwrap(), value);
+        assert_eq!(slab.take(empty, 1).unwrap(), Vec::<u8>::new());
+        assert_eq!(slab.free_blocks(), SLAB_BLOCKS);
+
+        // The blocks are reused, but the handles are stale
+        let other = slab.alloc(&value, 1).unwrap();
+        assert_eq!(other.index, empty.index);
+        assert_eq!(slab.free(empty, 1), Err(SlabError::InvalidHandle));
+        assert_eq!(slab.free(blob, 1), Err(SlabError::InvalidHandle));
+        slab.free(other, 1).unwrap();
+    }
+
+    #[test]
+    fn forged_handles() {
+        let slab = slab();
+        let value = vec![7; 2 * SLAB_BLOCK_SIZE];
+        let blob = slab.alloc(&value, 1).unwrap();
+        let second = blob.index + 1;
+
+        // The second block of the value, passed off as a (huge) value of its own
+        for len in [u32::MAX, SLAB_BLOCK_SIZE as u32] {
+            let forged = Blob {
+                index: second,
+                generation: blob.generation,
+                len,
+            };
+            assert_eq!(slab.take(forged, 1), Err(SlabError::InvalidHandle));
+            assert_eq!(slab.free(forged, 1), Err(SlabError::InvalidHandle));
+        }
+        let longer = Blob {
+            len: 3 * SLAB_BLOCK_SIZE as u32,
+            ..blob
+        };
+        assert_eq!(slab.take(longer, 1), Err(SlabError::InvalidHandle));
+
+        assert_eq!(slab.take(blob, 1).unwrap(), value);
+        assert_eq!(slab.free_blocks(), SLAB_BLOCKS);
+    }
+
+    #[test]
+    fn limits() {
+        let slab = slab();
+        let largest = vec![0; MAX_VALUE_SIZE];
+        assert_eq!(
+            slab.alloc(&[0; MAX_VALUE_SIZE + 1], 1),
+            Err(SlabError::TooLarge {
+                len: MAX_VALUE_SIZE + 1,
+                max: MAX_VALUE_SIZE
+            })
+        );
+        let per_value = MAX_VALUE_SIZE / SLAB_BLOCK_SIZE;
+        for _ in 0..SLAB_BLOCKS / per_value {
+            slab.alloc(&largest, 1).unwrap();
+        }
+        assert_eq!(slab.alloc(&[0], 2), Err(SlabError::Full));
+
+        // Dead clients do not leak their values
+        assert_eq!(slab.release(1), SLAB_BLOCKS);
+        slab.alloc(&largest, 2).unwrap();
+    }
+}
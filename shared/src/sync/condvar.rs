@@ -1,18 +1,14 @@
-use std::{
-    cell::UnsafeCell,
-    mem::MaybeUninit,
-    time::{Duration, SystemTime, UNIX_EPOCH},
-};
+use std::{cell::UnsafeCell, mem::MaybeUninit, time::Duration};
 
 use libc::{
-    __errno_location, c_int, pthread_cond_broadcast, pthread_cond_destroy, pthread_cond_init,
-    pthread_cond_signal, pthread_cond_t, pthread_cond_timedwait, pthread_cond_wait,
-    pthread_condattr_init, pthread_condattr_setpshared, pthread_mutex_t, timespec, ETIMEDOUT,
+    c_int, pthread_cond_broadcast, pthread_cond_destroy, pthread_cond_init, pthread_cond_signal,
+    pthread_cond_t, pthread_cond_timedwait, pthread_cond_wait, pthread_condattr_init,
+    pthread_condattr_setpshared, pthread_mutex_t, ETIMEDOUT,
 };
 
 use crate::{shm::ShmSafe, CheckOk};
 
-use super::{MutexGuard, INTER_PROCESS};
+use super::{deadline, LockResult, MutexGuard, INTER_PROCESS};
 
 #[repr(C)]
 #[derive(Debug)]
@@ -57,20 +53,21 @@ impl Condvar {
         }
     }
 
-    pub fn wait<'m, T>(&self, guard: MutexGuard<'m, T>) -> MutexGuard<'m, T> {
+    /// Same as [`super::Mutex::lock`], reports if the previous owner of the lock died
+    pub fn wait<'m, T>(&self, guard: MutexGuard<'m, T>) -> LockResult<MutexGuard<'m, T>> {
         unsafe {
-            if pthread_cond_wait((*self.inner.get()).as_mut_ptr(), guard.get_inner_lock()) != 0 {
-                panic!("failed to wait on condvar");
-            }
+            let result =
+                pthread_cond_wait((*self.inner.get()).as_mut_ptr(), guard.get_inner_lock());
+            guard.relocked(result)
         }
-        guard
     }
 
+    /// Returns `None` if the timeout expired
     pub fn wait_timeout<'m, T>(
         &self,
         guard: MutexGuard<'m, T>,
         timeout: Duration,
-    ) -> Option<MutexGuard<'m, T>> {
+    ) -> Option<LockResult<MutexGuard<'m, T>>> {
         let result = unsafe {
             cond_wait_timeout(
                 (*self.inner.get()).as_mut_ptr(),
@@ -79,13 +76,18 @@ impl Condvar {
             )
         };
         match result {
-            0 => Some(guard),
             ETIMEDOUT => None,
-            e => panic!("failed to wait for condvar: {e}"),
+            result => Some(unsafe { guard.relocked(result) }),
         }
     }
 }
 
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 unsafe impl Send for Condvar {}
 unsafe impl Sync for Condvar {}
 
@@ -99,19 +101,12 @@ impl Drop for Condvar {
 
 unsafe impl ShmSafe for Condvar {}
 
+/// # Safety
+/// `cond` and `mutex` must be initialized, and `mutex` locked by the caller
 pub unsafe fn cond_wait_timeout(
     cond: *mut pthread_cond_t,
     mutex: *mut pthread_mutex_t,
     timeout: Duration,
 ) -> c_int {
-    let target = SystemTime::now().duration_since(UNIX_EPOCH).unwrap() + timeout;
-    let ts = timespec {
-        tv_sec: target.as_secs() as i64,
-        tv_nsec: target.subsec_nanos() as i64,
-    };
-    if pthread_cond_timedwait(cond, mutex, &raw const ts) != 0 {
-        *__errno_location()
-    } else {
-        0
-    }
+    pthread_cond_timedwait(cond, mutex, &deadline(timeout))
 }
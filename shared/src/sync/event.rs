@@ -0,0 +1,75 @@
+use std::{
+    ptr::null,
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+
+use libc::{syscall, timespec, SYS_futex, FUTEX_WAIT, FUTEX_WAKE};
+
+use crate::shm::ShmSafe;
+
+use super::{LockResult, MutexGuard};
+
+/// Process-shared wakeup primitive on top of a futex, used like a condition variable
+///
+/// Unlike [`super::Condvar`], it keeps no state about its waiters,
+/// so waiters can be killed at any time without blocking the other processes
+#[repr(C)]
+#[derive(Debug)]
+pub struct Event {
+    /// Incremented on every notification, waiters sleep as long as it is unchanged
+    seq: AtomicU32,
+}
+
+impl Event {
+    pub fn new() -> Self {
+        Self {
+            seq: AtomicU32::new(0),
+        }
+    }
+
+    /// Release the lock and wait for a notification, or until the timeout expired
+    ///
+    /// Same as [`super::Mutex::lock`], reports if the previous owner of the lock died
+    pub fn wait<'m, T>(
+        &self,
+        guard: MutexGuard<'m, T>,
+        timeout: Option<Duration>,
+    ) -> LockResult<MutexGuard<'m, T>> {
+        // Read while holding the lock, notifications after the state changed are not missed
+        let seq = self.seq.load(Ordering::Acquire);
+        let mutex = guard.mutex();
+        drop(guard);
+
+        let ts = timeout.map(|timeout| timespec {
+            tv_sec: timeout.as_secs() as i64,
+            tv_nsec: timeout.subsec_nanos() as i64,
+        });
+        let ts = ts.as_ref().map_or(null(), |ts| ts as *const timespec);
+        // Returns early on notifications, timeouts and signals alike, callers check their condition
+        unsafe { syscall(SYS_futex, self.seq.as_ptr(), FUTEX_WAIT, seq, ts) };
+
+        mutex.lock()
+    }
+
+    pub fn notify_one(&self) {
+        self.notify(1);
+    }
+
+    pub fn notify_all(&self) {
+        self.notify(i32::MAX);
+    }
+
+    fn notify(&self, waiters: i32) {
+        self.seq.fetch_add(1, Ordering::Release);
+        unsafe { syscall(SYS_futex, self.seq.as_ptr(), FUTEX_WAKE, waiters) };
+    }
+}
+
+impl Default for Event {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl ShmSafe for Event {}
@@ -1,11 +1,63 @@
 mod condvar;
+mod event;
 mod mutex;
 mod rwlock;
 mod semaphore;
 
+use std::{
+    error::Error,
+    fmt, io,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use libc::{c_int, timespec, EAGAIN, EBUSY, ETIMEDOUT};
+
 const INTER_PROCESS: i32 = 1;
 
 pub use condvar::*;
+pub use event::*;
 pub use mutex::*;
 pub use rwlock::*;
 pub use semaphore::*;
+
+/// Why a non-blocking or timed operation did not succeed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncError {
+    /// The operation would have blocked
+    WouldBlock,
+    /// The timeout expired
+    TimedOut,
+    /// Any other error, with its error number
+    Os(c_int),
+}
+
+impl SyncError {
+    fn from_errno(errno: c_int) -> Self {
+        match errno {
+            EAGAIN | EBUSY => Self::WouldBlock,
+            ETIMEDOUT => Self::TimedOut,
+            e => Self::Os(e),
+        }
+    }
+}
+
+impl fmt::Display for SyncError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::WouldBlock => write!(f, "operation would block"),
+            Self::TimedOut => write!(f, "operation timed out"),
+            Self::Os(e) => write!(f, "{}", io::Error::from_raw_os_error(*e)),
+        }
+    }
+}
+
+impl Error for SyncError {}
+
+/// Absolute `CLOCK_REALTIME` time after `timeout`, as expected by the timed pthread functions
+fn deadline(timeout: Duration) -> timespec {
+    let target = SystemTime::now().duration_since(UNIX_EPOCH).unwrap() + timeout;
+    timespec {
+        tv_sec: target.as_secs() as i64,
+        tv_nsec: target.subsec_nanos() as i64,
+    }
+}
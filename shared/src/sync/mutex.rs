@@ -1,18 +1,41 @@
 use std::{
     cell::UnsafeCell,
-    mem::MaybeUninit,
+    fmt,
+    mem::{self, MaybeUninit},
     ops::{Deref, DerefMut},
+    time::Duration,
 };
 
 use libc::{
-    pthread_mutex_destroy, pthread_mutex_init, pthread_mutex_lock, pthread_mutex_t,
-    pthread_mutex_unlock, pthread_mutexattr_init, pthread_mutexattr_setpshared,
+    c_int, pthread_mutex_consistent, pthread_mutex_destroy, pthread_mutex_init, pthread_mutex_lock,
+    pthread_mutex_t, pthread_mutex_timedlock, pthread_mutex_trylock, pthread_mutex_unlock,
+    pthread_mutexattr_init, pthread_mutexattr_setpshared, pthread_mutexattr_setrobust, EOWNERDEAD,
+    PTHREAD_MUTEX_ROBUST,
 };
 
 use crate::{shm::ShmSafe, CheckOk};
 
-use super::INTER_PROCESS;
+use super::{deadline, SyncError, INTER_PROCESS};
 
+/// The previous owner of the mutex died while holding it
+///
+/// The mutex is usable again, but the data it protects may be inconsistent,
+/// and should be repaired before the guard is released
+#[derive(Debug)]
+pub struct OwnerDied<G>(G);
+
+impl<G> OwnerDied<G> {
+    pub fn into_inner(self) -> G {
+        self.0
+    }
+}
+
+pub type LockResult<G> = Result<G, OwnerDied<G>>;
+
+/// Robust, process-shared mutex
+///
+/// If a process dies while holding the lock, the next [`Mutex::lock`] reports it with [`OwnerDied`]
+/// instead of blocking forever
 #[repr(C)]
 #[derive(Debug)]
 pub struct Mutex<T> {
@@ -31,6 +54,10 @@ impl<T> Mutex<T> {
             .r("attr_setpshared")
             .unwrap();
 
+        pthread_mutexattr_setrobust(attr.as_mut_ptr(), PTHREAD_MUTEX_ROBUST)
+            .r("attr_setrobust")
+            .unwrap();
+
         pthread_mutex_init(lock, attr.as_ptr())
             .r("mutex_init")
             .unwrap();
@@ -43,6 +70,9 @@ impl<T> Mutex<T> {
         Self { lock, data }
     }
 
+    /// # Safety
+    /// `target` must be valid for writes, `init_data` has to fully
+    /// initialize the data behind the pointer it receives
     pub unsafe fn init_at(target: *mut Self, init_data: impl FnOnce(*mut T)) {
         let lock = &raw mut (*target).lock;
         let data = &raw mut (*target).data;
@@ -52,16 +82,44 @@ impl<T> Mutex<T> {
         init_data(data);
     }
 
-    pub fn lock(&self) -> MutexGuard<T> {
-        unsafe {
-            if pthread_mutex_lock((*self.lock.get()).as_mut_ptr()) != 0 {
-                panic!("failed to lock mutex");
-            }
-            MutexGuard {
-                lock: self,
-                data: &mut *self.data.get(),
-            }
+    pub fn lock(&self) -> LockResult<MutexGuard<'_, T>> {
+        let result = unsafe { pthread_mutex_lock((*self.lock.get()).as_mut_ptr()) };
+        self.acquired(result)
+            .unwrap_or_else(|e| panic!("failed to lock mutex: {e}"))
+    }
+
+    /// Acquire the lock if that is possible without blocking,
+    /// fails with [`SyncError::WouldBlock`] otherwise
+    ///
+    /// Same as [`Self::lock`], reports if the previous owner of the lock died
+    pub fn try_lock(&self) -> Result<LockResult<MutexGuard<'_, T>>, SyncError> {
+        let result = unsafe { pthread_mutex_trylock((*self.lock.get()).as_mut_ptr()) };
+        self.acquired(result)
+    }
+
+    /// Wait for at most `timeout` to acquire the lock, fails with [`SyncError::TimedOut`] once it expired
+    ///
+    /// Same as [`Self::lock`], reports if the previous owner of the lock died
+    pub fn lock_timeout(
+        &self,
+        timeout: Duration,
+    ) -> Result<LockResult<MutexGuard<'_, T>>, SyncError> {
+        let result =
+            unsafe { pthread_mutex_timedlock((*self.lock.get()).as_mut_ptr(), &deadline(timeout)) };
+        self.acquired(result)
+    }
+
+    /// Guard for the `result` of an attempt to acquire the lock, if it succeeded
+    fn acquired(&self, result: c_int) -> Result<LockResult<MutexGuard<'_, T>>, SyncError> {
+        if result != 0 && result != EOWNERDEAD {
+            return Err(SyncError::from_errno(result));
         }
+        let guard = MutexGuard {
+            lock: self,
+            data: unsafe { &mut *self.data.get() },
+        };
+        // Safety: The lock is held
+        Ok(unsafe { guard.relocked(result) })
     }
 }
 
@@ -71,9 +129,41 @@ pub struct MutexGuard<'a, T: 'a> {
 }
 
 impl<'a, T: 'a> MutexGuard<'a, T> {
+    pub(super) fn mutex(&self) -> &'a Mutex<T> {
+        self.lock
+    }
+
     pub fn get_inner_lock(&self) -> *mut pthread_mutex_t {
         unsafe { (*self.lock.lock.get()).as_mut_ptr() }
     }
+
+    /// Check the `result` of an operation that acquired the lock, and make it consistent
+    /// again if its previous owner died
+    ///
+    /// # Safety
+    /// The lock must be held by this thread, unless `result` indicates an error
+    pub(super) unsafe fn relocked(self, result: c_int) -> LockResult<Self> {
+        match result {
+            0 => Ok(self),
+            EOWNERDEAD => {
+                pthread_mutex_consistent(self.get_inner_lock())
+                    .r("mutex_consistent")
+                    .unwrap();
+                Err(OwnerDied(self))
+            }
+            e => {
+                // The lock is not held, so it must not be unlocked
+                mem::forget(self);
+                panic!("failed to lock mutex: {e}");
+            }
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for MutexGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
 }
 
 impl<T> Deref for MutexGuard<'_, T> {
@@ -111,3 +201,51 @@ impl<T> Drop for Mutex<T> {
 }
 
 unsafe impl<T> ShmSafe for Mutex<T> where T: ShmSafe {}
+
+#[cfg(test)]
+mod test {
+    use std::{mem, thread, time::Duration};
+
+    use crate::sync::SyncError;
+
+    use super::Mutex;
+
+    #[test]
+    fn owner_died() {
+        let mutex = Mutex::new(1);
+        thread::scope(|s| {
+            s.spawn(|| {
+                let mut guard = mutex.lock().unwrap();
+                *guard = 2;
+                // Exit while holding the lock
+                mem::forget(guard);
+            });
+        });
+
+        let died = mutex.lock().unwrap_err();
+        assert_eq!(*died.into_inner(), 2);
+        // Consistent again after the first lock
+        assert_eq!(*mutex.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn try_and_timed_lock() {
+        let mutex = Mutex::new(1);
+        let guard = mutex.lock().unwrap();
+        // Contended by another thread, the owner would deadlock on itself
+        thread::scope(|s| {
+            s.spawn(|| {
+                assert_eq!(mutex.try_lock().unwrap_err(), SyncError::WouldBlock);
+                assert_eq!(
+                    mutex.lock_timeout(Duration::from_millis(1)).unwrap_err(),
+                    SyncError::TimedOut
+                );
+            });
+        });
+        drop(guard);
+
+        assert_eq!(*mutex.try_lock().unwrap().unwrap(), 1);
+        let guard = mutex.lock_timeout(Duration::from_millis(1)).unwrap();
+        assert_eq!(*guard.unwrap(), 1);
+    }
+}
@@ -2,17 +2,24 @@ use std::{
     cell::UnsafeCell,
     mem::MaybeUninit,
     ops::{Deref, DerefMut},
+    time::Duration,
 };
 
 use libc::{
-    pthread_rwlock_destroy, pthread_rwlock_init, pthread_rwlock_rdlock, pthread_rwlock_t,
-    pthread_rwlock_unlock, pthread_rwlock_wrlock, pthread_rwlockattr_init,
-    pthread_rwlockattr_setpshared,
+    c_int, pthread_rwlock_destroy, pthread_rwlock_init, pthread_rwlock_rdlock, pthread_rwlock_t,
+    pthread_rwlock_tryrdlock, pthread_rwlock_trywrlock, pthread_rwlock_unlock,
+    pthread_rwlock_wrlock, pthread_rwlockattr_init, pthread_rwlockattr_setpshared, timespec,
 };
 
 use crate::{shm::ShmSafe, CheckOk};
 
-use super::INTER_PROCESS;
+use super::{deadline, SyncError, INTER_PROCESS};
+
+// Provided by glibc, but not bound by the libc crate on Linux
+extern "C" {
+    fn pthread_rwlock_timedrdlock(lock: *mut pthread_rwlock_t, abstime: *const timespec) -> c_int;
+    fn pthread_rwlock_timedwrlock(lock: *mut pthread_rwlock_t, abstime: *const timespec) -> c_int;
+}
 
 #[repr(C)]
 #[derive(Debug)]
@@ -45,10 +52,10 @@ impl<T> RwLock<T> {
         }
     }
 
-    pub fn read(&self) -> RwLockReadGuard<T> {
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
         unsafe {
             if pthread_rwlock_rdlock((*self.lock.get()).as_mut_ptr()) != 0 {
-                panic!("failed to wait for semaphore");
+                panic!("failed to lock rwlock");
             }
             RwLockReadGuard {
                 lock: self,
@@ -57,10 +64,10 @@ impl<T> RwLock<T> {
         }
     }
 
-    pub fn write(&self) -> RwLockWriteGuard<T> {
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
         unsafe {
             if pthread_rwlock_wrlock((*self.lock.get()).as_mut_ptr()) != 0 {
-                panic!("failed to wait for semaphore");
+                panic!("failed to lock rwlock");
             }
             RwLockWriteGuard {
                 lock: self,
@@ -68,6 +75,56 @@ impl<T> RwLock<T> {
             }
         }
     }
+
+    /// Acquire shared access if that is possible without blocking,
+    /// fails with [`SyncError::WouldBlock`] otherwise
+    pub fn try_read(&self) -> Result<RwLockReadGuard<'_, T>, SyncError> {
+        let result = unsafe { pthread_rwlock_tryrdlock((*self.lock.get()).as_mut_ptr()) };
+        self.read_acquired(result)
+    }
+
+    /// Wait for at most `timeout` for shared access, fails with [`SyncError::TimedOut`] once it expired
+    pub fn read_timeout(&self, timeout: Duration) -> Result<RwLockReadGuard<'_, T>, SyncError> {
+        let result = unsafe {
+            pthread_rwlock_timedrdlock((*self.lock.get()).as_mut_ptr(), &deadline(timeout))
+        };
+        self.read_acquired(result)
+    }
+
+    /// Acquire exclusive access if that is possible without blocking,
+    /// fails with [`SyncError::WouldBlock`] otherwise
+    pub fn try_write(&self) -> Result<RwLockWriteGuard<'_, T>, SyncError> {
+        let result = unsafe { pthread_rwlock_trywrlock((*self.lock.get()).as_mut_ptr()) };
+        self.write_acquired(result)
+    }
+
+    /// Wait for at most `timeout` for exclusive access, fails with [`SyncError::TimedOut`] once it expired
+    pub fn write_timeout(&self, timeout: Duration) -> Result<RwLockWriteGuard<'_, T>, SyncError> {
+        let result = unsafe {
+            pthread_rwlock_timedwrlock((*self.lock.get()).as_mut_ptr(), &deadline(timeout))
+        };
+        self.write_acquired(result)
+    }
+
+    fn read_acquired(&self, result: c_int) -> Result<RwLockReadGuard<'_, T>, SyncError> {
+        match result {
+            0 => Ok(RwLockReadGuard {
+                lock: self,
+                data: unsafe { &*self.data.get() },
+            }),
+            e => Err(SyncError::from_errno(e)),
+        }
+    }
+
+    fn write_acquired(&self, result: c_int) -> Result<RwLockWriteGuard<'_, T>, SyncError> {
+        match result {
+            0 => Ok(RwLockWriteGuard {
+                lock: self,
+                data: unsafe { &mut *self.data.get() },
+            }),
+            e => Err(SyncError::from_errno(e)),
+        }
+    }
 }
 
 pub struct RwLockReadGuard<'a, T: 'a> {
@@ -132,3 +189,36 @@ impl<T> Drop for RwLock<T> {
 }
 
 unsafe impl<T> ShmSafe for RwLock<T> where T: ShmSafe {}
+
+#[cfg(test)]
+mod test {
+    use std::{thread, time::Duration};
+
+    use crate::sync::SyncError;
+
+    use super::RwLock;
+
+    #[test]
+    fn try_and_timed_access() {
+        let lock = RwLock::new(1);
+        let reader = lock.try_read().unwrap();
+        thread::scope(|s| {
+            s.spawn(|| {
+                assert_eq!(*lock.read_timeout(Duration::from_millis(1)).unwrap(), 1);
+                assert!(matches!(lock.try_write(), Err(SyncError::WouldBlock)));
+                assert!(matches!(
+                    lock.write_timeout(Duration::from_millis(1)),
+                    Err(SyncError::TimedOut)
+                ));
+            });
+        });
+        drop(reader);
+
+        *lock.write_timeout(Duration::from_millis(1)).unwrap() = 2;
+        let writer = lock.try_write().unwrap();
+        thread::scope(|s| {
+            s.spawn(|| assert!(matches!(lock.try_read(), Err(SyncError::WouldBlock))));
+        });
+        assert_eq!(*writer, 2);
+    }
+}
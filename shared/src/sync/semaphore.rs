@@ -1,10 +1,13 @@
-use std::{cell::UnsafeCell, mem::MaybeUninit};
+use std::{cell::UnsafeCell, mem::MaybeUninit, time::Duration};
 
-use libc::{sem_destroy, sem_init, sem_post, sem_t, sem_wait};
+use libc::{
+    __errno_location, sem_destroy, sem_init, sem_post, sem_t, sem_timedwait, sem_trywait, sem_wait,
+    EINTR,
+};
 
 use crate::shm::ShmSafe;
 
-use super::INTER_PROCESS;
+use super::{deadline, SyncError, INTER_PROCESS};
 
 #[repr(C)]
 #[derive(Debug)]
@@ -22,11 +25,35 @@ impl Semaphore {
     }
 
     pub fn wait(&self) {
-        if unsafe { sem_wait((*self.inner.get()).as_mut_ptr()) } != 0 {
-            panic!("failed to wait for semaphore");
+        // Interrupted by signal handlers, even with `SA_RESTART`
+        while unsafe { sem_wait((*self.inner.get()).as_mut_ptr()) } != 0 {
+            if errno() != EINTR {
+                panic!("failed to wait for semaphore");
+            }
         }
     }
 
+    /// Decrement the semaphore if that is possible without blocking,
+    /// fails with [`SyncError::WouldBlock`] otherwise
+    pub fn try_wait(&self) -> Result<(), SyncError> {
+        match unsafe { sem_trywait((*self.inner.get()).as_mut_ptr()) } {
+            0 => Ok(()),
+            _ => Err(SyncError::from_errno(errno())),
+        }
+    }
+
+    /// Wait for at most `timeout`, fails with [`SyncError::TimedOut`] once it expired
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<(), SyncError> {
+        let deadline = deadline(timeout);
+        while unsafe { sem_timedwait((*self.inner.get()).as_mut_ptr(), &deadline) } != 0 {
+            match errno() {
+                EINTR => continue,
+                e => return Err(SyncError::from_errno(e)),
+            }
+        }
+        Ok(())
+    }
+
     pub fn post(&self) {
         if unsafe { sem_post((*self.inner.get()).as_mut_ptr()) } != 0 {
             panic!("failed to post semaphore");
@@ -34,6 +61,10 @@ impl Semaphore {
     }
 }
 
+fn errno() -> i32 {
+    unsafe { *__errno_location() }
+}
+
 unsafe impl Send for Semaphore {}
 unsafe impl Sync for Semaphore {}
 
@@ -46,3 +77,25 @@ impl Drop for Semaphore {
 }
 
 unsafe impl ShmSafe for Semaphore {}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::sync::SyncError;
+
+    use super::Semaphore;
+
+    #[test]
+    fn try_and_timed_wait() {
+        let semaphore = Semaphore::new(1);
+        assert_eq!(semaphore.try_wait(), Ok(()));
+        assert_eq!(semaphore.try_wait(), Err(SyncError::WouldBlock));
+        assert_eq!(
+            semaphore.wait_timeout(Duration::from_millis(1)),
+            Err(SyncError::TimedOut)
+        );
+        semaphore.post();
+        assert_eq!(semaphore.wait_timeout(Duration::from_millis(1)), Ok(()));
+    }
+}
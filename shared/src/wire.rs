@@ -0,0 +1,1088 @@
+//! Explicit encoding of the messages exchanged through shared memory
+//!
+//! Requests and responses are not placed in shared memory as Rust values, whose layout depends
+//! on the compiler and the target, and which are undefined behaviour to read if they are corrupt.
+//! Instead, they are encoded into frames of a fixed size ([`REQUEST_SIZE`] and [`RESPONSE_SIZE`]):
+//!
+//! - integers are little-endian, lengths and indices (`usize`) are encoded as `u32`
+//! - `bool` is a byte, 0 or 1
+//! - enums start with a tag byte, followed by the fields of the variant. The tags are listed
+//!   next to the variants in [`Wire`] implementations below
+//! - `Option<T>` is a tag byte, 0 for `None` and 1 for `Some`, followed by `T` if present
+//! - keys are their length as `u16`, followed by that many bytes. Error details are encoded
+//!   the same way, and have to be UTF-8
+//! - `Duration` is its seconds as `u64`, followed by its nanoseconds as `u32`
+//! - a [`Blob`] is its `index`, `generation` and `len`, all as `u32`. The value itself
+//!   stays in the slab, it is checked when it is taken out
+//! - arrays of which only the first `len` items are used are `len`, followed by these items.
+//!   A `len` exceeding the array is encoded unchanged, and rejected when decoding
+//!
+//! Requests start with `client_id`, `mailbox` and `request_id`, responses with `client_id`
+//! and `request_id`, all as `u32`, followed by the payload. Trailing bytes of a frame are ignored.
+//! Decoding checks all tags, lengths and strings, and fails with a [`DecodeError`] on garbage.
+
+use std::{error::Error, fmt, time::Duration};
+
+use arrayvec::ArrayString;
+
+use crate::{
+    key::Key, shm::ShmSafe, slab::Blob, AbortReason, BatchOp, BatchResult, ErrorCode, KeyType,
+    Overflow, RequestData, RequestPayload, ResponseData, ResponsePayload, TransactionOp, ValueType,
+    BATCH_SIZE, BUCKET_CHUNK_SIZE, ERROR_DETAIL_SIZE, MAX_KEY_SIZE, TRANSACTION_SIZE,
+};
+
+const fn max(a: usize, b: usize) -> usize {
+    if a > b {
+        a
+    } else {
+        b
+    }
+}
+
+// Encoded sizes of the largest values, the frames fit the largest messages
+const KEY: usize = 2 + MAX_KEY_SIZE;
+const VALUE: usize = size_of::<ValueType>();
+const LEN: usize = size_of::<u32>();
+const DURATION: usize = 12;
+const BLOB: usize = 3 * LEN;
+const BATCH_OP: usize = 1 + KEY + max(VALUE + DURATION, BLOB);
+const TRANSACTION_OP: usize = 1 + KEY + size_of::<i64>();
+const BATCH_RESULT: usize = 1 + max(KEY, BLOB);
+
+/// Size of an encoded request
+pub const REQUEST_SIZE: usize =
+    3 * LEN + 1 + LEN + max(BATCH_SIZE * BATCH_OP, TRANSACTION_SIZE * TRANSACTION_OP);
+/// Size of an encoded response
+pub const RESPONSE_SIZE: usize = 2 * LEN
+    + 1
+    + max(
+        LEN + 1 + BUCKET_CHUNK_SIZE * (KEY + VALUE),
+        max(LEN + BATCH_SIZE * BATCH_RESULT, 1 + 2 + ERROR_DETAIL_SIZE),
+    );
+
+/// Encoded message, see the [module documentation](self)
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Frame<const N: usize>([u8; N]);
+
+pub type EncodedRequest = Frame<REQUEST_SIZE>;
+pub type EncodedResponse = Frame<RESPONSE_SIZE>;
+
+impl<const N: usize> Frame<N> {
+    pub const fn zeroed() -> Self {
+        Self([0; N])
+    }
+
+    pub fn as_bytes(&self) -> &[u8; N] {
+        &self.0
+    }
+
+    pub fn as_bytes_mut(&mut self) -> &mut [u8; N] {
+        &mut self.0
+    }
+}
+
+impl<const N: usize> fmt::Debug for Frame<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Frame<{N}>")
+    }
+}
+
+// Any bytes are a valid frame
+unsafe impl<const N: usize> ShmSafe for Frame<N> {}
+
+/// Why a frame could not be decoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// A tag does not name a variant of `what`
+    InvalidTag { what: &'static str, tag: u8 },
+    /// A length exceeds the capacity of `what`
+    InvalidLength { what: &'static str, len: usize },
+    /// A string is not valid UTF-8
+    InvalidUtf8,
+    /// The nanoseconds of a duration are not below one second
+    InvalidDuration,
+    /// The message does not fit into the frame
+    Truncated,
+}
+
+impl DecodeError {
+    /// Code of the error response to a request that failed to decode
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::InvalidLength { what: "key", .. } => ErrorCode::InvalidKey,
+            _ => ErrorCode::Malformed,
+        }
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidTag { what, tag } => write!(f, "invalid tag {tag} of {what}"),
+            Self::InvalidLength { what, len } => write!(f, "invalid length {len} of {what}"),
+            Self::InvalidUtf8 => write!(f, "string is not valid UTF-8"),
+            Self::InvalidDuration => write!(f, "nanoseconds of duration out of range"),
+            Self::Truncated => write!(f, "message exceeds the frame"),
+        }
+    }
+}
+
+impl Error for DecodeError {}
+
+/// A request whose payload could not be decoded, its header is always readable
+#[derive(Debug, Clone, Copy)]
+pub struct MalformedRequest {
+    pub client_id: u32,
+    pub mailbox: u32,
+    pub request_id: u32,
+    pub error: DecodeError,
+}
+
+/// A response whose payload could not be decoded, its header is always readable
+#[derive(Debug, Clone, Copy)]
+pub struct MalformedResponse {
+    pub client_id: u32,
+    pub request_id: u32,
+    pub error: DecodeError,
+}
+
+impl RequestData {
+    pub fn encode(&self) -> EncodedRequest {
+        let mut frame = Frame::zeroed();
+        self.encode_into(&mut frame);
+        frame
+    }
+
+    pub fn encode_into(&self, frame: &mut EncodedRequest) {
+        let mut w = Writer::new(frame.as_bytes_mut());
+        self.client_id.encode(&mut w);
+        self.mailbox.encode(&mut w);
+        self.request_id.encode(&mut w);
+        self.payload.encode(&mut w);
+    }
+
+    pub fn decode(frame: &EncodedRequest) -> Result<Self, MalformedRequest> {
+        let mut r = Reader::new(frame.as_bytes());
+        // The frame is larger than the header
+        let [client_id, mailbox, request_id] = [(); 3].map(|()| u32::decode(&mut r).unwrap());
+        match RequestPayload::decode(&mut r) {
+            Ok(payload) => Ok(Self {
+                client_id,
+                mailbox,
+                request_id,
+                payload,
+            }),
+            Err(error) => Err(MalformedRequest {
+                client_id,
+                mailbox,
+                request_id,
+                error,
+            }),
+        }
+    }
+}
+
+impl ResponseData {
+    pub fn encode(&self) -> EncodedResponse {
+        let mut frame = Frame::zeroed();
+        self.encode_into(&mut frame);
+        frame
+    }
+
+    pub fn encode_into(&self, frame: &mut EncodedResponse) {
+        let mut w = Writer::new(frame.as_bytes_mut());
+        self.client_id.encode(&mut w);
+        self.request_id.encode(&mut w);
+        self.payload.encode(&mut w);
+    }
+
+    pub fn decode(frame: &EncodedResponse) -> Result<Self, MalformedResponse> {
+        let mut r = Reader::new(frame.as_bytes());
+        // The frame is larger than the header
+        let [client_id, request_id] = [(); 2].map(|()| u32::decode(&mut r).unwrap());
+        match ResponsePayload::decode(&mut r) {
+            Ok(payload) => Ok(Self {
+                client_id,
+                request_id,
+                payload,
+            }),
+            Err(error) => Err(MalformedResponse {
+                client_id,
+                request_id,
+                error,
+            }),
+        }
+    }
+}
+
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> Writer<'a> {
+    fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    /// Panics if the frame is too small, the frame sizes are chosen for the largest messages
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.buf[self.pos..self.pos + bytes.len()].copy_from_slice(bytes);
+        self.pos += bytes.len();
+    }
+
+    fn tag(&mut self, tag: u8) {
+        self.bytes(&[tag]);
+    }
+
+    /// `len` followed by the first `len` items
+    ///
+    /// A `len` beyond the items is written as is, followed by all items, so decoding
+    /// the message fails instead of dropping the items that did not fit
+    fn items<T: Wire>(&mut self, len: usize, items: &[T]) {
+        u32::try_from(len).unwrap_or(u32::MAX).encode(self);
+        for item in &items[..len.min(items.len())] {
+            item.encode(self);
+        }
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N], DecodeError> {
+        Ok(self.slice(N)?.try_into().unwrap())
+    }
+
+    fn slice(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos + len)
+            .ok_or(DecodeError::Truncated)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn tag(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.bytes::<1>()?[0])
+    }
+
+    fn len(&mut self, what: &'static str, capacity: usize) -> Result<usize, DecodeError> {
+        let len = u32::decode(self)? as usize;
+        if len > capacity {
+            return Err(DecodeError::InvalidLength { what, len });
+        }
+        Ok(len)
+    }
+
+    /// Counterpart of [`Writer::items`], unused items are `fill`
+    fn items<T: Wire + Copy, const N: usize>(
+        &mut self,
+        what: &'static str,
+        fill: T,
+    ) -> Result<(usize, [T; N]), DecodeError> {
+        let len = self.len(what, N)?;
+        let mut items = [fill; N];
+        for item in &mut items[..len] {
+            *item = T::decode(self)?;
+        }
+        Ok((len, items))
+    }
+}
+
+/// Types with an encoding, see the [module documentation](self)
+trait Wire: Sized {
+    fn encode(&self, w: &mut Writer);
+    fn decode(r: &mut Reader) -> Result<Self, DecodeError>;
+}
+
+impl Wire for u32 {
+    fn encode(&self, w: &mut Writer) {
+        w.bytes(&self.to_le_bytes());
+    }
+
+    fn decode(r: &mut Reader) -> Result<Self, DecodeError> {
+        r.bytes().map(Self::from_le_bytes)
+    }
+}
+
+impl Wire for u64 {
+    fn encode(&self, w: &mut Writer) {
+        w.bytes(&self.to_le_bytes());
+    }
+
+    fn decode(r: &mut Reader) -> Result<Self, DecodeError> {
+        r.bytes().map(Self::from_le_bytes)
+    }
+}
+
+impl Wire for i64 {
+    fn encode(&self, w: &mut Writer) {
+        w.bytes(&self.to_le_bytes());
+    }
+
+    fn decode(r: &mut Reader) -> Result<Self, DecodeError> {
+        r.bytes().map(Self::from_le_bytes)
+    }
+}
+
+impl Wire for bool {
+    fn encode(&self, w: &mut Writer) {
+        w.tag(*self as u8);
+    }
+
+    fn decode(r: &mut Reader) -> Result<Self, DecodeError> {
+        match r.tag()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            tag => Err(DecodeError::InvalidTag { what: "bool", tag }),
+        }
+    }
+}
+
+impl Wire for Duration {
+    fn encode(&self, w: &mut Writer) {
+        self.as_secs().encode(w);
+        self.subsec_nanos().encode(w);
+    }
+
+    fn decode(r: &mut Reader) -> Result<Self, DecodeError> {
+        let secs = u64::decode(r)?;
+        let nanos = u32::decode(r)?;
+        if nanos >= 1_000_000_000 {
+            return Err(DecodeError::InvalidDuration);
+        }
+        Ok(Duration::new(secs, nanos))
+    }
+}
+
+impl Wire for Key {
+    fn encode(&self, w: &mut Writer) {
+        w.bytes(&(self.len() as u16).to_le_bytes());
+        w.bytes(self.as_bytes());
+    }
+
+    fn decode(r: &mut Reader) -> Result<Self, DecodeError> {
+        let len = u16::from_le_bytes(r.bytes()?) as usize;
+        if len > MAX_KEY_SIZE {
+            return Err(DecodeError::InvalidLength { what: "key", len });
+        }
+        Ok(Key::try_from(r.slice(len)?).unwrap())
+    }
+}
+
+/// Error details
+impl<const N: usize> Wire for ArrayString<N> {
+    fn encode(&self, w: &mut Writer) {
+        w.bytes(&(self.len() as u16).to_le_bytes());
+        w.bytes(self.as_bytes());
+    }
+
+    fn decode(r: &mut Reader) -> Result<Self, DecodeError> {
+        let len = u16::from_le_bytes(r.bytes()?) as usize;
+        if len > N {
+            return Err(DecodeError::InvalidLength {
+                what: "string",
+                len,
+            });
+        }
+        let s = std::str::from_utf8(r.slice(len)?).map_err(|_| DecodeError::InvalidUtf8)?;
+        Ok(ArrayString::from(s).unwrap())
+    }
+}
+
+impl<T: Wire> Wire for Option<T> {
+    fn encode(&self, w: &mut Writer) {
+        match self {
+            None => w.tag(0),
+            Some(t) => {
+                w.tag(1);
+                t.encode(w);
+            }
+        }
+    }
+
+    fn decode(r: &mut Reader) -> Result<Self, DecodeError> {
+        match r.tag()? {
+            0 => Ok(None),
+            1 => T::decode(r).map(Some),
+            tag => Err(DecodeError::InvalidTag {
+                what: "option",
+                tag,
+            }),
+        }
+    }
+}
+
+impl Wire for Blob {
+    fn encode(&self, w: &mut Writer) {
+        self.index.encode(w);
+        self.generation.encode(w);
+        self.len.encode(w);
+    }
+
+    fn decode(r: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Self {
+            index: Wire::decode(r)?,
+            generation: Wire::decode(r)?,
+            len: Wire::decode(r)?,
+        })
+    }
+}
+
+/// Entries of bucket contents
+impl Wire for (KeyType, ValueType) {
+    fn encode(&self, w: &mut Writer) {
+        self.0.encode(w);
+        self.1.encode(w);
+    }
+
+    fn decode(r: &mut Reader) -> Result<Self, DecodeError> {
+        Ok((KeyType::decode(r)?, ValueType::decode(r)?))
+    }
+}
+
+impl Wire for Overflow {
+    fn encode(&self, w: &mut Writer) {
+        w.tag(match self {
+            Self::Saturating => 0,
+            Self::Wrapping => 1,
+        });
+    }
+
+    fn decode(r: &mut Reader) -> Result<Self, DecodeError> {
+        match r.tag()? {
+            0 => Ok(Self::Saturating),
+            1 => Ok(Self::Wrapping),
+            tag => Err(DecodeError::InvalidTag {
+                what: "overflow",
+                tag,
+            }),
+        }
+    }
+}
+
+impl Wire for ErrorCode {
+    fn encode(&self, w: &mut Writer) {
+        w.tag(match self {
+            Self::InvalidKey => 0,
+            Self::InvalidArgument => 1,
+            Self::TableFull => 2,
+            Self::Unsupported => 3,
+            Self::Internal => 4,
+            Self::Timeout => 5,
+            Self::Malformed => 6,
+            Self::WrongType => 7,
+            Self::SlabFull => 8,
+        });
+    }
+
+    fn decode(r: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(match r.tag()? {
+            0 => Self::InvalidKey,
+            1 => Self::InvalidArgument,
+            2 => Self::TableFull,
+            3 => Self::Unsupported,
+            4 => Self::Internal,
+            5 => Self::Timeout,
+            6 => Self::Malformed,
+            7 => Self::WrongType,
+            8 => Self::SlabFull,
+            tag => {
+                return Err(DecodeError::InvalidTag {
+                    what: "error code",
+                    tag,
+                })
+            }
+        })
+    }
+}
+
+impl Wire for AbortReason {
+    fn encode(&self, w: &mut Writer) {
+        match self {
+            Self::CheckFailed(current) => {
+                w.tag(0);
+                current.encode(w);
+            }
+            Self::Overflow => w.tag(1),
+            Self::TooLarge => w.tag(2),
+            Self::WrongType => w.tag(3),
+        }
+    }
+
+    fn decode(r: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(match r.tag()? {
+            0 => Self::CheckFailed(Wire::decode(r)?),
+            1 => Self::Overflow,
+            2 => Self::TooLarge,
+            3 => Self::WrongType,
+            tag => {
+                return Err(DecodeError::InvalidTag {
+                    what: "abort reason",
+                    tag,
+                })
+            }
+        })
+    }
+}
+
+impl Wire for BatchOp {
+    fn encode(&self, w: &mut Writer) {
+        match self {
+            Self::Insert(k, v) => {
+                w.tag(0);
+                k.encode(w);
+                v.encode(w);
+            }
+            Self::InsertWithTtl(k, v, ttl) => {
+                w.tag(1);
+                k.encode(w);
+                v.encode(w);
+                ttl.encode(w);
+            }
+            Self::Get(k) => {
+                w.tag(2);
+                k.encode(w);
+            }
+            Self::Delete(k) => {
+                w.tag(3);
+                k.encode(w);
+            }
+            Self::FetchAdd(k, v, overflow) => {
+                w.tag(4);
+                k.encode(w);
+                v.encode(w);
+                overflow.encode(w);
+            }
+            Self::InsertBytes(k, blob) => {
+                w.tag(5);
+                k.encode(w);
+                blob.encode(w);
+            }
+        }
+    }
+
+    fn decode(r: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(match r.tag()? {
+            0 => Self::Insert(Wire::decode(r)?, Wire::decode(r)?),
+            1 => Self::InsertWithTtl(Wire::decode(r)?, Wire::decode(r)?, Wire::decode(r)?),
+            2 => Self::Get(Wire::decode(r)?),
+            3 => Self::Delete(Wire::decode(r)?),
+            4 => Self::FetchAdd(Wire::decode(r)?, Wire::decode(r)?, Wire::decode(r)?),
+            5 => Self::InsertBytes(Wire::decode(r)?, Wire::decode(r)?),
+            tag => {
+                return Err(DecodeError::InvalidTag {
+                    what: "batch operation",
+                    tag,
+                })
+            }
+        })
+    }
+}
+
+impl Wire for TransactionOp {
+    fn encode(&self, w: &mut Writer) {
+        match self {
+            Self::Get(k) => {
+                w.tag(0);
+                k.encode(w);
+            }
+            Self::Insert(k, v) => {
+                w.tag(1);
+                k.encode(w);
+                v.encode(w);
+            }
+            Self::Delete(k) => {
+                w.tag(2);
+                k.encode(w);
+            }
+            Self::Check(k, expected) => {
+                w.tag(3);
+                k.encode(w);
+                expected.encode(w);
+            }
+            Self::Add(k, delta) => {
+                w.tag(4);
+                k.encode(w);
+                delta.encode(w);
+            }
+        }
+    }
+
+    fn decode(r: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(match r.tag()? {
+            0 => Self::Get(Wire::decode(r)?),
+            1 => Self::Insert(Wire::decode(r)?, Wire::decode(r)?),
+            2 => Self::Delete(Wire::decode(r)?),
+            3 => Self::Check(Wire::decode(r)?, Wire::decode(r)?),
+            4 => Self::Add(Wire::decode(r)?, Wire::decode(r)?),
+            tag => {
+                return Err(DecodeError::InvalidTag {
+                    what: "transaction operation",
+                    tag,
+                })
+            }
+        })
+    }
+}
+
+impl Wire for BatchResult {
+    fn encode(&self, w: &mut Writer) {
+        match self {
+            Self::Inserted => w.tag(0),
+            Self::InsertedEvicted(k) => {
+                w.tag(1);
+                k.encode(w);
+            }
+            Self::Value(v) => {
+                w.tag(2);
+                v.encode(w);
+            }
+            Self::Deleted => w.tag(3),
+            Self::Fetched(v) => {
+                w.tag(4);
+                v.encode(w);
+            }
+            Self::NotFound => w.tag(5),
+            Self::Error(code) => {
+                w.tag(6);
+                code.encode(w);
+            }
+            Self::Bytes(blob) => {
+                w.tag(7);
+                blob.encode(w);
+            }
+        }
+    }
+
+    fn decode(r: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(match r.tag()? {
+            0 => Self::Inserted,
+            1 => Self::InsertedEvicted(Wire::decode(r)?),
+            2 => Self::Value(Wire::decode(r)?),
+            3 => Self::Deleted,
+            4 => Self::Fetched(Wire::decode(r)?),
+            5 => Self::NotFound,
+            6 => Self::Error(Wire::decode(r)?),
+            7 => Self::Bytes(Wire::decode(r)?),
+            tag => {
+                return Err(DecodeError::InvalidTag {
+                    what: "batch result",
+                    tag,
+                })
+            }
+        })
+    }
+}
+
+impl Wire for RequestPayload {
+    fn encode(&self, w: &mut Writer) {
+        match self {
+            Self::Insert(k, v) => {
+                w.tag(0);
+                k.encode(w);
+                v.encode(w);
+            }
+            Self::InsertWithTtl(k, v, ttl) => {
+                w.tag(1);
+                k.encode(w);
+                v.encode(w);
+                ttl.encode(w);
+            }
+            Self::Touch(k, ttl) => {
+                w.tag(2);
+                k.encode(w);
+                ttl.encode(w);
+            }
+            Self::InsertIfAbsent(k, v) => {
+                w.tag(3);
+                k.encode(w);
+                v.encode(w);
+            }
+            Self::ReplaceIfPresent(k, v) => {
+                w.tag(4);
+                k.encode(w);
+                v.encode(w);
+            }
+            Self::CompareAndSwap { key, expected, new } => {
+                w.tag(5);
+                key.encode(w);
+                expected.encode(w);
+                new.encode(w);
+            }
+            Self::FetchAdd(k, v, overflow) => {
+                w.tag(6);
+                k.encode(w);
+                v.encode(w);
+                overflow.encode(w);
+            }
+            Self::FetchSub(k, v, overflow) => {
+                w.tag(7);
+                k.encode(w);
+                v.encode(w);
+                overflow.encode(w);
+            }
+            Self::FetchMin(k, v) => {
+                w.tag(8);
+                k.encode(w);
+                v.encode(w);
+            }
+            Self::FetchMax(k, v) => {
+                w.tag(9);
+                k.encode(w);
+                v.encode(w);
+            }
+            Self::Batch { len, ops } => {
+                w.tag(10);
+                w.items(*len, ops);
+            }
+            Self::Transaction { len, ops } => {
+                w.tag(11);
+                w.items(*len, ops);
+            }
+            Self::Get(k) => {
+                w.tag(12);
+                k.encode(w);
+            }
+            Self::ReadBucket(k) => {
+                w.tag(13);
+                k.encode(w);
+            }
+            Self::PrintHashmap => w.tag(14),
+            Self::Snapshot => w.tag(15),
+            Self::Delete(k) => {
+                w.tag(16);
+                k.encode(w);
+            }
+            Self::InsertBytes(k, blob) => {
+                w.tag(17);
+                k.encode(w);
+                blob.encode(w);
+            }
+        }
+    }
+
+    fn decode(r: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(match r.tag()? {
+            0 => Self::Insert(Wire::decode(r)?, Wire::decode(r)?),
+            1 => Self::InsertWithTtl(Wire::decode(r)?, Wire::decode(r)?, Wire::decode(r)?),
+            2 => Self::Touch(Wire::decode(r)?, Wire::decode(r)?),
+            3 => Self::InsertIfAbsent(Wire::decode(r)?, Wire::decode(r)?),
+            4 => Self::ReplaceIfPresent(Wire::decode(r)?, Wire::decode(r)?),
+            5 => Self::CompareAndSwap {
+                key: Wire::decode(r)?,
+                expected: Wire::decode(r)?,
+                new: Wire::decode(r)?,
+            },
+            6 => Self::FetchAdd(Wire::decode(r)?, Wire::decode(r)?, Wire::decode(r)?),
+            7 => Self::FetchSub(Wire::decode(r)?, Wire::decode(r)?, Wire::decode(r)?),
+            8 => Self::FetchMin(Wire::decode(r)?, Wire::decode(r)?),
+            9 => Self::FetchMax(Wire::decode(r)?, Wire::decode(r)?),
+            10 => {
+                let (len, ops) = r.items("batch", BatchOp::Get(KeyType::new()))?;
+                Self::Batch { len, ops }
+            }
+            11 => {
+                let (len, ops) = r.items("transaction", TransactionOp::Get(KeyType::new()))?;
+                Self::Transaction { len, ops }
+            }
+            12 => Self::Get(Wire::decode(r)?),
+            13 => Self::ReadBucket(Wire::decode(r)?),
+            14 => Self::PrintHashmap,
+            15 => Self::Snapshot,
+            16 => Self::Delete(Wire::decode(r)?),
+            17 => Self::InsertBytes(Wire::decode(r)?, Wire::decode(r)?),
+            tag => {
+                return Err(DecodeError::InvalidTag {
+                    what: "request",
+                    tag,
+                })
+            }
+        })
+    }
+}
+
+impl Wire for ResponsePayload {
+    fn encode(&self, w: &mut Writer) {
+        match self {
+            Self::Inserted => w.tag(0),
+            Self::InsertedEvicted(k) => {
+                w.tag(1);
+                k.encode(w);
+            }
+            Self::Value(v) => {
+                w.tag(2);
+                v.encode(w);
+            }
+            Self::BucketContent { len, more, data } => {
+                w.tag(3);
+                more.encode(w);
+                w.items(*len, data);
+            }
+            Self::Deleted => w.tag(4),
+            Self::Touched => w.tag(5),
+            Self::Conditional { success, current } => {
+                w.tag(6);
+                success.encode(w);
+                current.encode(w);
+            }
+            Self::Fetched(v) => {
+                w.tag(7);
+                v.encode(w);
+            }
+            Self::Batch { len, results } => {
+                w.tag(8);
+                w.items(*len, results);
+            }
+            Self::Committed { len, results } => {
+                w.tag(9);
+                w.items(*len, results);
+            }
+            Self::Aborted { index, reason } => {
+                w.tag(10);
+                (*index as u32).encode(w);
+                reason.encode(w);
+            }
+            Self::NotFound => w.tag(11),
+            Self::Printed => w.tag(12),
+            Self::Snapshotted { entries } => {
+                w.tag(13);
+                entries.encode(w);
+            }
+            Self::Error { code, detail } => {
+                w.tag(14);
+                code.encode(w);
+                detail.encode(w);
+            }
+            Self::Bytes(blob) => {
+                w.tag(15);
+                blob.encode(w);
+            }
+        }
+    }
+
+    fn decode(r: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(match r.tag()? {
+            0 => Self::Inserted,
+            1 => Self::InsertedEvicted(Wire::decode(r)?),
+            2 => Self::Value(Wire::decode(r)?),
+            3 => {
+                let more = Wire::decode(r)?;
+                let (len, data) = r.items("bucket content", (KeyType::new(), 0))?;
+                Self::BucketContent { len, more, data }
+            }
+            4 => Self::Deleted,
+            5 => Self::Touched,
+            6 => Self::Conditional {
+                success: Wire::decode(r)?,
+                current: Wire::decode(r)?,
+            },
+            7 => Self::Fetched(Wire::decode(r)?),
+            8 => {
+                let (len, results) = r.items("batch", BatchResult::NotFound)?;
+                Self::Batch { len, results }
+            }
+            9 => {
+                let (len, results) = r.items("transaction", None)?;
+                Self::Committed { len, results }
+            }
+            10 => Self::Aborted {
+                index: u32::decode(r)? as usize,
+                reason: Wire::decode(r)?,
+            },
+            11 => Self::NotFound,
+            12 => Self::Printed,
+            13 => Self::Snapshotted {
+                entries: Wire::decode(r)?,
+            },
+            14 => Self::Error {
+                code: Wire::decode(r)?,
+                detail: Wire::decode(r)?,
+            },
+            15 => Self::Bytes(Wire::decode(r)?),
+            tag => {
+                return Err(DecodeError::InvalidTag {
+                    what: "response",
+                    tag,
+                })
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::{
+        BatchOp, BatchResult, ErrorCode, KeyType, Overflow, RequestData, RequestPayload,
+        ResponseData, ResponsePayload, TransactionOp, BATCH_SIZE, BUCKET_CHUNK_SIZE, MAX_KEY_SIZE,
+        TRANSACTION_SIZE,
+    };
+
+    use super::DecodeError;
+
+    fn longest_key() -> KeyType {
+        KeyType::try_from(&[0xff; MAX_KEY_SIZE][..]).unwrap()
+    }
+
+    fn request(payload: RequestPayload) -> RequestData {
+        RequestData {
+            client_id: 1,
+            mailbox: 2,
+            request_id: 3,
+            payload,
+        }
+    }
+
+    #[test]
+    fn largest_messages_roundtrip() {
+        let duration = Duration::new(u64::MAX, 999_999_999);
+        let op = BatchOp::InsertWithTtl(longest_key(), u32::MAX, duration);
+        let batch = request(RequestPayload::Batch {
+            len: BATCH_SIZE,
+            ops: [op; BATCH_SIZE],
+        });
+        let frame = batch.encode();
+        let decoded = RequestData::decode(&frame).unwrap();
+        assert_eq!(format!("{batch:?}"), format!("{decoded:?}"));
+        assert_eq!(decoded.encode().as_bytes(), frame.as_bytes());
+
+        let content = ResponseData {
+            client_id: 1,
+            request_id: 3,
+            payload: ResponsePayload::BucketContent {
+                len: BUCKET_CHUNK_SIZE,
+                more: true,
+                data: [(longest_key(), u32::MAX); BUCKET_CHUNK_SIZE],
+            },
+        };
+        let frame = content.encode();
+        let decoded = ResponseData::decode(&frame).unwrap();
+        assert_eq!(format!("{content:?}"), format!("{decoded:?}"));
+
+        let failed = ResponseData {
+            payload: ResponsePayload::error(ErrorCode::Internal, &"x".repeat(1000)),
+            ..content
+        };
+        let decoded = ResponseData::decode(&failed.encode()).unwrap();
+        assert_eq!(format!("{failed:?}"), format!("{decoded:?}"));
+        let batch = ResponseData {
+            payload: ResponsePayload::Batch {
+                len: 1,
+                results: [BatchResult::InsertedEvicted(longest_key()); BATCH_SIZE],
+            },
+            ..failed
+        };
+        let decoded = ResponseData::decode(&batch.encode()).unwrap();
+        assert!(matches!(
+            decoded.payload,
+            ResponsePayload::Batch { len: 1, .. }
+        ));
+    }
+
+    #[test]
+    fn garbage_is_rejected() {
+        let key = KeyType::try_from("key").unwrap();
+        let frame = request(RequestPayload::FetchAdd(key, 1, Overflow::Wrapping)).encode();
+        // Header (12), tag (1), key length (2) and key (3), value (4), overflow tag
+        let overflow = 12 + 1 + 2 + 3 + 4;
+
+        let mut corrupt = frame;
+        corrupt.as_bytes_mut()[12] = 200;
+        let malformed = RequestData::decode(&corrupt).unwrap_err();
+        assert_eq!((malformed.mailbox, malformed.request_id), (2, 3));
+        assert_eq!(
+            malformed.error,
+            DecodeError::InvalidTag {
+                what: "request",
+                tag: 200
+            }
+        );
+        assert_eq!(malformed.error.code(), ErrorCode::Malformed);
+
+        let mut corrupt = frame;
+        corrupt.as_bytes_mut()[overflow] = 2;
+        let malformed = RequestData::decode(&corrupt).unwrap_err();
+        assert!(matches!(malformed.error, DecodeError::InvalidTag { .. }));
+
+        let mut corrupt = frame;
+        corrupt.as_bytes_mut()[13..15].copy_from_slice(&u16::MAX.to_le_bytes());
+        let malformed = RequestData::decode(&corrupt).unwrap_err();
+        assert_eq!(malformed.error.code(), ErrorCode::InvalidKey);
+
+        let mut corrupt = frame;
+        corrupt.as_bytes_mut()[13..15].copy_from_slice(&(MAX_KEY_SIZE as u16 + 1).to_le_bytes());
+        let malformed = RequestData::decode(&corrupt).unwrap_err();
+        assert_eq!(
+            malformed.error,
+            DecodeError::InvalidLength {
+                what: "key",
+                len: MAX_KEY_SIZE + 1
+            }
+        );
+
+        // Keys are not checked for UTF-8
+        let mut binary = frame;
+        binary.as_bytes_mut()[15] = 0xff;
+        let decoded = RequestData::decode(&binary).unwrap();
+        assert!(
+            matches!(decoded.payload, RequestPayload::FetchAdd(k, ..) if k.as_bytes() == b"\xffey")
+        );
+
+        let mut corrupt = request(RequestPayload::Batch {
+            len: 0,
+            ops: [BatchOp::Get(key); BATCH_SIZE],
+        })
+        .encode();
+        corrupt.as_bytes_mut()[13..17].copy_from_slice(&(BATCH_SIZE as u32 + 1).to_le_bytes());
+        let malformed = RequestData::decode(&corrupt).unwrap_err();
+        assert_eq!(
+            malformed.error,
+            DecodeError::InvalidLength {
+                what: "batch",
+                len: BATCH_SIZE + 1
+            }
+        );
+    }
+
+    #[test]
+    fn oversized_lists_are_rejected() {
+        let key = KeyType::try_from("key").unwrap();
+        let batch = request(RequestPayload::Batch {
+            len: BATCH_SIZE + 1,
+            ops: [BatchOp::Insert(key, 1); BATCH_SIZE],
+        });
+        let malformed = RequestData::decode(&batch.encode()).unwrap_err();
+        assert_eq!(
+            malformed.error,
+            DecodeError::InvalidLength {
+                what: "batch",
+                len: BATCH_SIZE + 1
+            }
+        );
+
+        let transaction = request(RequestPayload::Transaction {
+            len: TRANSACTION_SIZE + 1,
+            ops: [TransactionOp::Insert(key, 1); TRANSACTION_SIZE],
+        });
+        let malformed = RequestData::decode(&transaction.encode()).unwrap_err();
+        assert_eq!(
+            malformed.error,
+            DecodeError::InvalidLength {
+                what: "transaction",
+                len: TRANSACTION_SIZE + 1
+            }
+        );
+        assert_eq!(malformed.error.code(), ErrorCode::Malformed);
+    }
+}